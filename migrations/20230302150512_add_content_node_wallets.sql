@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE network_monitoring_content_nodes
+    ADD COLUMN owner_wallet VARCHAR,
+    ADD COLUMN delegate_owner_wallet VARCHAR;
@@ -0,0 +1,6 @@
+-- Add migration script here
+CREATE INDEX idx_cids_from_discovery_run_id_user_id
+    ON network_monitoring_cids_from_discovery (run_id, user_id);
+
+CREATE INDEX idx_cids_from_content_run_id_spid_cid
+    ON network_monitoring_cids_from_content (run_id, content_node_spID, cid);
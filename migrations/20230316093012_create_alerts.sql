@@ -0,0 +1,10 @@
+-- Alerts that have been sent to Slack, so the same alert isn't sent again every run
+CREATE TABLE network_monitoring_alerts (
+    fingerprint VARCHAR NOT NULL,
+    summary VARCHAR NOT NULL,
+    first_fired_run_id INT NOT NULL,
+    last_fired_run_id INT NOT NULL,
+    last_notified_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    resolved_at TIMESTAMPTZ,
+    PRIMARY KEY (fingerprint)
+);
@@ -0,0 +1,14 @@
+-- Track each stage of a run, so partial runs can be told apart from complete ones
+ALTER TABLE network_monitoring_index_blocks
+    ADD COLUMN discovery_status VARCHAR,
+    ADD COLUMN discovery_started_at TIMESTAMPTZ,
+    ADD COLUMN discovery_finished_at TIMESTAMPTZ,
+    ADD COLUMN discovery_error TEXT,
+    ADD COLUMN content_status VARCHAR,
+    ADD COLUMN content_started_at TIMESTAMPTZ,
+    ADD COLUMN content_finished_at TIMESTAMPTZ,
+    ADD COLUMN content_error TEXT,
+    ADD COLUMN metrics_status VARCHAR,
+    ADD COLUMN metrics_started_at TIMESTAMPTZ,
+    ADD COLUMN metrics_finished_at TIMESTAMPTZ,
+    ADD COLUMN metrics_error TEXT;
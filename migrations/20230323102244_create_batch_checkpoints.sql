@@ -0,0 +1,11 @@
+-- Batches of a run that were checked and saved, so an interrupted run can be resumed
+CREATE TABLE network_monitoring_batch_checkpoints (
+    run_id INT NOT NULL,
+    spid INT NOT NULL,
+    replica VARCHAR NOT NULL,
+    batch_check VARCHAR NOT NULL,
+    batch_offset BIGINT NOT NULL,
+    saved_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_run_id FOREIGN KEY (run_id) REFERENCES network_monitoring_index_blocks(run_id) ON DELETE CASCADE,
+    PRIMARY KEY (run_id, spid, replica, batch_check, batch_offset)
+);
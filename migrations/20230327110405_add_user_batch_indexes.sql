@@ -0,0 +1,10 @@
+-- Content nodes page through their users with `user_id > $cursor ORDER BY user_id`,
+-- one replica at a time, so each replica column gets an index ending in user_id
+CREATE INDEX idx_users_run_id_primaryspid_user_id
+    ON network_monitoring_users (run_id, primarySpID, user_id);
+
+CREATE INDEX idx_users_run_id_secondary1spid_user_id
+    ON network_monitoring_users (run_id, secondary1SpID, user_id);
+
+CREATE INDEX idx_users_run_id_secondary2spid_user_id
+    ON network_monitoring_users (run_id, secondary2SpID, user_id);
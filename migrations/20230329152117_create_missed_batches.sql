@@ -0,0 +1,15 @@
+-- Batches of users whose clock values couldn't be checked, retried at the end of the content stage.
+-- Rows are removed once their batch is saved, so what's left was missed by the run.
+CREATE TABLE network_monitoring_missed_batches (
+    run_id INT NOT NULL,
+    spid INT NOT NULL,
+    replica VARCHAR NOT NULL,
+    batch_offset BIGINT NOT NULL,
+    wallets TEXT[] NOT NULL,
+    error_kind VARCHAR NOT NULL,
+    error TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 1,
+    missed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_run_id FOREIGN KEY (run_id) REFERENCES network_monitoring_index_blocks(run_id) ON DELETE CASCADE,
+    PRIMARY KEY (run_id, spid, replica, batch_offset)
+);
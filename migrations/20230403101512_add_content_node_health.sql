@@ -0,0 +1,8 @@
+-- What storage-v2 content nodes report on `/health_check`, NULL on legacy networks
+ALTER TABLE network_monitoring_content_nodes
+    ADD COLUMN is_healthy BOOLEAN,
+    ADD COLUMN version VARCHAR;
+
+-- Storage-v2 checks page through the CIDs of a run in CID order
+CREATE INDEX idx_cids_from_discovery_run_id_cid_user_id
+    ON network_monitoring_cids_from_discovery (run_id, cid, user_id);
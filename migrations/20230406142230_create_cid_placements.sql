@@ -0,0 +1,17 @@
+-- Protocol the content nodes of a run were checked with, NULL for runs from before it was recorded
+ALTER TABLE network_monitoring_index_blocks
+    ADD COLUMN content_protocol VARCHAR;
+
+-- Content nodes rendezvous hashing places each CID of a storage-v2 run on,
+-- `rank` 0 being the node ranked first for the CID
+CREATE TABLE network_monitoring_cid_placements (
+    run_id INT NOT NULL,
+    cid VARCHAR NOT NULL,
+    spid INT NOT NULL,
+    rank SMALLINT NOT NULL,
+    CONSTRAINT fk_run_id FOREIGN KEY (run_id) REFERENCES network_monitoring_index_blocks(run_id) ON DELETE CASCADE,
+    PRIMARY KEY (run_id, cid, spid)
+);
+
+CREATE INDEX idx_cid_placements_run_id_spid
+    ON network_monitoring_cid_placements (run_id, spid);
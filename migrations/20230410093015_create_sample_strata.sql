@@ -0,0 +1,13 @@
+-- Runs that only checked a random sample of the users of each primary
+ALTER TABLE network_monitoring_index_blocks
+    ADD COLUMN is_sampled BOOL NOT NULL DEFAULT FALSE;
+
+-- Users of each primary a sampled run drew its sample from, and how many of them it kept
+CREATE TABLE network_monitoring_sample_strata (
+    run_id INT NOT NULL,
+    primary_spid INT NOT NULL,
+    population BIGINT NOT NULL,
+    sample_size BIGINT NOT NULL,
+    CONSTRAINT fk_run_id FOREIGN KEY (run_id) REFERENCES network_monitoring_index_blocks(run_id) ON DELETE CASCADE,
+    PRIMARY KEY (run_id, primary_spid)
+);
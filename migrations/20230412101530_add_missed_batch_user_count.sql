@@ -0,0 +1,6 @@
+-- Users a missed batch left unchecked, every user of the replica after it when it couldn't be read
+ALTER TABLE network_monitoring_missed_batches
+    ADD COLUMN user_count BIGINT NOT NULL DEFAULT 0;
+
+UPDATE network_monitoring_missed_batches
+SET user_count = cardinality(wallets);
@@ -0,0 +1,10 @@
+-- CIDs a content node was asked about but didn't answer for,
+-- so they're neither present nor missing from it
+CREATE TABLE network_monitoring_unchecked_cids (
+    run_id INT NOT NULL,
+    content_node_spid INT NOT NULL,
+    cid VARCHAR NOT NULL,
+    user_id INT NOT NULL,
+    CONSTRAINT fk_run_id FOREIGN KEY (run_id) REFERENCES network_monitoring_index_blocks(run_id) ON DELETE CASCADE,
+    PRIMARY KEY (run_id, content_node_spid, cid, user_id)
+);
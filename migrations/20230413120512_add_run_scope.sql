@@ -0,0 +1,7 @@
+-- Runs that only checked some of the content nodes or users, which aren't comparable to full runs
+ALTER TABLE network_monitoring_index_blocks
+    ADD COLUMN is_scoped BOOL NOT NULL DEFAULT FALSE;
+
+-- Content nodes a scoped run left out, none of them were checked
+ALTER TABLE network_monitoring_content_nodes
+    ADD COLUMN is_in_scope BOOL NOT NULL DEFAULT TRUE;
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::Result;
+use serde_json::json;
+use sqlx::PgPool;
+
+use crate::{
+    configuration::{AlertSettings, ContentProtocol, MetricsSettings},
+    metrics::{get_primary_user_count, get_unsynced_users_count, CNodeCount},
+    runs::{self, get_previous_run_id},
+};
+
+/// Alert rules, the fingerprints of their alerts start with them
+const UNSYNCED_USERS_RULE: &str = "unsynced_users";
+const PRIMARY_USER_DROP_RULE: &str = "primary_user_drop";
+const UNREACHABLE_NODE_RULE: &str = "unreachable_node";
+
+/// A rule that fired for a run.
+/// Alerts with the same fingerprint are the same alert, even if their summary changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+    pub fingerprint: String,
+    pub summary: String,
+}
+
+/// An alert that was previously sent to Slack
+#[derive(Debug, Clone)]
+pub struct SentAlert {
+    pub fingerprint: String,
+    pub summary: String,
+    pub last_notified_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+/// What should be sent to Slack for a run
+#[derive(Debug, Default)]
+pub struct Notification {
+    /// Alerts that are new, fired again after being resolved, or are due to be re-sent
+    pub firing: Vec<Alert>,
+    /// Alerts that were sent and no longer fire
+    pub resolved: Vec<SentAlert>,
+}
+
+impl Notification {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.firing.is_empty() && self.resolved.is_empty()
+    }
+}
+
+/// Evaluate the alert rules for `run_id` and post anything new to `slack_url`.
+/// The `deregistered_nodes` aren't checked, so they never alert as unreachable.
+///
+/// # Errors
+///
+/// Returns an error if a rule can't be evaluated or the Slack webhook rejects the message
+#[tracing::instrument(skip(pool, config, deregistered_nodes))]
+pub async fn notify(
+    pool: &PgPool,
+    run_id: i32,
+    config: &MetricsSettings,
+    deregistered_nodes: &[String],
+) -> Result<()> {
+    if config.slack_url.is_empty() {
+        tracing::debug!("`metrics.slack_url` is not set, skipping alerts");
+        return Ok(());
+    }
+
+    let (firing, rules) = evaluate(pool, run_id, &config.alerts, deregistered_nodes).await?;
+
+    // A rule that wasn't evaluated for this run can't resolve its alerts
+    let sent = get_sent_alerts(pool)
+        .await?
+        .into_iter()
+        .filter(|alert| {
+            let rule = alert.fingerprint.split(':').next().unwrap_or_default();
+            rules.contains(&rule)
+        })
+        .collect::<Vec<SentAlert>>();
+    let renotify_after =
+        Duration::hours(i64::try_from(config.alerts.renotify_after_hours).unwrap_or(i64::MAX));
+    let notification = plan(&firing, &sent, Utc::now(), renotify_after);
+
+    // Post before recording, so a failed post is retried on the next run
+    if !notification.is_empty() {
+        post_to_slack(&config.slack_url, &format_message(run_id, &notification)).await?;
+    }
+
+    save_alerts(pool, run_id, &firing, &notification).await?;
+
+    Ok(())
+}
+
+/// Work out which alerts to send, given every alert that fired for this run
+/// and the alerts that were sent before
+#[must_use]
+pub fn plan(
+    firing: &[Alert],
+    sent: &[SentAlert],
+    now: DateTime<Utc>,
+    renotify_after: Duration,
+) -> Notification {
+    let sent_by_fingerprint = sent
+        .iter()
+        .map(|alert| (alert.fingerprint.as_str(), alert))
+        .collect::<HashMap<&str, &SentAlert>>();
+
+    let firing_now = firing
+        .iter()
+        .filter(
+            |alert| match sent_by_fingerprint.get(alert.fingerprint.as_str()) {
+                Some(sent) if sent.resolved_at.is_none() => {
+                    now - sent.last_notified_at >= renotify_after
+                }
+                _ => true,
+            },
+        )
+        .cloned()
+        .collect::<Vec<Alert>>();
+
+    let resolved = sent
+        .iter()
+        .filter(|sent| sent.resolved_at.is_none())
+        .filter(|sent| {
+            !firing
+                .iter()
+                .any(|alert| alert.fingerprint == sent.fingerprint)
+        })
+        .cloned()
+        .collect::<Vec<SentAlert>>();
+
+    Notification {
+        firing: firing_now,
+        resolved,
+    }
+}
+
+/// Slack `mrkdwn` summary of a notification
+#[must_use]
+pub fn format_message(run_id: i32, notification: &Notification) -> String {
+    let mut lines = vec![format!("*Network monitoring run {run_id}*")];
+
+    if !notification.firing.is_empty() {
+        lines.push(format!(
+            ":rotating_light: {} firing",
+            notification.firing.len()
+        ));
+        lines.extend(
+            notification
+                .firing
+                .iter()
+                .map(|alert| format!("• {}", alert.summary)),
+        );
+    }
+
+    if !notification.resolved.is_empty() {
+        lines.push(format!(
+            ":white_check_mark: {} resolved",
+            notification.resolved.len()
+        ));
+        lines.extend(
+            notification
+                .resolved
+                .iter()
+                .map(|alert| format!("• ~{}~", alert.summary)),
+        );
+    }
+
+    lines.join("\n")
+}
+
+/// Post `text` to a Slack incoming webhook
+///
+/// # Errors
+///
+/// Returns an error if the request fails or the webhook responds with an error status
+#[tracing::instrument(skip(text))]
+pub async fn post_to_slack(url: &str, text: &str) -> Result<()> {
+    reqwest::Client::new()
+        .post(url)
+        .json(&json!({ "text": text }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Alerts for the nodes whose primary user count dropped by more than `max_drop_percent`
+#[must_use]
+pub fn primary_user_drops(
+    previous_run_id: i32,
+    previous: &[CNodeCount],
+    current: &[CNodeCount],
+    max_drop_percent: f64,
+) -> Vec<Alert> {
+    let current_by_endpoint = current
+        .iter()
+        .map(|cnode| (cnode.endpoint.as_str(), cnode.count))
+        .collect::<HashMap<&str, i64>>();
+
+    previous
+        .iter()
+        .filter(|cnode| cnode.count > 0)
+        .filter_map(|cnode| {
+            let count = current_by_endpoint
+                .get(cnode.endpoint.as_str())
+                .copied()
+                .unwrap_or(0);
+
+            #[allow(clippy::cast_precision_loss)]
+            let drop_percent = (cnode.count - count) as f64 / cnode.count as f64 * 100.0;
+
+            (drop_percent > max_drop_percent).then(|| Alert {
+                fingerprint: format!("{PRIMARY_USER_DROP_RULE}:{}", cnode.endpoint),
+                summary: format!(
+                    "primary users on {} dropped {drop_percent:.1}% since run {previous_run_id} ({} → {count})",
+                    cnode.endpoint, cnode.count
+                ),
+            })
+        })
+        .collect()
+}
+
+/// The alerts that fire for `run_id`, and the rules that were evaluated for it
+#[tracing::instrument(skip(pool, config, deregistered_nodes))]
+async fn evaluate(
+    pool: &PgPool,
+    run_id: i32,
+    config: &AlertSettings,
+    deregistered_nodes: &[String],
+) -> Result<(Vec<Alert>, Vec<&'static str>)> {
+    let mut alerts = Vec::new();
+    let mut rules = Vec::new();
+
+    // Every rule is about users' replica sets, which storage-v2 runs don't check
+    if runs::get_content_protocol(pool, run_id).await? == ContentProtocol::StorageV2 {
+        return Ok((alerts, rules));
+    }
+
+    // A sample's unsynced users aren't comparable to the limit, its estimate is exported instead
+    let is_sampled = runs::is_sampled(pool, run_id).await?;
+    // A scoped run only checked some nodes or users, so rules about every user don't apply
+    let is_scoped = runs::is_scoped(pool, run_id).await?;
+
+    if let Some(max_unsynced_users) = config
+        .max_unsynced_users
+        .filter(|_| !is_sampled && !is_scoped)
+    {
+        rules.push(UNSYNCED_USERS_RULE);
+        let unsynced_users_count = get_unsynced_users_count(pool, run_id).await?;
+        if unsynced_users_count > max_unsynced_users {
+            alerts.push(Alert {
+                fingerprint: UNSYNCED_USERS_RULE.into(),
+                summary: format!(
+                    "{unsynced_users_count} users are unsynced, above the limit of {max_unsynced_users}"
+                ),
+            });
+        }
+    }
+
+    if let Some(max_drop_percent) = config.max_primary_user_drop_percent.filter(|_| !is_scoped) {
+        rules.push(PRIMARY_USER_DROP_RULE);
+        if let Some(previous_run_id) = get_previous_run_id(pool, run_id, false).await? {
+            let previous = get_primary_user_count(pool, previous_run_id).await?;
+            let current = get_primary_user_count(pool, run_id).await?;
+            alerts.extend(primary_user_drops(
+                previous_run_id,
+                &previous,
+                &current,
+                max_drop_percent,
+            ));
+        }
+    }
+
+    if config.unreachable_nodes {
+        rules.push(UNREACHABLE_NODE_RULE);
+        for cnode in get_unreachable_nodes(pool, run_id, deregistered_nodes).await? {
+            alerts.push(Alert {
+                fingerprint: format!("{UNREACHABLE_NODE_RULE}:{}", cnode.endpoint),
+                summary: format!(
+                    "{} didn't return a clock value for any of its {} users",
+                    cnode.endpoint, cnode.count
+                ),
+            });
+        }
+    }
+
+    Ok((alerts, rules))
+}
+
+/// Registered nodes in scope where every user in their replica sets still has the default clock value
+#[tracing::instrument(skip(pool))]
+async fn get_unreachable_nodes(
+    pool: &PgPool,
+    run_id: i32,
+    deregistered_nodes: &[String],
+) -> Result<Vec<CNodeCount>> {
+    let unreachable_nodes = sqlx::query!(
+        r#"
+        SELECT cnodes.endpoint AS endpoint, COUNT(*) AS count
+        FROM network_monitoring_content_nodes AS cnodes
+        JOIN network_monitoring_users AS users
+        ON
+            users.run_id = cnodes.run_id
+        AND
+            cnodes.spid IN (users.primaryspid, users.secondary1spid, users.secondary2spid)
+        WHERE
+            cnodes.run_id = $1
+        AND
+            cnodes.is_in_scope
+        AND
+            cnodes.endpoint <> ALL($2)
+        GROUP BY cnodes.endpoint
+        HAVING COUNT(*) FILTER (
+            WHERE
+                (users.primaryspid = cnodes.spid AND users.primary_clock_value != -1)
+            OR
+                (users.secondary1spid = cnodes.spid AND users.secondary1_clock_value != -1)
+            OR
+                (users.secondary2spid = cnodes.spid AND users.secondary2_clock_value != -1)
+        ) = 0;
+        "#,
+        run_id,
+        deregistered_nodes,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CNodeCount {
+        endpoint: row.endpoint,
+        count: row.count.unwrap_or(0),
+    })
+    .collect::<Vec<CNodeCount>>();
+
+    Ok(unreachable_nodes)
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_sent_alerts(pool: &PgPool) -> Result<Vec<SentAlert>> {
+    let sent_alerts = sqlx::query_as!(
+        SentAlert,
+        r#"
+        SELECT fingerprint, summary, last_notified_at, resolved_at
+        FROM network_monitoring_alerts;
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(sent_alerts)
+}
+
+#[tracing::instrument(skip(pool, firing, notification))]
+async fn save_alerts(
+    pool: &PgPool,
+    run_id: i32,
+    firing: &[Alert],
+    notification: &Notification,
+) -> Result<()> {
+    let (fingerprints, summaries): (Vec<String>, Vec<String>) = notification
+        .firing
+        .iter()
+        .map(|alert| (alert.fingerprint.clone(), alert.summary.clone()))
+        .unzip();
+    let still_firing = firing
+        .iter()
+        .map(|alert| alert.fingerprint.clone())
+        .collect::<Vec<String>>();
+    let resolved = notification
+        .resolved
+        .iter()
+        .map(|alert| alert.fingerprint.clone())
+        .collect::<Vec<String>>();
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_alerts
+        SET last_fired_run_id = $1
+        WHERE fingerprint = ANY($2::text[]);
+        "#,
+        run_id,
+        &still_firing,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    // An alert that fires again after being resolved starts over from this run
+    sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_alerts (fingerprint, summary, first_fired_run_id, last_fired_run_id)
+        SELECT fingerprint, summary, $1, $1
+        FROM UNNEST($2::text[], $3::text[]) AS alerts(fingerprint, summary)
+        ON CONFLICT (fingerprint) DO UPDATE SET
+            summary = EXCLUDED.summary,
+            first_fired_run_id = CASE
+                WHEN network_monitoring_alerts.resolved_at IS NULL
+                THEN network_monitoring_alerts.first_fired_run_id
+                ELSE EXCLUDED.first_fired_run_id
+            END,
+            last_notified_at = NOW(),
+            resolved_at = NULL;
+        "#,
+        run_id,
+        &fingerprints,
+        &summaries,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_alerts
+        SET resolved_at = NOW()
+        WHERE fingerprint = ANY($1::text[]);
+        "#,
+        &resolved,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
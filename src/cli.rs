@@ -0,0 +1,163 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    configuration::{Environment, ScopeSettings},
+    report::ReportArgs,
+    user_check::UserQuery,
+};
+
+/// Check that Audius content nodes hold what discovery says they should
+#[derive(Parser, Debug)]
+#[command(name = "audius_network_monitor", version)]
+pub struct Cli {
+    /// Directory holding `base.yml` and a file per environment
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        default_value = "configuration"
+    )]
+    pub config_dir: PathBuf,
+
+    /// Environment whose file is read over `base.yml`
+    #[arg(
+        long,
+        global = true,
+        env = "APP_ENVIRONMENT",
+        default_value = "stage",
+        value_parser = parse_environment
+    )]
+    pub environment: Environment,
+
+    /// Tracing filter, a level like `debug` or directives like `audius_network_monitor=debug`
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILTER",
+        env = "RUST_LOG",
+        default_value = "info"
+    )]
+    pub log_level: String,
+
+    /// Defaults to `run`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Run every stage once: import discovery, check the content nodes, then generate metrics
+    Run {
+        /// Finish an interrupted run instead of starting a new one
+        #[arg(long, value_name = "RUN_ID")]
+        resume: Option<i32>,
+
+        #[command(flatten)]
+        scope: ScopeArgs,
+    },
+    /// Keep running every stage on the configured schedule
+    Daemon,
+    /// Create a run and only import discovery into it
+    Discover(ScopeArgs),
+    /// Check the content nodes of a run whose discovery was imported
+    CheckContent {
+        run_id: i32,
+
+        #[command(flatten)]
+        scope: ScopeArgs,
+    },
+    /// Generate and export the metrics of a run again
+    Metrics {
+        /// Defaults to the latest run
+        run_id: Option<i32>,
+        /// Generate the metrics of a run whose discovery or content stage didn't succeed
+        #[arg(long)]
+        allow_incomplete: bool,
+    },
+    /// Compare a run to the one before it
+    Report(ReportArgs),
+    /// Check the replica set of a single user live, without creating a run
+    User {
+        /// User id, wallet or handle
+        query: UserQuery,
+    },
+    /// Apply the migrations of the network monitoring DB and exit
+    Migrate,
+}
+
+/// Overrides of the `scope` settings, limiting a run to some content nodes or users
+#[derive(Args, Debug, Default, PartialEq)]
+pub struct ScopeArgs {
+    /// Only check the content node with this spid, can be repeated
+    #[arg(long = "include-spid", value_name = "SPID")]
+    pub include_spids: Vec<i32>,
+
+    /// Only check the content node with this endpoint, can be repeated
+    #[arg(long = "include-endpoint", value_name = "ENDPOINT")]
+    pub include_endpoints: Vec<String>,
+
+    /// Never check the content node with this spid, can be repeated
+    #[arg(long = "exclude-spid", value_name = "SPID")]
+    pub exclude_spids: Vec<i32>,
+
+    /// Never check the content node with this endpoint, can be repeated
+    #[arg(long = "exclude-endpoint", value_name = "ENDPOINT")]
+    pub exclude_endpoints: Vec<String>,
+
+    /// Only import the users with an id of at least this
+    #[arg(long, value_name = "USER_ID")]
+    pub min_user_id: Option<i32>,
+
+    /// Only import the users with an id of at most this
+    #[arg(long, value_name = "USER_ID")]
+    pub max_user_id: Option<i32>,
+
+    /// Only import this percentage of the users
+    #[arg(long, value_name = "PERCENT")]
+    pub sample_percent: Option<f64>,
+
+    /// Only check a random sample of this many users per primary and estimate the rest
+    #[arg(long = "sample-per-primary", value_name = "USERS")]
+    pub sample_users_per_primary: Option<i64>,
+
+    /// Log the requests each content node would get instead of sending them
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl ScopeArgs {
+    /// Override `scope` with the flags that were passed, lists passed replace the configured ones
+    pub fn apply(self, scope: &mut ScopeSettings) {
+        if !self.include_spids.is_empty() {
+            scope.include_spids = self.include_spids;
+        }
+        if !self.include_endpoints.is_empty() {
+            scope.include_endpoints = self.include_endpoints;
+        }
+        if !self.exclude_spids.is_empty() {
+            scope.exclude_spids = self.exclude_spids;
+        }
+        if !self.exclude_endpoints.is_empty() {
+            scope.exclude_endpoints = self.exclude_endpoints;
+        }
+        if self.min_user_id.is_some() {
+            scope.min_user_id = self.min_user_id;
+        }
+        if self.max_user_id.is_some() {
+            scope.max_user_id = self.max_user_id;
+        }
+        if self.sample_percent.is_some() {
+            scope.sample_percent = self.sample_percent;
+        }
+        if self.sample_users_per_primary.is_some() {
+            scope.sample_users_per_primary = self.sample_users_per_primary;
+        }
+        scope.dry_run |= self.dry_run;
+    }
+}
+
+fn parse_environment(environment: &str) -> Result<Environment, String> {
+    Environment::try_from(environment.to_owned())
+}
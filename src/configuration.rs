@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use secrecy::ExposeSecret;
 use secrecy::Secret;
 use serde_aux::prelude::deserialize_number_from_string;
@@ -5,12 +7,20 @@ use sqlx::postgres::PgConnectOptions;
 use sqlx::postgres::PgSslMode;
 use sqlx::ConnectOptions;
 
+use crate::domain::ContentNode;
+
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub foreign_database: DatabaseSettings,
+    pub discovery: DiscoverySettings,
     pub content: ContentSettings,
     pub metrics: MetricsSettings,
+    pub scheduler: SchedulerSettings,
+
+    /// Part of the network a run is limited to, the whole network by default
+    #[serde(default)]
+    pub scope: ScopeSettings,
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
@@ -52,14 +62,254 @@ impl DatabaseSettings {
     }
 }
 
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct DiscoverySettings {
+    /// Where the content node list for each run is read from
+    pub registry: RegistrySource,
+
+    /// A second registry whose content nodes are compared against `registry`
+    #[serde(default)]
+    pub cross_check_registry: Option<RegistrySource>,
+
+    #[serde(default)]
+    pub static_content_nodes: Vec<ContentNode>,
+
+    #[serde(default)]
+    pub chain: Option<ChainSettings>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrySource {
+    Discovery,
+    Static,
+    Chain,
+}
+
+impl RegistrySource {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegistrySource::Discovery => "discovery",
+            RegistrySource::Static => "static",
+            RegistrySource::Chain => "chain",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ChainSettings {
+    pub rpc_url: String,
+    pub service_provider_factory_address: String,
+
+    #[serde(default = "default_service_type")]
+    pub service_type: String,
+}
+
+fn default_service_type() -> String {
+    "content-node".into()
+}
+
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct ContentSettings {
     pub deregistered_nodes: Vec<String>,
 
-    #[serde(deserialize_with = "deserialize_number_from_string")]
+    /// Service provider requests are signed as, only used with `delegate_priv_key`
+    #[serde(default, deserialize_with = "deserialize_number_from_string")]
     pub signature_spid: u16,
 
-    pub delegate_priv_key: Secret<String>,
+    /// Key of the delegate owner wallet of `signature_spid`, requests are sent unsigned without it
+    #[serde(default)]
+    pub delegate_priv_key: Option<Secret<String>>,
+
+    /// How the content nodes of this network are checked
+    #[serde(default)]
+    pub protocol: ContentProtocol,
+
+    #[serde(default)]
+    pub storage_v2: StorageV2Settings,
+
+    /// Client used for every request to a content node
+    #[serde(default)]
+    pub http: HttpSettings,
+
+    /// How hard requests to content nodes are allowed to hit them
+    #[serde(default)]
+    pub limits: LimitSettings,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ScopeSettings {
+    /// Only check the content nodes with these spids or `include_endpoints`, every node if both are empty
+    pub include_spids: Vec<i32>,
+    pub include_endpoints: Vec<String>,
+
+    /// Never check the content nodes with these spids or endpoints
+    pub exclude_spids: Vec<i32>,
+    pub exclude_endpoints: Vec<String>,
+
+    /// Only keep the users with an id in this range, bounds included
+    pub min_user_id: Option<i32>,
+    pub max_user_id: Option<i32>,
+
+    /// Only keep this percentage of the users, always the same ones for a given percentage
+    pub sample_percent: Option<f64>,
+
+    /// Only check a random sample of this many users per primary,
+    /// metrics then estimate the sync status of every user from them
+    pub sample_users_per_primary: Option<i64>,
+
+    /// Work out the requests content nodes would get instead of sending them,
+    /// the run stops before metrics
+    pub dry_run: bool,
+}
+
+impl ScopeSettings {
+    /// Whether `cnode` is checked by runs in this scope
+    #[must_use]
+    pub fn includes(&self, cnode: &ContentNode) -> bool {
+        let included = (self.include_spids.is_empty() && self.include_endpoints.is_empty())
+            || self.include_spids.contains(&cnode.spid)
+            || self.include_endpoints.contains(&cnode.endpoint);
+        let excluded = self.exclude_spids.contains(&cnode.spid)
+            || self.exclude_endpoints.contains(&cnode.endpoint);
+
+        included && !excluded
+    }
+
+    /// Whether runs in this scope check only some of the content nodes
+    #[must_use]
+    pub fn limits_nodes(&self) -> bool {
+        !(self.include_spids.is_empty()
+            && self.include_endpoints.is_empty()
+            && self.exclude_spids.is_empty()
+            && self.exclude_endpoints.is_empty())
+    }
+
+    /// Whether runs in this scope keep only some of the users
+    #[must_use]
+    pub fn limits_users(&self) -> bool {
+        self.min_user_id.is_some() || self.max_user_id.is_some() || self.sample_percent.is_some()
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LimitSettings {
+    /// Requests in flight across every content node
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_in_flight: usize,
+
+    /// Requests in flight to a single content node
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_in_flight_per_endpoint: usize,
+
+    /// Requests sent to a single content node per second, unlimited if unset
+    pub requests_per_second: Option<f64>,
+
+    /// Requests sent to a single content node at once before `requests_per_second` applies
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub burst: u32,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 32,
+            max_in_flight_per_endpoint: 3,
+            requests_per_second: Some(5.0),
+            burst: 5,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentProtocol {
+    /// Users' replica sets are checked with `/users/batch_clock_status`
+    /// and their CIDs with `/batch_cids_exist`
+    #[default]
+    Legacy,
+    /// Blobs are placed by rendezvous hashing and checked one by one
+    StorageV2,
+}
+
+impl ContentProtocol {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentProtocol::Legacy => "legacy",
+            ContentProtocol::StorageV2 => "storage_v2",
+        }
+    }
+
+    #[must_use]
+    pub fn parse(protocol: &str) -> Option<ContentProtocol> {
+        match protocol {
+            "legacy" => Some(ContentProtocol::Legacy),
+            "storage_v2" => Some(ContentProtocol::StorageV2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StorageV2Settings {
+    /// Content nodes each blob is stored on
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub replication_factor: usize,
+}
+
+impl Default for StorageV2Settings {
+    fn default() -> Self {
+        Self {
+            replication_factor: 3,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub connect_timeout_seconds: u64,
+
+    /// Time for a content node to answer a request, including reading the response
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub request_timeout_seconds: u64,
+
+    /// Retries of a request after a timeout, a connection error or a 5xx/429 status
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled for each one after it
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_backoff_millis: u64,
+
+    /// Idle connections kept open to each content node
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_idle_connections: usize,
+
+    pub user_agent: String,
+
+    /// Proxy for every request, e.g. `http://proxy.internal:3128`
+    pub proxy: Option<String>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_seconds: 10,
+            request_timeout_seconds: 120,
+            retries: 3,
+            retry_backoff_millis: 500,
+            max_idle_connections: 8,
+            user_agent: concat!("audius-network-monitor/", env!("CARGO_PKG_VERSION")).into(),
+            proxy: None,
+        }
+    }
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
@@ -67,8 +317,110 @@ pub struct MetricsSettings {
     pub foundation_nodes: Vec<i32>,
     pub push_gateway: String,
     pub slack_url: String,
+
+    /// Push metrics to `push_gateway`, serve them on `/metrics`, or both
+    pub export: MetricsExport,
+    pub server: ServerSettings,
+
+    /// Rules evaluated after every run, firing alerts are posted to `slack_url`
+    #[serde(default)]
+    pub alerts: AlertSettings,
+
+    /// Confidence level of the intervals around the estimates of sampled runs
+    #[serde(default = "default_confidence_level")]
+    pub confidence_level: f64,
+}
+
+fn default_confidence_level() -> f64 {
+    0.95
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AlertSettings {
+    /// Alert when more users than this are unsynced
+    pub max_unsynced_users: Option<i64>,
+
+    /// Alert when a node's primary user count drops by more than this since the previous run
+    pub max_primary_user_drop_percent: Option<f64>,
+
+    /// Alert when a node didn't return a clock value for any of its users
+    pub unreachable_nodes: bool,
+
+    /// Hours before an alert that is still firing is sent again
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub renotify_after_hours: u64,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            max_unsynced_users: None,
+            max_primary_user_drop_percent: None,
+            unreachable_nodes: true,
+            renotify_after_hours: 24,
+        }
+    }
 }
 
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsExport {
+    Push,
+    Pull,
+    Both,
+}
+
+impl MetricsExport {
+    #[must_use]
+    pub fn push(self) -> bool {
+        matches!(self, MetricsExport::Push | MetricsExport::Both)
+    }
+
+    #[must_use]
+    pub fn pull(self) -> bool {
+        matches!(self, MetricsExport::Pull | MetricsExport::Both)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ServerSettings {
+    pub host: String,
+
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct SchedulerSettings {
+    /// Seconds between the start of each run in daemon mode
+    #[serde(default)]
+    pub interval_seconds: Option<u64>,
+
+    /// Cron expression (`sec min hour day month weekday`), takes precedence over `interval_seconds`
+    #[serde(default)]
+    pub cron: Option<String>,
+
+    /// Seconds an in-progress run gets to finish after SIGTERM before it's aborted
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub shutdown_timeout_seconds: u64,
+
+    pub stage_timeouts: StageTimeoutSettings,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct StageTimeoutSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub discovery_seconds: u64,
+
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub content_seconds: u64,
+
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub metrics_seconds: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Environment {
     Stage,
     Production,
@@ -98,21 +450,45 @@ impl TryFrom<String> for Environment {
     }
 }
 
+/// Read the settings from `configuration/base.yml`, the file for the
+/// current `APP_ENVIRONMENT`, and `APP_`-prefixed environment variables
+///
+/// # Errors
+///
+/// Returns an error if a configuration file is missing or the merged
+/// settings fail to deserialize
+///
+/// # Panics
+///
+/// Panics if the current directory can't be determined or
+/// `APP_ENVIRONMENT` isn't a supported environment
 pub fn read() -> Result<Settings, config::ConfigError> {
-    let mut builder = config::Config::builder();
-
     let base_path = std::env::current_dir().expect("Failed to determine the current directory");
 
-    let configuration_directory = base_path.join("configuration");
-
-    builder =
-        builder.add_source(config::File::from(configuration_directory.join("base")).required(true));
-
     let environment: Environment = std::env::var("APP_ENVIRONMENT")
         .unwrap_or_else(|_| "stage".into())
         .try_into()
         .expect("Failed to parse APP_ENVIRONMENT");
 
+    read_from(&base_path.join("configuration"), environment)
+}
+
+/// Read the settings from `base.yml` and `<environment>.yml` in `configuration_directory`,
+/// then `APP_`-prefixed environment variables
+///
+/// # Errors
+///
+/// Returns an error if a configuration file is missing or the merged
+/// settings fail to deserialize
+pub fn read_from(
+    configuration_directory: &Path,
+    environment: Environment,
+) -> Result<Settings, config::ConfigError> {
+    let mut builder = config::Config::builder();
+
+    builder =
+        builder.add_source(config::File::from(configuration_directory.join("base")).required(true));
+
     builder = builder.add_source(
         config::File::from(configuration_directory.join(environment.as_str())).required(true),
     );
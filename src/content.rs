@@ -1,63 +1,497 @@
-use color_eyre::eyre::Result;
-use futures::{future::join_all, stream::futures_unordered::FuturesUnordered};
-use sqlx::PgPool;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use color_eyre::eyre::{eyre, Result};
+use sqlx::{PgExecutor, PgPool};
 use thiserror::Error;
-use tokio::join;
+use tokio::{join, task::JoinSet};
 
 use crate::{
-    configuration::ContentSettings,
-    domain::{ContentNode, WalletClockPair},
-    utils::{make_request, UserStatusPayload},
+    configuration::{ContentProtocol, ContentSettings, ScopeSettings},
+    domain::{CidExists, ContentNode, WalletClockPair},
+    prometheus::{USER_BATCH_DURATION_GAUGE, USER_BATCH_DURATION_HISTOGRAM},
+    runs,
+    signing::Signer,
+    storage_v2::StorageV2Checker,
+    utils::{
+        write_in_chunks, CidsExistPayload, ContentNodeClient, RequestError, UserStatusPayload,
+    },
 };
 
-const BATCH_SIZE: usize = 5_000;
+pub(crate) const BATCH_SIZE: i64 = 5_000;
+/// Clock values are saved in smaller chunks than they are requested in
+const SAVE_CHUNK_SIZE: usize = 500;
+
+const CLOCK_STATUS_ROUTE: &str = "/users/batch_clock_status";
+pub(crate) const CIDS_EXIST_ROUTE: &str = "/batch_cids_exist";
+/// Image directories are checked through a separate route
+/// because the node has to look inside of them
+pub(crate) const IMAGE_CIDS_EXIST_ROUTE: &str = "/batch_image_cids_exist";
 
 #[derive(Debug)]
-enum Replica {
+pub(crate) enum Replica {
     Primary,
     Secondary1,
     Secondary2,
 }
 
+impl Replica {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Replica::Primary => "primary",
+            Replica::Secondary1 => "secondary1",
+            Replica::Secondary2 => "secondary2",
+        }
+    }
+
+    fn parse(replica: &str) -> Option<Replica> {
+        match replica {
+            "primary" => Some(Replica::Primary),
+            "secondary1" => Some(Replica::Secondary1),
+            "secondary2" => Some(Replica::Secondary2),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of request a batch was checked with, checkpointed separately
+#[derive(Debug, Clone, Copy)]
+enum BatchCheck {
+    Clock,
+    Cids,
+    ImageCids,
+}
+
+impl BatchCheck {
+    fn as_str(self) -> &'static str {
+        match self {
+            BatchCheck::Clock => "clock",
+            BatchCheck::Cids => "cids",
+            BatchCheck::ImageCids => "image_cids",
+        }
+    }
+}
+
+/// The step a batch of users failed at
+#[derive(Debug, Clone, Copy)]
+enum BatchErrorKind {
+    /// Reading the batch from the `network_monitoring` DB
+    Fetch,
+    /// Requesting the clock values from the content node
+    Request,
+    /// Saving the clock values
+    Save,
+}
+
+impl BatchErrorKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            BatchErrorKind::Fetch => "fetch",
+            BatchErrorKind::Request => "request",
+            BatchErrorKind::Save => "save",
+        }
+    }
+}
+
+/// A batch of users whose clock values couldn't be checked
+#[derive(Debug)]
+struct MissedBatch<'a> {
+    replica: &'a Replica,
+    spid: i32,
+    batch_offset: i64,
+    /// Empty when the batch couldn't be read
+    wallets: &'a [String],
+    /// Users left unchecked, which includes every later batch when the batch couldn't be read
+    user_count: i64,
+    kind: BatchErrorKind,
+    error: String,
+}
+
 #[derive(Error, Debug)]
-enum ContentNodeError {
+pub(crate) enum ContentNodeError {
     #[error("endpoint string is empty")]
     EndpointIsEmpty,
-    #[error("request to content node failed")]
-    ContentNodeRequestError,
-    #[error("failed to query user counts")]
-    UserCountsIsNone,
+    #[error("request to content node failed: {0}")]
+    ContentNodeRequestError(#[from] RequestError),
 }
 
+/// How the content nodes of a network are checked
+#[async_trait]
+pub trait ContentChecker: Send + Sync {
+    fn protocol(&self) -> ContentProtocol;
+
+    /// Check what `cnode` holds and save it for `run_id`
+    async fn check_node(&self, pool: &PgPool, run_id: i32, cnode: ContentNode) -> Result<()>;
+
+    /// Called once every content node of `run_id` was checked
+    async fn finish(&self, pool: &PgPool, run_id: i32, content_nodes: &[ContentNode])
+        -> Result<()>;
+
+    /// Work out the requests `check_node` would send to `cnode`, without sending any
+    async fn plan_node(
+        &self,
+        pool: &PgPool,
+        run_id: i32,
+        cnode: &ContentNode,
+    ) -> Result<RequestPlan>;
+}
+
+/// Requests a check would send to a content node, by route
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RequestPlan {
+    pub routes: BTreeMap<&'static str, PlannedRequests>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PlannedRequests {
+    pub requests: i64,
+    /// Wallets or CIDs the requests carry
+    pub items: i64,
+}
+
+impl RequestPlan {
+    /// Plan `items` sent in batches of up to `batch_size` to `route`
+    pub fn add_batches(&mut self, route: &'static str, items: i64, batch_size: i64) {
+        if items <= 0 {
+            return;
+        }
+
+        let planned = self.routes.entry(route).or_default();
+        planned.requests += (items + batch_size - 1) / batch_size;
+        planned.items += items;
+    }
+
+    #[must_use]
+    pub fn requests(&self) -> i64 {
+        self.routes.values().map(|planned| planned.requests).sum()
+    }
+}
+
+impl fmt::Display for RequestPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let routes = self
+            .routes
+            .iter()
+            .map(|(route, planned)| {
+                format!("{} to {route} ({} items)", planned.requests, planned.items)
+            })
+            .collect::<Vec<String>>();
+
+        if routes.is_empty() {
+            f.write_str("no requests")
+        } else {
+            f.write_str(&routes.join(", "))
+        }
+    }
+}
+
+/// Build the checker for the protocol of the network
+///
+/// # Errors
+///
+/// Returns an error if the delegate key is invalid or the HTTP client can't be built
+pub fn from_settings(config: &ContentSettings) -> Result<Arc<dyn ContentChecker>> {
+    let client = client_from_settings(config)?;
+
+    let checker: Arc<dyn ContentChecker> = match config.protocol {
+        ContentProtocol::Legacy => Arc::new(LegacyChecker::new(client)),
+        ContentProtocol::StorageV2 => Arc::new(StorageV2Checker::new(
+            client,
+            config.storage_v2.replication_factor,
+            config.deregistered_nodes.clone(),
+        )),
+    };
+
+    Ok(checker)
+}
+
+/// Build the client every request to a content node is sent with,
+/// signed with the delegate key when one is configured
+///
+/// # Errors
+///
+/// Returns an error if the delegate key is invalid or the HTTP client can't be built
+pub fn client_from_settings(config: &ContentSettings) -> Result<ContentNodeClient> {
+    let signer = if let Some(key) = &config.delegate_priv_key {
+        Some(Signer::new(config.signature_spid, key)?)
+    } else {
+        tracing::warn!(
+            "`content.delegate_priv_key` is not set, routes gated to service providers will refuse requests"
+        );
+        None
+    };
+    let client = ContentNodeClient::new(&config.http, &config.limits, signer)?;
+
+    Ok(client)
+}
+
+/// Check every content node of `run_id` with the protocol of the network.
+/// Batches that were already saved by an interrupted attempt at this run are skipped.
+///
+/// # Errors
+///
+/// Returns an error if the checker can't be built, the content nodes of the run can't be read
+/// or any of them fails to be checked
 #[tracing::instrument(skip(pool))]
-pub async fn index(pool: &PgPool, run_id: i32, config: ContentSettings) -> Result<()> {
+pub async fn index(
+    pool: &PgPool,
+    run_id: i32,
+    config: ContentSettings,
+    scope: ScopeSettings,
+) -> Result<()> {
+    let checker = from_settings(&config)?;
     let content_nodes = get_content_nodes(pool, run_id).await?;
 
-    let tasks = content_nodes
-        .into_iter()
-        .filter_map(|cnode| {
-            if config.deregistered_nodes.contains(&cnode.endpoint) {
-                tracing::info!("skipping {} because it is deregistered", cnode.endpoint);
-                return None;
+    tracing::info!(
+        "checking {} content nodes with the {} protocol",
+        content_nodes.len(),
+        checker.protocol().as_str()
+    );
+    runs::set_content_protocol(pool, run_id, checker.protocol()).await?;
+
+    if scope.limits_nodes() {
+        let out_of_scope = content_nodes
+            .iter()
+            .filter(|cnode| !scope.includes(cnode))
+            .map(|cnode| cnode.spid)
+            .collect::<Vec<i32>>();
+        save_scope(pool, run_id, &out_of_scope).await?;
+        runs::mark_scoped(pool, run_id).await?;
+    }
+
+    // Tasks in a `JoinSet` are aborted when it's dropped,
+    // so cancelling `index` stops every content node check
+    let mut tasks = JoinSet::new();
+    for cnode in content_nodes.iter().cloned() {
+        if config.deregistered_nodes.contains(&cnode.endpoint) {
+            tracing::info!("skipping {} because it is deregistered", cnode.endpoint);
+            continue;
+        }
+        if !scope.includes(&cnode) {
+            tracing::info!("skipping {} because it is out of scope", cnode.endpoint);
+            continue;
+        }
+        if scope.dry_run {
+            let plan = checker.plan_node(pool, run_id, &cnode).await?;
+            tracing::info!(
+                "dry run, {} would get {} requests: {plan}",
+                cnode.endpoint,
+                plan.requests()
+            );
+            continue;
+        }
+
+        let pool = pool.clone();
+        let checker = checker.clone();
+        tasks.spawn(async move {
+            let endpoint = cnode.endpoint.clone();
+            (endpoint, checker.check_node(&pool, run_id, cnode).await)
+        });
+    }
+
+    let mut failed_checks = 0;
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((_, Ok(()))) => {}
+            Ok((endpoint, Err(e))) => {
+                tracing::error!("error checking {endpoint} {:?}", e);
+                failed_checks += 1;
+            }
+            Err(e) => {
+                tracing::error!("content node check panicked or was cancelled {:?}", e);
+                failed_checks += 1;
             }
+        }
+    }
+    // The stage fails so a resumed run checks the nodes again
+    if failed_checks > 0 {
+        return Err(eyre!("{failed_checks} content node checks failed"));
+    }
 
-            let pool_clone = pool.clone();
-            Some(tokio::spawn(async move {
-                check_users(pool_clone, run_id, cnode).await
-            }))
-        })
-        .collect::<FuturesUnordered<_>>();
+    if !scope.dry_run {
+        checker.finish(pool, run_id, &content_nodes).await?;
+    }
 
-    join_all(tasks).await;
+    Ok(())
+}
+
+/// Record which content nodes of `run_id` are out of scope,
+/// a resumed run may have been given another scope
+#[tracing::instrument(skip(pool))]
+async fn save_scope(pool: &PgPool, run_id: i32, out_of_scope: &[i32]) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_content_nodes
+        SET is_in_scope = NOT (spid = ANY($2::int[]))
+        WHERE run_id = $1;
+        "#,
+        run_id,
+        out_of_scope,
+    )
+    .execute(pool)
+    .await?;
 
     Ok(())
 }
 
+/// Checks users' replica sets with `/users/batch_clock_status`, then their CIDs,
+/// and retries the batches of users that were missed
+pub struct LegacyChecker {
+    client: ContentNodeClient,
+}
+
+impl LegacyChecker {
+    #[must_use]
+    pub fn new(client: ContentNodeClient) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl ContentChecker for LegacyChecker {
+    fn protocol(&self) -> ContentProtocol {
+        ContentProtocol::Legacy
+    }
+
+    async fn check_node(&self, pool: &PgPool, run_id: i32, cnode: ContentNode) -> Result<()> {
+        let users_result =
+            check_users(pool.clone(), run_id, cnode.clone(), self.client.clone()).await;
+        let cids_result =
+            Box::pin(check_cids(pool.clone(), run_id, cnode, self.client.clone())).await;
+
+        users_result.and(cids_result)
+    }
+
+    async fn finish(
+        &self,
+        pool: &PgPool,
+        run_id: i32,
+        content_nodes: &[ContentNode],
+    ) -> Result<()> {
+        retry_missed_batches(pool, run_id, content_nodes, &self.client).await
+    }
+
+    /// Each replica's users and CIDs are requested in their own batches
+    async fn plan_node(
+        &self,
+        pool: &PgPool,
+        run_id: i32,
+        cnode: &ContentNode,
+    ) -> Result<RequestPlan> {
+        let mut plan = RequestPlan::default();
+
+        for replica_count in get_replica_counts(pool, run_id, cnode.spid).await? {
+            plan.add_batches(CLOCK_STATUS_ROUTE, replica_count.users, BATCH_SIZE);
+            plan.add_batches(CIDS_EXIST_ROUTE, replica_count.cids, BATCH_SIZE);
+            plan.add_batches(IMAGE_CIDS_EXIST_ROUTE, replica_count.dir_cids, BATCH_SIZE);
+        }
+
+        Ok(plan)
+    }
+}
+
+/// Users and CIDs a content node is a replica for
+#[derive(Debug)]
+struct ReplicaCount {
+    users: i64,
+    cids: i64,
+    dir_cids: i64,
+}
+
+/// The users `spid` is each replica for, with their CIDs
 #[tracing::instrument(skip(pool))]
-async fn get_content_nodes(pool: &PgPool, run_id: i32) -> Result<Vec<ContentNode>> {
+async fn get_replica_counts(pool: &PgPool, run_id: i32, spid: i32) -> Result<Vec<ReplicaCount>> {
+    let replica_counts = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(DISTINCT users.user_id) AS users,
+            COUNT(cids.cid) FILTER (WHERE cids.ctype != 'dir') AS cids,
+            COUNT(cids.cid) FILTER (WHERE cids.ctype = 'dir') AS dir_cids
+        FROM network_monitoring_users AS users
+        CROSS JOIN LATERAL (
+            VALUES
+                ('primary', users.primaryspid),
+                ('secondary1', users.secondary1spid),
+                ('secondary2', users.secondary2spid)
+        ) AS replicas(replica, spid)
+        LEFT JOIN network_monitoring_cids_from_discovery AS cids
+        ON
+            cids.run_id = users.run_id
+        AND
+            cids.user_id = users.user_id
+        WHERE
+            users.run_id = $1
+        AND
+            replicas.spid = $2
+        GROUP BY replicas.replica;
+        "#,
+        run_id,
+        spid,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| ReplicaCount {
+        users: row.users.unwrap_or(0),
+        cids: row.cids.unwrap_or(0),
+        dir_cids: row.dir_cids.unwrap_or(0),
+    })
+    .collect::<Vec<ReplicaCount>>();
+
+    Ok(replica_counts)
+}
+
+/// Check the replicas that missed batches again.
+/// Saved batches have a checkpoint, so only the missed ones are requested again.
+#[tracing::instrument(skip(pool, content_nodes, client))]
+async fn retry_missed_batches(
+    pool: &PgPool,
+    run_id: i32,
+    content_nodes: &[ContentNode],
+    client: &ContentNodeClient,
+) -> Result<()> {
+    let missed_replicas = get_missed_replicas(pool, run_id).await?;
+    if missed_replicas.is_empty() {
+        return Ok(());
+    }
+
+    tracing::info!(
+        "retrying the missed batches of {} replicas",
+        missed_replicas.len()
+    );
+
+    let mut tasks = JoinSet::new();
+    for (spid, replica) in missed_replicas {
+        let Some(replica) = Replica::parse(&replica) else {
+            continue;
+        };
+        let Some(cnode) = content_nodes.iter().find(|cnode| cnode.spid == spid) else {
+            continue;
+        };
+
+        let pool = pool.clone();
+        let client = client.clone();
+        let endpoint = cnode.endpoint.clone();
+        tasks.spawn(async move {
+            check_replica(&replica, &pool, run_id, spid, &endpoint, &client).await
+        });
+    }
+
+    while tasks.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_content_nodes(pool: &PgPool, run_id: i32) -> Result<Vec<ContentNode>> {
     let content_nodes = sqlx::query!(
         r#"
-        SELECT spid, endpoint
+        SELECT spid, endpoint, owner_wallet, delegate_owner_wallet
         FROM network_monitoring_content_nodes
         WHERE run_id = $1; 
         "#,
@@ -69,6 +503,8 @@ async fn get_content_nodes(pool: &PgPool, run_id: i32) -> Result<Vec<ContentNode
     .map(|row| ContentNode {
         endpoint: row.endpoint,
         spid: row.spid,
+        owner_wallet: row.owner_wallet,
+        delegate_owner_wallet: row.delegate_owner_wallet,
     })
     .collect::<Vec<ContentNode>>();
 
@@ -78,38 +514,34 @@ async fn get_content_nodes(pool: &PgPool, run_id: i32) -> Result<Vec<ContentNode
 /// In parallel, for every replica in a user's replica set (primary, secondary1, secondary2)
 /// that equals the current content node endpoint (`cnode`)
 /// 1. Get the user's wallets
-/// 2. Get the clock value for that user from the content node 
-/// 3. Save the clock value in the network_monitoring DB
-#[tracing::instrument(skip(pool))]
-async fn check_users(pool: PgPool, run_id: i32, cnode: ContentNode) -> Result<()> {
-    let ContentNode { spid, endpoint } = cnode;
-    let (primary_count, secondary1_count, secondary2_count) =
-        get_user_counts(&pool, run_id, spid).await?;
+/// 2. Get the clock value for that user from the content node
+/// 3. Save the clock value in the `network_monitoring` DB
+#[tracing::instrument(skip(pool, client))]
+async fn check_users(
+    pool: PgPool,
+    run_id: i32,
+    cnode: ContentNode,
+    client: ContentNodeClient,
+) -> Result<()> {
+    let ContentNode { spid, endpoint, .. } = cnode;
 
     let (primary_result, secondary1_result, secondary2_result) = join!(
-        check_replica(
-            &Replica::Primary,
-            &pool,
-            run_id,
-            primary_count,
-            spid,
-            &endpoint
-        ),
+        check_replica(&Replica::Primary, &pool, run_id, spid, &endpoint, &client),
         check_replica(
             &Replica::Secondary1,
             &pool,
             run_id,
-            secondary1_count,
             spid,
-            &endpoint
+            &endpoint,
+            &client
         ),
         check_replica(
             &Replica::Secondary2,
             &pool,
             run_id,
-            secondary2_count,
             spid,
-            &endpoint
+            &endpoint,
+            &client
         ),
     );
 
@@ -120,105 +552,145 @@ async fn check_users(pool: PgPool, run_id: i32, cnode: ContentNode) -> Result<()
     Ok(())
 }
 
-#[tracing::instrument(skip(pool))]
-async fn get_user_counts(
-    pool: &PgPool,
-    run_id: i32,
-    spid: i32,
-) -> Result<(i64, i64, i64), ContentNodeError> {
-    let user_counts = sqlx::query!(
-        r#"
-        SELECT primary_group.spid as spid, primary_count, secondary1_count, secondary2_count
-        FROM (
-            (
-                SELECT primaryspid AS spid, COUNT(*) AS primary_count
-                FROM network_monitoring_users 
-                WHERE run_id = $1
-                GROUP BY primaryspid
-            ) as primary_group
-            JOIN 
-            (
-                SELECT secondary1spid AS spid, COUNT(*) AS secondary1_count 
-                FROM network_monitoring_users 
-                WHERE run_id = $1
-                GROUP BY secondary1spid
-            ) secondary1_group
-            ON primary_group.spid = secondary1_group.spid
-            JOIN
-            (
-                SELECT secondary2spid AS spid, COUNT(*) AS secondary2_count 
-                FROM network_monitoring_users 
-                WHERE run_id = $1
-                GROUP BY secondary2spid
-            ) secondary2_group
-            ON primary_group.spid = secondary2_group.spid
-        )
-        WHERE primary_group.spid = $2; 
-    "#,
-        run_id,
-        spid
-    )
-    .fetch_one(pool)
-    .await
-    .map_err(|_| ContentNodeError::UserCountsIsNone)?;
-
-    Ok((
-        user_counts
-            .primary_count
-            .ok_or(ContentNodeError::UserCountsIsNone)?,
-        user_counts
-            .secondary1_count
-            .ok_or(ContentNodeError::UserCountsIsNone)?,
-        user_counts
-            .secondary2_count
-            .ok_or(ContentNodeError::UserCountsIsNone)?,
-    ))
-}
-
-#[tracing::instrument(skip(pool))]
+#[tracing::instrument(skip(pool, client))]
 async fn check_replica(
     replica: &Replica,
     pool: &PgPool,
     run_id: i32,
-    count: i64,
     spid: i32,
     endpoint: &str,
+    client: &ContentNodeClient,
 ) -> Result<()> {
-    for offset in (0..count).step_by(BATCH_SIZE) {
-        let wallet_batch = match get_batch(replica, pool, run_id, spid, offset).await {
+    let saved_batches = get_checkpoints(pool, run_id, spid, replica, BatchCheck::Clock).await?;
+    let missed_batches = get_missed_batches(pool, run_id, spid, replica).await?;
+    let user_count = get_replica_user_count(pool, run_id, spid, replica).await?;
+
+    // The users of a run don't change once discovery is imported, so paging by `user_id`
+    // always cuts the same batches and their offset still identifies them for checkpoints
+    let mut offset = 0;
+    let mut after_user_id = i32::MIN;
+    let mut paged_users = 0;
+    loop {
+        let batch_offset = offset;
+        let started = Instant::now();
+        let user_batch = match get_batch(replica, pool, run_id, spid, after_user_id).await {
             Ok(batch) => batch,
             Err(e) => {
                 tracing::error!("error getting batch {:?}", e);
-                continue;
+                // Later batches can't be found without this one, they're read again on retry
+                save_missed_batch(
+                    pool,
+                    run_id,
+                    &MissedBatch {
+                        replica,
+                        spid,
+                        batch_offset,
+                        wallets: &[],
+                        user_count: user_count - paged_users,
+                        kind: BatchErrorKind::Fetch,
+                        error: format!("{e:#}"),
+                    },
+                )
+                .await;
+                observe_batch(endpoint, replica, BatchErrorKind::Fetch.as_str(), started);
+                break;
             }
         };
 
-        if wallet_batch.is_empty() {
+        let Some(last_user) = user_batch.last() else {
+            break;
+        };
+        after_user_id = last_user.user_id;
+        offset += BATCH_SIZE;
+        paged_users += i64::try_from(user_batch.len()).unwrap_or(i64::MAX);
+
+        if saved_batches.contains(&batch_offset) {
             continue;
         }
 
-        let clock_values = match get_user_clock_values(endpoint, wallet_batch).await {
+        let wallet_batch = user_batch
+            .into_iter()
+            .map(|user| user.wallet)
+            .collect::<Vec<String>>();
+
+        let clock_values = match get_user_clock_values(client, endpoint, wallet_batch.clone()).await
+        {
             Ok(values) => values,
             Err(e) => {
                 tracing::error!("error getting clock values {:?}", e);
+                save_missed_batch(
+                    pool,
+                    run_id,
+                    &MissedBatch {
+                        replica,
+                        spid,
+                        batch_offset,
+                        wallets: &wallet_batch,
+                        user_count: i64::try_from(wallet_batch.len()).unwrap_or(i64::MAX),
+                        kind: BatchErrorKind::Request,
+                        error: format!("{e:#}"),
+                    },
+                )
+                .await;
+                observe_batch(endpoint, replica, BatchErrorKind::Request.as_str(), started);
                 continue;
             }
         };
 
-        match save_batch(replica, pool, run_id, spid, &clock_values).await {
-            Ok(_) => (),
-            Err(e) => {
-                tracing::error!("error saving clock values {:?}", e);
-                continue;
+        if let Err(e) = save_batch(replica, pool, run_id, spid, &clock_values).await {
+            tracing::error!("error saving clock values {:?}", e);
+            save_missed_batch(
+                pool,
+                run_id,
+                &MissedBatch {
+                    replica,
+                    spid,
+                    batch_offset,
+                    wallets: &wallet_batch,
+                    user_count: i64::try_from(wallet_batch.len()).unwrap_or(i64::MAX),
+                    kind: BatchErrorKind::Save,
+                    error: format!("{e:#}"),
+                },
+            )
+            .await;
+            observe_batch(endpoint, replica, BatchErrorKind::Save.as_str(), started);
+            continue;
+        }
+
+        let elapsed = started.elapsed();
+        observe_batch(endpoint, replica, "succeeded", started);
+        USER_BATCH_DURATION_GAUGE
+            .with_label_values(&[&run_id.to_string(), endpoint])
+            .set(i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX));
+
+        // Clock updates are idempotent, so a crash before the checkpoint only redoes this batch
+        if let Err(e) =
+            save_checkpoint(pool, run_id, spid, replica, BatchCheck::Clock, batch_offset).await
+        {
+            tracing::error!("error saving checkpoint {:?}", e);
+        }
+
+        if missed_batches.contains(&batch_offset) {
+            if let Err(e) = delete_missed_batch(pool, run_id, spid, replica, batch_offset).await {
+                tracing::error!("error deleting missed batch {:?}", e);
             }
-        };
+        }
     }
 
     Ok(())
 }
 
-#[tracing::instrument(skip(wallet_batch))]
-async fn get_user_clock_values(
+/// Time a user batch of `replica` on `endpoint` under `status`,
+/// `succeeded` or the step it failed at
+fn observe_batch(endpoint: &str, replica: &Replica, status: &str, started: Instant) {
+    USER_BATCH_DURATION_HISTOGRAM
+        .with_label_values(&[endpoint, replica.as_str(), status])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+#[tracing::instrument(skip(client, wallet_batch))]
+pub(crate) async fn get_user_clock_values(
+    client: &ContentNodeClient,
     endpoint: &str,
     wallet_batch: Vec<String>,
 ) -> Result<Vec<WalletClockPair>, ContentNodeError> {
@@ -226,88 +698,125 @@ async fn get_user_clock_values(
         return Err(ContentNodeError::EndpointIsEmpty);
     }
 
-    let route = "/users/batch_clock_status";
-    let url = format!("{endpoint}{route}");
-
     let payload = UserStatusPayload {
         wallet_public_keys: wallet_batch,
     };
-    let results = make_request(&url, &payload)
-        .await
-        .map_err(|_| ContentNodeError::ContentNodeRequestError)?;
+    let results = client
+        .make_request(endpoint, CLOCK_STATUS_ROUTE, &payload)
+        .await?;
 
     Ok(results)
 }
 
+/// The users `spid` is `replica` for, counted before paging through them
+/// so the users a failed read skips are known
+#[tracing::instrument(skip(pool))]
+async fn get_replica_user_count(
+    pool: &PgPool,
+    run_id: i32,
+    spid: i32,
+    replica: &Replica,
+) -> Result<i64> {
+    let user_count = sqlx::query!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM network_monitoring_users
+        WHERE run_id = $1
+        AND CASE $3
+            WHEN 'primary' THEN primaryspid
+            WHEN 'secondary1' THEN secondary1spid
+            ELSE secondary2spid
+        END = $2;
+        "#,
+        run_id,
+        spid,
+        replica.as_str(),
+    )
+    .fetch_one(pool)
+    .await?
+    .count;
+
+    Ok(user_count)
+}
+
+#[derive(Debug)]
+struct UserWallet {
+    user_id: i32,
+    wallet: String,
+}
+
+/// The next batch of users with `spid` as their `replica`, after `after_user_id`.
+/// Seeking past the last user of the previous batch stays as fast as the first batch,
+/// where an `OFFSET` would scan every user before it.
 #[tracing::instrument(skip(pool))]
 async fn get_batch(
     replica: &Replica,
     pool: &PgPool,
     run_id: i32,
     spid: i32,
-    offset: i64,
-) -> Result<Vec<String>> {
+    after_user_id: i32,
+) -> Result<Vec<UserWallet>> {
     let batch = match replica {
-        Replica::Primary => sqlx::query!(
-            r#"
-                    SELECT wallet 
+        Replica::Primary => {
+            sqlx::query_as!(
+                UserWallet,
+                r#"
+                    SELECT user_id, wallet AS "wallet!"
                     FROM network_monitoring_users
                     WHERE run_id = $1
                     AND primaryspid = $2
-                    ORDER BY user_id 
-                    OFFSET $3
-                    LIMIT $4; 
+                    AND user_id > $3
+                    ORDER BY user_id
+                    LIMIT $4;
                 "#,
-            run_id,
-            spid,
-            offset,
-            BATCH_SIZE as i64,
-        )
-        .fetch_all(pool)
-        .await?
-        .into_iter()
-        .map(|row| row.wallet)
-        .collect::<Vec<String>>(),
-        Replica::Secondary1 => sqlx::query!(
-            r#"
-                    SELECT wallet 
+                run_id,
+                spid,
+                after_user_id,
+                BATCH_SIZE,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        Replica::Secondary1 => {
+            sqlx::query_as!(
+                UserWallet,
+                r#"
+                    SELECT user_id, wallet AS "wallet!"
                     FROM network_monitoring_users
                     WHERE run_id = $1
                     AND secondary1spid = $2
-                    ORDER BY user_id 
-                    OFFSET $3
-                    LIMIT $4; 
+                    AND user_id > $3
+                    ORDER BY user_id
+                    LIMIT $4;
                 "#,
-            run_id,
-            spid,
-            offset,
-            BATCH_SIZE as i64,
-        )
-        .fetch_all(pool)
-        .await?
-        .into_iter()
-        .map(|row| row.wallet)
-        .collect::<Vec<String>>(),
-        Replica::Secondary2 => sqlx::query!(
-            r#"
-                    SELECT wallet 
+                run_id,
+                spid,
+                after_user_id,
+                BATCH_SIZE,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        Replica::Secondary2 => {
+            sqlx::query_as!(
+                UserWallet,
+                r#"
+                    SELECT user_id, wallet AS "wallet!"
                     FROM network_monitoring_users
                     WHERE run_id = $1
                     AND secondary2spid = $2
-                    ORDER BY user_id 
-                    OFFSET $3
-                    LIMIT $4; 
+                    AND user_id > $3
+                    ORDER BY user_id
+                    LIMIT $4;
                 "#,
-            run_id,
-            spid,
-            offset,
-            BATCH_SIZE as i64,
-        )
-        .fetch_all(pool)
-        .await?
-        .into_iter()
-        .map(|row| row.wallet)
-        .collect::<Vec<String>>(),
+                run_id,
+                spid,
+                after_user_id,
+                BATCH_SIZE,
+            )
+            .fetch_all(pool)
+            .await?
+        }
     };
 
     Ok(batch)
@@ -319,21 +828,10 @@ async fn save_batch(
     pool: &PgPool,
     run_id: i32,
     spid: i32,
-    clock_values: &Vec<WalletClockPair>,
+    clock_values: &[WalletClockPair],
 ) -> Result<()> {
-    let mini_batch_size = 500;
-    let count = clock_values.len();
-
-    for offset in (0..count).step_by(mini_batch_size) {
-        let end = if (offset + mini_batch_size) >= count {
-            count - 1
-        } else {
-            offset + mini_batch_size
-        };
-
-        let mini_batch = &clock_values[offset..end];
-
-        let (wallets, clocks): (Vec<String>, Vec<i32>) = mini_batch
+    write_in_chunks(clock_values, SAVE_CHUNK_SIZE, |chunk| async move {
+        let (wallets, clocks): (Vec<String>, Vec<i32>) = chunk
             .iter()
             .map(|pair| (pair.wallet_public_key.clone(), pair.clock))
             .unzip();
@@ -384,7 +882,554 @@ async fn save_batch(
         }
         .execute(pool)
         .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+/// In parallel, for every replica in a user's replica set (primary, secondary1, secondary2)
+/// that equals the current content node endpoint (`cnode`)
+/// 1. Get the CIDs discovery says the user's content should have
+/// 2. Ask the content node which of those CIDs it has
+/// 3. Save the CIDs the content node has in the `network_monitoring` DB
+#[tracing::instrument(skip(pool, client))]
+async fn check_cids(
+    pool: PgPool,
+    run_id: i32,
+    cnode: ContentNode,
+    client: ContentNodeClient,
+) -> Result<()> {
+    let ContentNode { spid, endpoint, .. } = cnode;
+
+    let (primary_result, secondary1_result, secondary2_result) = join!(
+        check_replica_cids(&Replica::Primary, &pool, run_id, spid, &endpoint, &client),
+        check_replica_cids(
+            &Replica::Secondary1,
+            &pool,
+            run_id,
+            spid,
+            &endpoint,
+            &client
+        ),
+        check_replica_cids(
+            &Replica::Secondary2,
+            &pool,
+            run_id,
+            spid,
+            &endpoint,
+            &client
+        ),
+    );
+
+    primary_result?;
+    secondary1_result?;
+    secondary2_result?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool, client))]
+async fn check_replica_cids(
+    replica: &Replica,
+    pool: &PgPool,
+    run_id: i32,
+    spid: i32,
+    endpoint: &str,
+    client: &ContentNodeClient,
+) -> Result<()> {
+    let saved_cid_batches = get_checkpoints(pool, run_id, spid, replica, BatchCheck::Cids).await?;
+    let saved_image_cid_batches =
+        get_checkpoints(pool, run_id, spid, replica, BatchCheck::ImageCids).await?;
+
+    // Like users, the CIDs of a run don't change once discovery is imported,
+    // so their offset still identifies the batches for checkpoints
+    let mut offset = 0;
+    let mut after_cid = String::new();
+    let mut after_user_id = i32::MIN;
+    loop {
+        let batch_offset = offset;
+        let cid_batch =
+            match get_cid_batch(replica, pool, run_id, spid, &after_cid, after_user_id).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    tracing::error!("error getting cid batch {:?}", e);
+                    // Later batches can't be found without this one, so the check fails
+                    // and a resumed run reads them again
+                    return Err(e);
+                }
+            };
+
+        let Some(last_cid) = cid_batch.last() else {
+            break;
+        };
+        after_cid.clone_from(&last_cid.cid);
+        after_user_id = last_cid.user_id;
+        offset += BATCH_SIZE;
+
+        let (dir_cids, cids): (Vec<ExpectedCid>, Vec<ExpectedCid>) = cid_batch
+            .into_iter()
+            .partition(|expected| expected.ctype == "dir");
+
+        for (check, saved_batches, route, expected_cids) in [
+            (BatchCheck::Cids, &saved_cid_batches, CIDS_EXIST_ROUTE, cids),
+            (
+                BatchCheck::ImageCids,
+                &saved_image_cid_batches,
+                IMAGE_CIDS_EXIST_ROUTE,
+                dir_cids,
+            ),
+        ] {
+            if expected_cids.is_empty() || saved_batches.contains(&batch_offset) {
+                continue;
+            }
+
+            let cids = expected_cids
+                .iter()
+                .map(|expected| expected.cid.clone())
+                .collect::<Vec<String>>();
+
+            let cids_exist = match get_cids_exist(client, endpoint, route, cids).await {
+                Ok(values) => values,
+                Err(e) => {
+                    tracing::error!("error checking cids {:?}", e);
+                    // Without a checkpoint, a resumed run asks for the batch again
+                    if let Err(e) = save_unchecked_cids(pool, run_id, spid, &expected_cids).await {
+                        tracing::error!("error saving unchecked cids {:?}", e);
+                    }
+                    continue;
+                }
+            };
+
+            // Inserting CIDs isn't idempotent, so they're saved with their checkpoint
+            let saved = async {
+                let mut tx = pool.begin().await?;
+                save_cids(&mut tx, run_id, spid, &expected_cids, &cids_exist).await?;
+                delete_unchecked_cids(&mut tx, run_id, spid, &expected_cids).await?;
+                save_checkpoint(&mut tx, run_id, spid, replica, check, batch_offset).await?;
+                tx.commit().await?;
+
+                Ok::<(), color_eyre::eyre::Error>(())
+            };
+
+            if let Err(e) = saved.await {
+                tracing::error!("error saving cids {:?}", e);
+                if let Err(e) = save_unchecked_cids(pool, run_id, spid, &expected_cids).await {
+                    tracing::error!("error saving unchecked cids {:?}", e);
+                }
+            }
+        }
     }
 
     Ok(())
 }
+
+#[derive(Debug)]
+pub(crate) struct ExpectedCid {
+    pub(crate) cid: String,
+    pub(crate) user_id: i32,
+    pub(crate) ctype: String,
+}
+
+#[tracing::instrument(skip(client, cids))]
+pub(crate) async fn get_cids_exist(
+    client: &ContentNodeClient,
+    endpoint: &str,
+    route: &str,
+    cids: Vec<String>,
+) -> Result<Vec<CidExists>, ContentNodeError> {
+    if endpoint.is_empty() {
+        return Err(ContentNodeError::EndpointIsEmpty);
+    }
+
+    let payload = CidsExistPayload { cids };
+    let results = client.make_cids_request(endpoint, route, &payload).await?;
+
+    Ok(results)
+}
+
+/// The next batch of CIDs of the users with `spid` as their `replica`,
+/// after `after_cid` and `after_user_id`
+#[tracing::instrument(skip(pool))]
+async fn get_cid_batch(
+    replica: &Replica,
+    pool: &PgPool,
+    run_id: i32,
+    spid: i32,
+    after_cid: &str,
+    after_user_id: i32,
+) -> Result<Vec<ExpectedCid>> {
+    let batch = match replica {
+        Replica::Primary => {
+            sqlx::query_as!(
+                ExpectedCid,
+                r#"
+                    SELECT cids.cid, cids.user_id, cids.ctype
+                    FROM network_monitoring_cids_from_discovery AS cids
+                    JOIN network_monitoring_users AS users
+                    ON cids.run_id = users.run_id
+                    AND cids.user_id = users.user_id
+                    WHERE cids.run_id = $1
+                    AND users.primaryspid = $2
+                    AND (cids.cid, cids.user_id) > ($3, $4)
+                    ORDER BY cids.cid, cids.user_id
+                    LIMIT $5;
+                "#,
+                run_id,
+                spid,
+                after_cid,
+                after_user_id,
+                BATCH_SIZE,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        Replica::Secondary1 => {
+            sqlx::query_as!(
+                ExpectedCid,
+                r#"
+                    SELECT cids.cid, cids.user_id, cids.ctype
+                    FROM network_monitoring_cids_from_discovery AS cids
+                    JOIN network_monitoring_users AS users
+                    ON cids.run_id = users.run_id
+                    AND cids.user_id = users.user_id
+                    WHERE cids.run_id = $1
+                    AND users.secondary1spid = $2
+                    AND (cids.cid, cids.user_id) > ($3, $4)
+                    ORDER BY cids.cid, cids.user_id
+                    LIMIT $5;
+                "#,
+                run_id,
+                spid,
+                after_cid,
+                after_user_id,
+                BATCH_SIZE,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        Replica::Secondary2 => {
+            sqlx::query_as!(
+                ExpectedCid,
+                r#"
+                    SELECT cids.cid, cids.user_id, cids.ctype
+                    FROM network_monitoring_cids_from_discovery AS cids
+                    JOIN network_monitoring_users AS users
+                    ON cids.run_id = users.run_id
+                    AND cids.user_id = users.user_id
+                    WHERE cids.run_id = $1
+                    AND users.secondary2spid = $2
+                    AND (cids.cid, cids.user_id) > ($3, $4)
+                    ORDER BY cids.cid, cids.user_id
+                    LIMIT $5;
+                "#,
+                run_id,
+                spid,
+                after_cid,
+                after_user_id,
+                BATCH_SIZE,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+    };
+
+    Ok(batch)
+}
+
+#[tracing::instrument(skip(executor, expected_cids, cids_exist))]
+pub(crate) async fn save_cids(
+    executor: impl PgExecutor<'_>,
+    run_id: i32,
+    spid: i32,
+    expected_cids: &[ExpectedCid],
+    cids_exist: &[CidExists],
+) -> Result<()> {
+    let existing = cids_exist
+        .iter()
+        .filter(|cid_exists| cid_exists.exists)
+        .map(|cid_exists| cid_exists.cid.as_str())
+        .collect::<HashSet<&str>>();
+
+    let (cids, user_ids): (Vec<String>, Vec<i32>) = expected_cids
+        .iter()
+        .filter(|expected| existing.contains(expected.cid.as_str()))
+        .map(|expected| (expected.cid.clone(), expected.user_id))
+        .unzip();
+
+    if cids.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_cids_from_content (
+            cid,
+            run_id,
+            user_id,
+            content_node_spid
+        )
+        SELECT tmp.cid, $1::int, tmp.user_id, $2::int
+        FROM UNNEST($3::text[], $4::int[]) AS tmp(cid, user_id);
+    "#,
+        run_id,
+        spid,
+        &cids,
+        &user_ids,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Save the CIDs a content node didn't answer for, so they don't count as missing from it
+#[tracing::instrument(skip(executor, expected_cids))]
+pub(crate) async fn save_unchecked_cids(
+    executor: impl PgExecutor<'_>,
+    run_id: i32,
+    spid: i32,
+    expected_cids: &[ExpectedCid],
+) -> Result<()> {
+    let (cids, user_ids): (Vec<String>, Vec<i32>) = expected_cids
+        .iter()
+        .map(|expected| (expected.cid.clone(), expected.user_id))
+        .unzip();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_unchecked_cids (
+            run_id,
+            content_node_spid,
+            cid,
+            user_id
+        )
+        SELECT $1::int, $2::int, tmp.cid, tmp.user_id
+        FROM UNNEST($3::text[], $4::int[]) AS tmp(cid, user_id)
+        ON CONFLICT DO NOTHING;
+    "#,
+        run_id,
+        spid,
+        &cids,
+        &user_ids,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Forget the CIDs of a batch a content node answered for after missing them before
+#[tracing::instrument(skip(executor, expected_cids))]
+async fn delete_unchecked_cids(
+    executor: impl PgExecutor<'_>,
+    run_id: i32,
+    spid: i32,
+    expected_cids: &[ExpectedCid],
+) -> Result<()> {
+    let (cids, user_ids): (Vec<String>, Vec<i32>) = expected_cids
+        .iter()
+        .map(|expected| (expected.cid.clone(), expected.user_id))
+        .unzip();
+
+    sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_unchecked_cids AS unchecked
+        USING UNNEST($3::text[], $4::int[]) AS tmp(cid, user_id)
+        WHERE unchecked.run_id = $1
+        AND unchecked.content_node_spid = $2
+        AND unchecked.cid = tmp.cid
+        AND unchecked.user_id = tmp.user_id;
+    "#,
+        run_id,
+        spid,
+        &cids,
+        &user_ids,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_checkpoints(
+    pool: &PgPool,
+    run_id: i32,
+    spid: i32,
+    replica: &Replica,
+    check: BatchCheck,
+) -> Result<HashSet<i64>> {
+    let checkpoints = sqlx::query!(
+        r#"
+        SELECT batch_offset
+        FROM network_monitoring_batch_checkpoints
+        WHERE run_id = $1
+        AND spid = $2
+        AND replica = $3
+        AND batch_check = $4;
+        "#,
+        run_id,
+        spid,
+        replica.as_str(),
+        check.as_str(),
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.batch_offset)
+    .collect::<HashSet<i64>>();
+
+    Ok(checkpoints)
+}
+
+#[tracing::instrument(skip(executor))]
+async fn save_checkpoint(
+    executor: impl PgExecutor<'_>,
+    run_id: i32,
+    spid: i32,
+    replica: &Replica,
+    check: BatchCheck,
+    batch_offset: i64,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_batch_checkpoints (
+            run_id,
+            spid,
+            replica,
+            batch_check,
+            batch_offset
+        ) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT DO NOTHING;
+        "#,
+        run_id,
+        spid,
+        replica.as_str(),
+        check.as_str(),
+        batch_offset,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_missed_replicas(pool: &PgPool, run_id: i32) -> Result<Vec<(i32, String)>> {
+    let missed_replicas = sqlx::query!(
+        r#"
+        SELECT DISTINCT spid, replica
+        FROM network_monitoring_missed_batches
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.spid, row.replica))
+    .collect::<Vec<(i32, String)>>();
+
+    Ok(missed_replicas)
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_missed_batches(
+    pool: &PgPool,
+    run_id: i32,
+    spid: i32,
+    replica: &Replica,
+) -> Result<HashSet<i64>> {
+    let missed_batches = sqlx::query!(
+        r#"
+        SELECT batch_offset
+        FROM network_monitoring_missed_batches
+        WHERE run_id = $1
+        AND spid = $2
+        AND replica = $3;
+        "#,
+        run_id,
+        spid,
+        replica.as_str(),
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.batch_offset)
+    .collect::<HashSet<i64>>();
+
+    Ok(missed_batches)
+}
+
+/// Save a batch to be retried, counting the attempts when it was missed before.
+/// Failing to save it is only logged, the batch is still retried because it has no checkpoint.
+#[tracing::instrument(skip(pool, missed), fields(batch_offset = missed.batch_offset))]
+async fn save_missed_batch(pool: &PgPool, run_id: i32, missed: &MissedBatch<'_>) {
+    let saved = sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_missed_batches (
+            run_id,
+            spid,
+            replica,
+            batch_offset,
+            wallets,
+            user_count,
+            error_kind,
+            error
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (run_id, spid, replica, batch_offset) DO UPDATE SET
+            wallets = CASE
+                WHEN cardinality(EXCLUDED.wallets) = 0
+                THEN network_monitoring_missed_batches.wallets
+                ELSE EXCLUDED.wallets
+            END,
+            user_count = EXCLUDED.user_count,
+            error_kind = EXCLUDED.error_kind,
+            error = EXCLUDED.error,
+            attempts = network_monitoring_missed_batches.attempts + 1,
+            missed_at = NOW();
+        "#,
+        run_id,
+        missed.spid,
+        missed.replica.as_str(),
+        missed.batch_offset,
+        missed.wallets,
+        missed.user_count,
+        missed.kind.as_str(),
+        missed.error,
+    )
+    .execute(pool)
+    .await;
+
+    if let Err(e) = saved {
+        tracing::error!("error saving missed batch {:?}", e);
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+async fn delete_missed_batch(
+    pool: &PgPool,
+    run_id: i32,
+    spid: i32,
+    replica: &Replica,
+    batch_offset: i64,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_missed_batches
+        WHERE run_id = $1
+        AND spid = $2
+        AND replica = $3
+        AND batch_offset = $4;
+        "#,
+        run_id,
+        spid,
+        replica.as_str(),
+        batch_offset,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
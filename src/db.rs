@@ -13,11 +13,34 @@ pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
         .connect_lazy_with(configuration.with_db())
 }
 
+/// Foreign server the discovery DB is reached through
+const FDW_SERVER: &str = "fdw_server_connection";
+
+/// Discovery tables imported into the `discovery` schema
+const FOREIGN_TABLES: [&str; 4] = ["users", "tracks", "blocks", "ursm_content_nodes"];
+
+/// Set up the connection to the discovery DB and import its tables into the `discovery` schema.
+/// The foreign server and the user mapping of the connecting role are created,
+/// or updated when the configuration changed, and the imported tables are replaced
+/// in a single transaction so nothing else in the schema is dropped.
+///
+/// # Errors
+///
+/// Returns an error if the extension, server, user mapping or tables can't be set up
 #[tracing::instrument(skip(pool))]
 pub async fn create_foreign_connection(
     pool: &PgPool,
     configuration: &DatabaseSettings,
 ) -> Result<()> {
+    set_up_connection(pool, configuration).await?;
+    import_foreign_tables(pool).await?;
+
+    Ok(())
+}
+
+/// Create the extension, then create or update the foreign server and the user mapping
+#[tracing::instrument(skip(pool))]
+async fn set_up_connection(pool: &PgPool, configuration: &DatabaseSettings) -> Result<()> {
     sqlx::query!(
         r#"
         CREATE EXTENSION IF NOT EXISTS postgres_fdw;
@@ -26,56 +49,227 @@ pub async fn create_foreign_connection(
     .execute(pool)
     .await?;
 
-    sqlx::query(&format!(
+    set_up_server(pool, configuration).await?;
+    set_up_user_mapping(pool, configuration).await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+async fn set_up_server(pool: &PgPool, configuration: &DatabaseSettings) -> Result<()> {
+    let wanted = [
+        ("dbname", configuration.database_name.clone()),
+        ("host", configuration.host.clone()),
+        ("port", configuration.port.to_string()),
+    ];
+
+    let current = sqlx::query!(
         r#"
-            CREATE SERVER IF NOT EXISTS fdw_server_connection 
-            FOREIGN DATA WRAPPER postgres_fdw 
-            OPTIONS (dbname '{}', host '{}', port '{}');
+        SELECT srvoptions
+        FROM pg_foreign_server
+        WHERE srvname = $1;
         "#,
-        configuration.database_name, configuration.host, configuration.port,
-    ))
-    .execute(pool)
+        FDW_SERVER,
+    )
+    .fetch_optional(pool)
     .await?;
 
-    sqlx::query(&format!(
+    let statement = match current {
+        None => format!(
+            "CREATE SERVER {} FOREIGN DATA WRAPPER postgres_fdw OPTIONS ({});",
+            quote_ident(FDW_SERVER),
+            create_options(&wanted),
+        ),
+        Some(row) => {
+            let changes = option_changes(&row.srvoptions.unwrap_or_default(), &wanted);
+            if changes.is_empty() {
+                return Ok(());
+            }
+
+            tracing::info!("updating the options of foreign server {FDW_SERVER}");
+            format!(
+                "ALTER SERVER {} OPTIONS ({});",
+                quote_ident(FDW_SERVER),
+                changes.join(", "),
+            )
+        }
+    };
+
+    sqlx::query(&statement).execute(pool).await?;
+
+    Ok(())
+}
+
+/// Map the role connected to the network monitoring DB, whichever it is, to the discovery user
+#[tracing::instrument(skip(pool))]
+async fn set_up_user_mapping(pool: &PgPool, configuration: &DatabaseSettings) -> Result<()> {
+    let wanted = [
+        ("user", configuration.username.clone()),
+        ("password", configuration.password.expose_secret().clone()),
+    ];
+
+    let current = sqlx::query!(
         r#"
-        CREATE USER MAPPING IF NOT EXISTS FOR postgres 
-        SERVER fdw_server_connection 
-        OPTIONS (user '{}', password '{}');
+        SELECT umoptions
+        FROM pg_user_mappings
+        WHERE srvname = $1
+        AND usename = CURRENT_USER;
         "#,
-        configuration.username,
-        configuration.password.expose_secret(),
-    ))
-    .execute(pool)
+        FDW_SERVER,
+    )
+    .fetch_optional(pool)
     .await?;
 
+    let statement = match current {
+        None => format!(
+            "CREATE USER MAPPING FOR CURRENT_USER SERVER {} OPTIONS ({});",
+            quote_ident(FDW_SERVER),
+            create_options(&wanted),
+        ),
+        Some(row) => {
+            let changes = option_changes(&row.umoptions.unwrap_or_default(), &wanted);
+            if changes.is_empty() {
+                return Ok(());
+            }
+
+            tracing::info!("updating the user mapping of foreign server {FDW_SERVER}");
+            format!(
+                "ALTER USER MAPPING FOR CURRENT_USER SERVER {} OPTIONS ({});",
+                quote_ident(FDW_SERVER),
+                changes.join(", "),
+            )
+        }
+    };
+
+    sqlx::query(&statement).execute(pool).await?;
+
+    Ok(())
+}
+
+/// Replace the imported tables, picking up changes to their columns.
+/// Queries reading them wait for the transaction instead of finding them missing.
+#[tracing::instrument(skip(pool))]
+async fn import_foreign_tables(pool: &PgPool) -> Result<()> {
+    let tables = FOREIGN_TABLES
+        .iter()
+        .map(|table| quote_ident(table))
+        .collect::<Vec<String>>();
+    let qualified_tables = tables
+        .iter()
+        .map(|table| format!("discovery.{table}"))
+        .collect::<Vec<String>>();
+
+    let mut tx = pool.begin().await?;
+
     sqlx::query!(
         r#"
-        DROP SCHEMA IF EXISTS discovery CASCADE;
+        CREATE SCHEMA IF NOT EXISTS discovery;
     "#
     )
-    .execute(pool)
+    .execute(&mut tx)
     .await?;
 
-    sqlx::query!(
-        r#"
-        CREATE SCHEMA discovery;
-    "#
-    )
-    .execute(pool)
+    sqlx::query(&format!(
+        "DROP FOREIGN TABLE IF EXISTS {};",
+        qualified_tables.join(", "),
+    ))
+    .execute(&mut tx)
     .await?;
 
-    sqlx::query!(
+    sqlx::query(&format!(
+        r#"IMPORT FOREIGN SCHEMA "public" LIMIT TO ({}) FROM SERVER {} INTO discovery;"#,
+        tables.join(", "),
+        quote_ident(FDW_SERVER),
+    ))
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// `OPTIONS` of a new foreign server or user mapping
+fn create_options(wanted: &[(&str, String)]) -> String {
+    wanted
+        .iter()
+        .map(|(name, value)| format!("{} {}", quote_ident(name), quote_literal(value)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// The `ADD` and `SET` clauses bringing `current`, the `name=value` options
+/// of a foreign server or user mapping, in line with `wanted`
+#[must_use]
+pub fn option_changes(current: &[String], wanted: &[(&str, String)]) -> Vec<String> {
+    wanted
+        .iter()
+        .filter_map(|(name, value)| {
+            let current_value = current
+                .iter()
+                .find_map(|option| option.strip_prefix(&format!("{name}=")));
+
+            match current_value {
+                Some(current_value) if current_value == value => None,
+                Some(_) => Some(format!(
+                    "SET {} {}",
+                    quote_ident(name),
+                    quote_literal(value)
+                )),
+                None => Some(format!(
+                    "ADD {} {}",
+                    quote_ident(name),
+                    quote_literal(value)
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Quote `identifier` for SQL like postgres' `quote_ident`, doubling its double quotes
+#[must_use]
+pub fn quote_ident(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Quote `literal` for SQL like postgres' `quote_literal`, doubling its quotes,
+/// and its backslashes in an escape string so it's read the same whatever
+/// `standard_conforming_strings` is
+#[must_use]
+pub fn quote_literal(literal: &str) -> String {
+    let quoted = literal.replace('\'', "''");
+    if quoted.contains('\\') {
+        format!("E'{}'", quoted.replace('\\', "\\\\"))
+    } else {
+        format!("'{quoted}'")
+    }
+}
+
+/// Set up the connection to discovery, only importing its tables when they weren't already,
+/// so a run reading through them doesn't wait on them being replaced.
+/// The server and the user mapping are still updated when the configuration changed.
+///
+/// # Errors
+///
+/// Returns an error if the schema can't be looked up or the connection can't be set up
+#[tracing::instrument(skip(pool))]
+pub async fn ensure_foreign_connection(
+    pool: &PgPool,
+    configuration: &DatabaseSettings,
+) -> Result<()> {
+    let exists = sqlx::query!(
         r#"
-        IMPORT FOREIGN SCHEMA "public"
-        LIMIT
-                TO (users, tracks, blocks, ursm_content_nodes)
-        FROM
-                SERVER fdw_server_connection INTO discovery; 
+        SELECT to_regclass('discovery.users') IS NOT NULL AS "exists!";
         "#
     )
-    .execute(pool)
-    .await?;
+    .fetch_one(pool)
+    .await?
+    .exists;
+
+    set_up_connection(pool, configuration).await?;
+    if !exists {
+        import_foreign_tables(pool).await?;
+    }
 
     Ok(())
 }
@@ -1,17 +1,46 @@
 use color_eyre::eyre::Result;
 use sqlx::PgPool;
+use thiserror::Error;
 
-use crate::domain::ContentNode;
+use crate::{
+    configuration::{DiscoverySettings, RegistrySource, ScopeSettings},
+    domain::ContentNode,
+    prometheus::REGISTRY_MISMATCH_COUNT_GAUGE,
+    registry, runs,
+};
 
-#[tracing::instrument(skip(pool))]
-pub async fn index(pool: &PgPool) -> Result<i32> {
-    // Create new run in table `network_monitoring_index_blocks`
-    let run_id = create_new_run(pool).await?;
+#[derive(Error, Debug)]
+enum DiscoveryError {
+    #[error("the {0:?} registry has no content nodes")]
+    NoContentNodes(RegistrySource),
+    #[error("`scope.sample_percent` must be above 0 and at most 100, not {0}")]
+    InvalidSamplePercent(f64),
+    #[error("`scope.sample_users_per_primary` must be above 0, not {0}")]
+    InvalidSampleSize(i64),
+}
+
+#[tracing::instrument(skip(pool, config))]
+pub async fn index(
+    pool: &PgPool,
+    run_id: i32,
+    config: DiscoverySettings,
+    scope: ScopeSettings,
+) -> Result<()> {
+    if let Some(percent) = scope.sample_percent {
+        if !(percent > 0.0 && percent <= 100.0) {
+            return Err(DiscoveryError::InvalidSamplePercent(percent).into());
+        }
+    }
+    if let Some(sample_size) = scope.sample_users_per_primary {
+        if sample_size <= 0 {
+            return Err(DiscoveryError::InvalidSampleSize(sample_size).into());
+        }
+    }
 
     delete_old_run_data(pool, run_id).await?;
 
     // Pull Content Nodes list into table `network_monitoring_content_nodes`
-    import_content_nodes(pool, run_id).await?;
+    import_content_nodes(pool, run_id, &config).await?;
 
     // Pull table `users` into table `network_monitoring_users`
     import_users(pool, run_id).await?;
@@ -19,79 +48,51 @@ pub async fn index(pool: &PgPool) -> Result<i32> {
     // Pull cids into table `network_monitoring_cids_from_discovery`
     import_cids(pool, run_id).await?;
 
-    Ok(run_id)
-}
-
-#[tracing::instrument(skip(pool))]
-async fn create_new_run(pool: &PgPool) -> Result<i32> {
-    // get latest block number
-    let latest_block_number = sqlx::query!(
-        r#"
-        SELECT number FROM discovery.blocks WHERE is_current = TRUE LIMIT 1;
-        "#,
-    )
-    .fetch_one(pool)
-    .await?
-    .number;
-
-    // create new run in DB
-    let run_id = sqlx::query!(
-        r#"
-        INSERT INTO network_monitoring_index_blocks (
-            is_current, 
-            blocknumber, 
-            is_complete,
-            created_at
-        ) VALUES (
-            TRUE,
-            $1,
-            FALSE,
-            NOW()
-        )
-        RETURNING run_id; 
-    "#,
-        latest_block_number,
-    )
-    .fetch_one(pool)
-    .await?
-    .run_id;
+    if scope.limits_users() {
+        scope_users(pool, run_id, &scope).await?;
+        runs::mark_scoped(pool, run_id).await?;
+    }
 
-    // remove is_current from latest run
-    sqlx::query!(
-        r#"
-        UPDATE network_monitoring_index_blocks 
-        SET is_current = FALSE
-        WHERE blocknumber != $1;
-    "#,
-        latest_block_number,
-    )
-    .execute(pool)
-    .await?;
+    if let Some(sample_size) = scope.sample_users_per_primary {
+        sample_users(pool, run_id, sample_size).await?;
+    }
 
-    // return new run id
-    Ok(run_id)
+    Ok(())
 }
 
+/// Delete the runs before `run_id` but the latest few of each kind,
+/// so frequent sampled runs don't push the full runs out
 #[tracing::instrument(skip(pool))]
 async fn delete_old_run_data(pool: &PgPool, run_id: i32) -> Result<()> {
-    // Number of runs to keep in the DB
+    // Number of previous runs of each kind to keep in the DB
     let latest_runs_to_keep = 3;
-    let to_delete = run_id - latest_runs_to_keep;
 
-    if to_delete <= 0 {
-        tracing::info!("no previous run data deleted");
-        return Ok(());
-    }
-
-    sqlx::query!(
+    let deleted = sqlx::query!(
         r#"
         DELETE FROM network_monitoring_index_blocks
-        WHERE run_id < $1; 
+        WHERE run_id IN (
+            SELECT run_id
+            FROM (
+                SELECT
+                    run_id,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY is_sampled, is_scoped
+                        ORDER BY run_id DESC
+                    ) AS newer_runs
+                FROM network_monitoring_index_blocks
+                WHERE run_id < $1
+            ) AS runs
+            WHERE runs.newer_runs > $2
+        );
     "#,
-        to_delete,
+        run_id,
+        latest_runs_to_keep,
     )
     .execute(pool)
-    .await?;
+    .await?
+    .rows_affected();
+
+    tracing::info!("deleted the data of {deleted} previous runs");
 
     Ok(())
 }
@@ -128,61 +129,117 @@ async fn import_users(pool: &PgPool, run_id: i32) -> Result<()> {
     Ok(())
 }
 
-#[tracing::instrument(skip(pool))]
-async fn import_content_nodes(pool: &PgPool, run_id: i32) -> Result<()> {
-    let content_nodes = vec![
-        ContentNode {
-            spid: 6,
-            endpoint: "https://usermetadata.staging.audius.co".to_string(),
-        },
-        ContentNode {
-            spid: 7,
-            endpoint: "https://creatornode5.staging.audius.co".to_string(),
-        },
-        ContentNode {
-            spid: 8,
-            endpoint: "https://creatornode6.staging.audius.co".to_string(),
-        },
-        ContentNode {
-            spid: 9,
-            endpoint: "https://creatornode7.staging.audius.co".to_string(),
-        },
-        ContentNode {
-            spid: 10,
-            endpoint: "https://creatornode8.staging.audius.co".to_string(),
-        },
-        ContentNode {
-            spid: 11,
-            endpoint: "https://creatornode9.staging.audius.co".to_string(),
-        },
-        ContentNode {
-            spid: 12,
-            endpoint: "https://creatornode10.staging.audius.co".to_string(),
-        },
-        ContentNode {
-            spid: 13,
-            endpoint: "https://creatornode11.staging.audius.co".to_string(),
-        },
-    ];
-
-    for cnode in content_nodes {
-        sqlx::query!(
-            r#"
-            INSERT INTO network_monitoring_content_nodes (run_id, spid, endpoint)
-            VALUES (
-                $1,
-                $2,
-                $3
-            );
-        "#,
+#[tracing::instrument(skip(pool, config))]
+async fn import_content_nodes(
+    pool: &PgPool,
+    run_id: i32,
+    config: &DiscoverySettings,
+) -> Result<()> {
+    let registry = registry::from_settings(config.registry, pool, config)?;
+    let content_nodes = registry.content_nodes().await?;
+
+    if content_nodes.is_empty() {
+        return Err(DiscoveryError::NoContentNodes(config.registry).into());
+    }
+
+    tracing::info!(
+        "importing {} content nodes from the {:?} registry",
+        content_nodes.len(),
+        config.registry
+    );
+
+    if let Some(cross_check_source) = config.cross_check_registry {
+        cross_check_content_nodes(run_id, cross_check_source, &content_nodes, pool, config).await?;
+    }
+
+    let spids = content_nodes
+        .iter()
+        .map(|cnode| cnode.spid)
+        .collect::<Vec<i32>>();
+    let endpoints = content_nodes
+        .iter()
+        .map(|cnode| cnode.endpoint.clone())
+        .collect::<Vec<String>>();
+    let owner_wallets = content_nodes
+        .iter()
+        .map(|cnode| cnode.owner_wallet.clone())
+        .collect::<Vec<Option<String>>>();
+    let delegate_owner_wallets = content_nodes
+        .iter()
+        .map(|cnode| cnode.delegate_owner_wallet.clone())
+        .collect::<Vec<Option<String>>>();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_content_nodes (
             run_id,
-            cnode.spid,
-            cnode.endpoint
+            spid,
+            endpoint,
+            owner_wallet,
+            delegate_owner_wallet
         )
-        .execute(pool)
+        SELECT $1::int, *
+        FROM UNNEST($2::int[], $3::text[], $4::text[], $5::text[]);
+    "#,
+        run_id,
+        &spids,
+        &endpoints,
+        &owner_wallets as &[Option<String>],
+        &delegate_owner_wallets as &[Option<String>],
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Compare the imported content nodes against a second registry,
+/// logging every difference and exporting the number of differences
+#[tracing::instrument(skip(content_nodes, pool, config))]
+async fn cross_check_content_nodes(
+    run_id: i32,
+    source: RegistrySource,
+    content_nodes: &[ContentNode],
+    pool: &PgPool,
+    config: &DiscoverySettings,
+) -> Result<()> {
+    let other = registry::from_settings(source, pool, config)?
+        .content_nodes()
         .await?;
+    let diff = registry::cross_check(content_nodes, &other);
+
+    for cnode in &diff.missing_from_other {
+        tracing::warn!(
+            "content node {} ({}) is missing from the {:?} registry",
+            cnode.spid,
+            cnode.endpoint,
+            source
+        );
+    }
+
+    for cnode in &diff.missing_from_registry {
+        tracing::warn!(
+            "content node {} ({}) is only in the {:?} registry",
+            cnode.spid,
+            cnode.endpoint,
+            source
+        );
     }
 
+    for (cnode, other_cnode) in &diff.mismatched {
+        tracing::warn!(
+            "content node {} differs from the {:?} registry: {:?} != {:?}",
+            cnode.spid,
+            source,
+            cnode,
+            other_cnode
+        );
+    }
+
+    REGISTRY_MISMATCH_COUNT_GAUGE
+        .with_label_values(&[source.as_str(), &run_id.to_string()])
+        .set(i64::try_from(diff.count())?);
+
     Ok(())
 }
 
@@ -297,9 +354,9 @@ async fn import_cids(pool: &PgPool, run_id: i32) -> Result<()> {
     sqlx::query!(
         r#"
         INSERT INTO network_monitoring_cids_from_discovery (cid, run_id, ctype, user_id)
-        SELECT download -> 'cid' as cid, $1, 'track', owner_id
+        SELECT download ->> 'cid' as cid, $1, 'track', owner_id
         FROM discovery.tracks
-        WHERE download -> 'cid' != 'null'
+        WHERE download ->> 'cid' IS NOT NULL
         AND is_current = TRUE;
     "#,
         run_id
@@ -311,7 +368,7 @@ async fn import_cids(pool: &PgPool, run_id: i32) -> Result<()> {
         r#"
         INSERT INTO network_monitoring_cids_from_discovery (cid, run_id, ctype, user_id)
         SELECT
-            jsonb_array_elements(track_segments) -> 'multihash',
+            jsonb_array_elements(track_segments) ->> 'multihash',
             $1,
             'track',
             owner_id
@@ -326,3 +383,113 @@ async fn import_cids(pool: &PgPool, run_id: i32) -> Result<()> {
 
     Ok(())
 }
+
+/// Remove the users outside of `scope` and their CIDs from the run.
+/// Users are sampled by the hash of their id, so a percentage always keeps the same users.
+#[tracing::instrument(skip(pool))]
+async fn scope_users(pool: &PgPool, run_id: i32, scope: &ScopeSettings) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_cids_from_discovery
+        WHERE run_id = $1
+        AND NOT (
+            user_id BETWEEN COALESCE($2, user_id) AND COALESCE($3, user_id)
+            AND ($4::float8 IS NULL OR ABS(hashint4(user_id)::bigint) % 10000 < $4 * 100)
+        );
+        "#,
+        run_id,
+        scope.min_user_id,
+        scope.max_user_id,
+        scope.sample_percent,
+    )
+    .execute(pool)
+    .await?;
+
+    let removed_users = sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_users
+        WHERE run_id = $1
+        AND NOT (
+            user_id BETWEEN COALESCE($2, user_id) AND COALESCE($3, user_id)
+            AND ($4::float8 IS NULL OR ABS(hashint4(user_id)::bigint) % 10000 < $4 * 100)
+        );
+        "#,
+        run_id,
+        scope.min_user_id,
+        scope.max_user_id,
+        scope.sample_percent,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    tracing::info!("removed {removed_users} users outside of the scope of run {run_id}");
+
+    Ok(())
+}
+
+/// Keep a random sample of up to `sample_size` users per primary and their CIDs,
+/// recording how many users each primary has for metrics to weigh its sample by.
+/// Users without a primary have no sync status to estimate, so none are kept.
+#[tracing::instrument(skip(pool))]
+async fn sample_users(pool: &PgPool, run_id: i32, sample_size: i64) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_sample_strata (run_id, primary_spid, population, sample_size)
+        SELECT $1, primaryspid, COUNT(*), LEAST(COUNT(*), $2)
+        FROM network_monitoring_users
+        WHERE run_id = $1
+        AND primaryspid IS NOT NULL
+        GROUP BY primaryspid;
+        "#,
+        run_id,
+        sample_size,
+    )
+    .execute(pool)
+    .await?;
+
+    let removed_users = sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_users AS users
+        USING (
+            SELECT
+                user_id,
+                primaryspid,
+                ROW_NUMBER() OVER (PARTITION BY primaryspid ORDER BY random()) AS sample_rank
+            FROM network_monitoring_users
+            WHERE run_id = $1
+        ) AS ranked
+        WHERE users.run_id = $1
+        AND users.user_id = ranked.user_id
+        AND (ranked.primaryspid IS NULL OR ranked.sample_rank > $2);
+        "#,
+        run_id,
+        sample_size,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    // The sample is random, so the CIDs to remove are those of the users that are gone
+    sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_cids_from_discovery AS cids
+        WHERE cids.run_id = $1
+        AND NOT EXISTS (
+            SELECT 1
+            FROM network_monitoring_users AS users
+            WHERE users.run_id = $1
+            AND users.user_id = cids.user_id
+        );
+        "#,
+        run_id,
+    )
+    .execute(pool)
+    .await?;
+
+    runs::mark_sampled(pool, run_id).await?;
+
+    tracing::info!("sampled up to {sample_size} users per primary, removed {removed_users} users from run {run_id}");
+
+    Ok(())
+}
@@ -1,14 +1,30 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct ContentNode {
     pub endpoint: String,
     pub spid: i32,
+    pub owner_wallet: Option<String>,
+    pub delegate_owner_wallet: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct WalletClockPair {
     pub wallet_public_key: String,
     pub clock: i32,
 }
+
+/// What a storage-v2 content node reports about itself on `/health_check`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeHealth {
+    #[serde(default)]
+    pub healthy: bool,
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CidExists {
+    pub cid: String,
+    pub exists: bool,
+}
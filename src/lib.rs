@@ -1,15 +1,27 @@
-#![feature(async_closure)]
 #![forbid(unsafe_code)]
 #![warn(clippy::pedantic)]
 // #![warn(clippy::restriction)]
 #![warn(clippy::style)]
+#![allow(clippy::non_std_lazy_statics)]
 
+pub mod alerts;
+pub mod cli;
 pub mod configuration;
 pub mod content;
 pub mod db;
 pub mod discovery;
 pub mod domain;
+pub mod limits;
 pub mod metrics;
 pub mod prometheus;
+pub mod registry;
+pub mod rendezvous;
+pub mod report;
+pub mod runs;
+pub mod scheduler;
+pub mod server;
+pub mod signing;
+pub mod storage_v2;
 pub mod telemetry;
+pub mod user_check;
 pub mod utils;
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::sleep,
+};
+
+use crate::{
+    configuration::LimitSettings,
+    prometheus::{
+        CONTENT_NODE_IN_FLIGHT_REQUESTS_GAUGE, CONTENT_NODE_REQUEST_LIMIT_GAUGE,
+        CONTENT_NODE_REQUEST_WAIT_SECONDS_COUNTER,
+    },
+};
+
+/// Token bucket holding up to `burst` requests and refilled at `requests_per_second`.
+///
+/// A request always takes its token, so the bucket goes into debt while requests wait
+/// and they're let through in the order they asked.
+#[derive(Debug)]
+pub struct TokenBucket {
+    requests_per_second: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    #[must_use]
+    pub fn new(requests_per_second: f64, burst: u32, now: Instant) -> Self {
+        let burst = f64::from(burst.max(1));
+
+        Self {
+            requests_per_second,
+            burst,
+            tokens: burst,
+            refilled_at: now,
+        }
+    }
+
+    /// Take a token at `now`, returning how long to wait before sending the request
+    pub fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.requests_per_second).min(self.burst);
+        self.refilled_at = self.refilled_at.max(now);
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.requests_per_second)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct EndpointLimits {
+    in_flight: Arc<Semaphore>,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+/// Bounds the requests sent to content nodes, across every node and to each one
+#[derive(Debug)]
+pub struct RequestLimiter {
+    config: LimitSettings,
+    in_flight: Arc<Semaphore>,
+    endpoints: Mutex<HashMap<String, Arc<EndpointLimits>>>,
+}
+
+/// Held while a request is in flight, frees its slots when dropped
+#[derive(Debug)]
+pub struct RequestPermit {
+    endpoint: String,
+    _endpoint_permit: OwnedSemaphorePermit,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        CONTENT_NODE_IN_FLIGHT_REQUESTS_GAUGE
+            .with_label_values(&[&self.endpoint])
+            .dec();
+    }
+}
+
+impl RequestLimiter {
+    #[must_use]
+    pub fn new(config: &LimitSettings) -> Self {
+        let config = LimitSettings {
+            max_in_flight: config.max_in_flight.max(1),
+            max_in_flight_per_endpoint: config.max_in_flight_per_endpoint.max(1),
+            requests_per_second: config.requests_per_second.filter(|rate| *rate > 0.0),
+            burst: config.burst,
+        };
+
+        tracing::info!(
+            "limiting content node requests to {} in flight, {} in flight per endpoint, {}",
+            config.max_in_flight,
+            config.max_in_flight_per_endpoint,
+            config.requests_per_second.map_or_else(
+                || "without a rate limit".into(),
+                |rate| format!(
+                    "{rate} per second per endpoint in bursts of {}",
+                    config.burst
+                )
+            ),
+        );
+
+        #[allow(clippy::cast_precision_loss)]
+        for (limit, value) in [
+            ("max_in_flight", config.max_in_flight as f64),
+            (
+                "max_in_flight_per_endpoint",
+                config.max_in_flight_per_endpoint as f64,
+            ),
+            (
+                "requests_per_second",
+                config.requests_per_second.unwrap_or(0.0),
+            ),
+            ("burst", f64::from(config.burst)),
+        ] {
+            CONTENT_NODE_REQUEST_LIMIT_GAUGE
+                .with_label_values(&[limit])
+                .set(value);
+        }
+
+        Self {
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight)),
+            endpoints: Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    /// Wait for a slot for `endpoint`, then for its rate limit, then for a global slot,
+    /// so that requests queued behind a slow node don't hold slots other nodes could use
+    ///
+    /// # Panics
+    ///
+    /// Never, the semaphores are never closed
+    pub async fn acquire(&self, endpoint: &str) -> RequestPermit {
+        let limits = self.endpoint_limits(endpoint);
+
+        let started = Instant::now();
+        let endpoint_permit = limits
+            .in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("endpoint semaphore is never closed");
+        waited(endpoint, "endpoint", started);
+
+        if let Some(bucket) = &limits.bucket {
+            let wait = bucket
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take(Instant::now());
+            if !wait.is_zero() {
+                tracing::debug!("rate limited {endpoint} for {wait:?}");
+                sleep(wait).await;
+                CONTENT_NODE_REQUEST_WAIT_SECONDS_COUNTER
+                    .with_label_values(&[endpoint, "rate"])
+                    .inc_by(wait.as_secs_f64());
+            }
+        }
+
+        let started = Instant::now();
+        let permit = self
+            .in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("global semaphore is never closed");
+        waited(endpoint, "global", started);
+
+        CONTENT_NODE_IN_FLIGHT_REQUESTS_GAUGE
+            .with_label_values(&[endpoint])
+            .inc();
+
+        RequestPermit {
+            endpoint: endpoint.into(),
+            _endpoint_permit: endpoint_permit,
+            _permit: permit,
+        }
+    }
+
+    fn endpoint_limits(&self, endpoint: &str) -> Arc<EndpointLimits> {
+        let mut endpoints = self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        endpoints
+            .entry(endpoint.into())
+            .or_insert_with(|| {
+                Arc::new(EndpointLimits {
+                    in_flight: Arc::new(Semaphore::new(self.config.max_in_flight_per_endpoint)),
+                    bucket: self.config.requests_per_second.map(|requests_per_second| {
+                        Mutex::new(TokenBucket::new(
+                            requests_per_second,
+                            self.config.burst,
+                            Instant::now(),
+                        ))
+                    }),
+                })
+            })
+            .clone()
+    }
+}
+
+/// Count the time spent waiting for a slot of `limit`
+fn waited(endpoint: &str, limit: &str, started: Instant) {
+    let wait = started.elapsed();
+    if wait >= Duration::from_millis(1) {
+        CONTENT_NODE_REQUEST_WAIT_SECONDS_COUNTER
+            .with_label_values(&[endpoint, limit])
+            .inc_by(wait.as_secs_f64());
+    }
+}
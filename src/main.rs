@@ -1,40 +1,117 @@
 use audius_network_monitor::{
-    configuration, content,
-    db::{create_foreign_connection, get_connection_pool},
-    discovery, metrics,
+    cli::{Cli, Command, ScopeArgs},
+    configuration::{self, Settings},
+    db::{ensure_foreign_connection, get_connection_pool},
+    report, runs, scheduler,
+    server::{self, HealthState},
     telemetry::{get_subscriber, init_subscriber},
+    user_check,
 };
-use color_eyre::eyre::Result;
+use clap::Parser;
+use color_eyre::eyre::{eyre, Result};
+use sqlx::PgPool;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
 
+    let cli = Cli::parse();
+
     let subscriber = get_subscriber(
         "audius_network_monitor".into(),
-        "info".into(),
+        cli.log_level.clone(),
         std::io::stdout,
     );
     init_subscriber(subscriber);
 
-    let configuration = configuration::read().expect("Failed to read configuration");
+    let mut configuration = configuration::read_from(&cli.config_dir, cli.environment)
+        .expect("Failed to read configuration");
 
     let pool = get_connection_pool(&configuration.database);
-    sqlx::migrate!("./migrations").run(&pool).await?;
-    create_foreign_connection(&pool, &configuration.foreign_database).await?;
 
-    // Index data from the discovery node postgres DB
-    // into the separate network monitoring postgres DB
-    let run_id = discovery::index(&pool).await?;
+    let command = cli.command.unwrap_or(Command::Run {
+        resume: None,
+        scope: ScopeArgs::default(),
+    });
+    match command {
+        Command::Run { resume, scope } => {
+            migrate(&pool).await?;
+            scope.apply(&mut configuration.scope);
+            warn_if_pulled(&configuration);
+
+            match resume {
+                Some(run_id) => scheduler::resume_job(&pool, &configuration, run_id).await?,
+                None => scheduler::run_job(&pool, &configuration).await?,
+            };
+        }
+        Command::Daemon => {
+            migrate(&pool).await?;
+
+            let health = HealthState::new();
 
-    // Fetch data (CIDs and Users) from content nodes
-    // and save it into the network monitoring postgres DB
-    content::index(&pool, run_id, configuration.content).await?;
+            if configuration.metrics.export.pull() {
+                let (_, server) = server::bind(&configuration.metrics.server, health.clone())?;
+                tokio::spawn(async move {
+                    if let Err(e) = server.await {
+                        tracing::error!("metrics server failed {:?}", e);
+                    }
+                });
+            }
 
-    // Run OLAP-type queries on the network monitoring DB
-    // and export the data to the prometheus push-gateway
-    // to be later scraped by prometheus
-    metrics::generate(&pool, run_id, configuration.metrics).await?;
+            scheduler::run_daemon(&pool, configuration, health).await?;
+        }
+        Command::Discover(scope) => {
+            migrate(&pool).await?;
+            scope.apply(&mut configuration.scope);
+
+            let run_id = scheduler::discover(&pool, &configuration).await?;
+            tracing::info!("imported discovery into run {run_id}");
+        }
+        Command::CheckContent { run_id, scope } => {
+            migrate(&pool).await?;
+            scope.apply(&mut configuration.scope);
+            scheduler::check_content(&pool, &configuration, run_id).await?;
+        }
+        Command::Metrics {
+            run_id,
+            allow_incomplete,
+        } => {
+            migrate(&pool).await?;
+            warn_if_pulled(&configuration);
+
+            let run_id = match run_id {
+                Some(run_id) => run_id,
+                None => runs::get_latest_run_id(&pool, allow_incomplete)
+                    .await?
+                    .ok_or_else(|| eyre!("there are no runs to generate metrics for"))?,
+            };
+            scheduler::generate_metrics(&pool, &configuration, run_id, allow_incomplete).await?;
+        }
+        Command::Report(args) => {
+            report::run(&pool, args, &configuration.metrics).await?;
+        }
+        Command::User { query } => {
+            ensure_foreign_connection(&pool, &configuration.foreign_database).await?;
+            user_check::run(&pool, &configuration, &query).await?;
+        }
+        Command::Migrate => {
+            migrate(&pool).await?;
+            tracing::info!("migrations are up to date");
+        }
+    }
 
     Ok(())
 }
+
+/// Apply the migrations of the network monitoring DB, only the commands that write to it need them
+async fn migrate(pool: &PgPool) -> Result<()> {
+    sqlx::migrate!("./migrations").run(pool).await?;
+
+    Ok(())
+}
+
+fn warn_if_pulled(configuration: &Settings) {
+    if configuration.metrics.export.pull() {
+        tracing::warn!("metrics can only be pulled in daemon mode, they won't be served");
+    }
+}
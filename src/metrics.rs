@@ -1,6 +1,10 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
 use color_eyre::eyre::Result;
 use num_traits::cast::ToPrimitive;
-use prometheus::labels;
 use sqlx::{
     types::{
         chrono::{DateTime, Utc},
@@ -8,26 +12,97 @@ use sqlx::{
     },
     PgPool,
 };
+use thiserror::Error;
 
 use crate::{
-    configuration::MetricsSettings,
+    alerts,
+    configuration::{ContentProtocol, MetricsSettings},
     prometheus::{
-        ALL_USER_COUNT_GAUGE, FULLY_SYNCED_USERS_COUNT_GAUGE,
-        FULLY_SYNCED_USER_BY_PRIMARY_COUNT_GAUGE, FULLY_SYNCED_USER_BY_REPLICA_COUNT_GAUGE,
-        NULL_PRIMARY_USERS_COUNT_GAUGE, PARTIALLY_SYNCED_USERS_COUNT_GAUGE,
-        PARTIALLY_SYNCED_USER_BY_PRIMARY_COUNT_GAUGE, PARTIALLY_SYNCED_USER_BY_REPLICA_COUNT_GAUGE,
-        PRIMARY_USER_COUNT_GAUGE, UNHEALTHY_REPLICA_USERS_COUNT_GAUGE, UNSYNCED_USERS_COUNT_GAUGE,
-        UNSYNCED_USER_BY_PRIMARY_COUNT_GAUGE, UNSYNCED_USER_BY_REPLICA_COUNT_GAUGE,
-        USERS_WITH_ALL_FOUNDATION_NODE_REPLICA_SET_GAUGE,
-        USERS_WITH_NO_FOUNDATION_NODE_REPLICA_SET_GAUGE, USER_COUNT_GAUGE, TOTAL_JOB_DURATION_GAUGE,
+        drop_stale_runs, ALL_USER_COUNT_GAUGE, CID_REPLICAS_COUNT_GAUGE, EXPECTED_CIDS_COUNT_GAUGE,
+        FULLY_SYNCED_USERS_COUNT_GAUGE, FULLY_SYNCED_USER_BY_PRIMARY_COUNT_GAUGE,
+        FULLY_SYNCED_USER_BY_REPLICA_COUNT_GAUGE, GENERATING_METRICS_DURATION_GAUGE,
+        MISSED_USERS_COUNT_GAUGE, MISSING_CIDS_COUNT_GAUGE, NULL_PRIMARY_USERS_COUNT_GAUGE,
+        PARTIALLY_SYNCED_USERS_COUNT_GAUGE, PARTIALLY_SYNCED_USER_BY_PRIMARY_COUNT_GAUGE,
+        PARTIALLY_SYNCED_USER_BY_REPLICA_COUNT_GAUGE, PLACED_CIDS_COUNT_GAUGE,
+        PLACED_CIDS_PRESENT_COUNT_GAUGE, PRESENT_CIDS_COUNT_GAUGE, PRIMARY_USER_COUNT_GAUGE,
+        REPLICATION_SHORTFALL_GAUGE, SAMPLED_POPULATION_COUNT_GAUGE, SYNC_STATUS_ESTIMATE_GAUGE,
+        TOTAL_JOB_DURATION_GAUGE, UNAVAILABLE_CIDS_COUNT_GAUGE, UNCHECKED_CIDS_COUNT_GAUGE,
+        UNDER_REPLICATED_CIDS_COUNT_GAUGE, UNHEALTHY_REPLICA_USERS_COUNT_GAUGE,
+        UNSYNCED_USERS_COUNT_GAUGE, UNSYNCED_USER_BY_PRIMARY_COUNT_GAUGE,
+        UNSYNCED_USER_BY_REPLICA_COUNT_GAUGE, USERS_WITH_ALL_FOUNDATION_NODE_REPLICA_SET_GAUGE,
+        USERS_WITH_NO_FOUNDATION_NODE_REPLICA_SET_GAUGE, USER_COUNT_GAUGE,
     },
+    runs,
 };
 
+#[derive(Error, Debug)]
+pub enum MetricsError {
+    #[error("`metrics.confidence_level` must be above 0 and below 1, not {0}")]
+    InvalidConfidenceLevel(f64),
+}
+
 pub struct CNodeCount {
     pub endpoint: String,
     pub count: i64,
 }
 
+pub struct CNodeCidCount {
+    pub endpoint: String,
+    pub ctype: String,
+    pub count: i64,
+}
+
+pub struct CNodeCidAvailability {
+    pub endpoint: String,
+    pub replica: String,
+    pub ctype: String,
+    pub expected_count: i64,
+    pub present_count: i64,
+}
+
+/// `count` storage-v2 CIDs placed on `placed` content nodes, `held` of which have them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CidReplication {
+    pub placed: i64,
+    pub held: i64,
+    pub count: i64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReplicationShortfall {
+    /// CIDs missing from at least one of the content nodes they're placed on
+    pub under_replicated_count: i64,
+    /// CIDs missing from every content node they're placed on
+    pub unavailable_count: i64,
+    /// Replicas missing across every CID
+    pub missing_replicas: i64,
+}
+
+pub struct CNodePlacedCids {
+    pub endpoint: String,
+    pub placed_count: i64,
+    pub present_count: i64,
+}
+
+/// The users of a primary a sampled run drew its sample from,
+/// and the sync status of the `sampled` users it checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampledStratum {
+    pub population: i64,
+    pub sampled: i64,
+    pub fully_synced: i64,
+    pub partially_synced: i64,
+    pub unsynced: i64,
+}
+
+/// Share of every user estimated from a sample, likely within `lower` and `upper`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub ratio: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
 pub struct CNodeSyncedStatus {
     pub spid: i32,
     pub endpoint: String,
@@ -36,11 +111,99 @@ pub struct CNodeSyncedStatus {
     pub unsynced_count: i64,
 }
 
-#[tracing::instrument(skip(pool))]
-pub async fn generate(pool: &PgPool, run_id: i32, config: MetricsSettings) -> Result<()> {
+/// Generate the metrics of `run_id`, then push and alert on them.
+/// Runs whose discovery or content stage didn't succeed are refused unless `allow_incomplete`,
+/// so partial data isn't exported.
+///
+/// # Errors
+///
+/// Returns an error if the run isn't indexed, a query fails or the push fails
+#[tracing::instrument(skip(pool, deregistered_nodes))]
+pub async fn generate(
+    pool: &PgPool,
+    run_id: i32,
+    config: MetricsSettings,
+    deregistered_nodes: &[String],
+    allow_incomplete: bool,
+) -> Result<()> {
+    if !allow_incomplete {
+        runs::ensure_indexed(pool, run_id).await?;
+    }
+
     // GENERATE METRICS
+    let generating_start = Instant::now();
     let run_time_start = get_run_start_time(pool, run_id).await?;
     let user_count = get_user_count(pool, run_id).await?;
+
+    // REGISTER METRICS
+    USER_COUNT_GAUGE
+        .with_label_values(&[&run_id.to_string()])
+        .set(user_count);
+
+    // Storage-v2 runs don't check users' replica sets, their CIDs are placed on content nodes
+    let protocol = runs::get_content_protocol(pool, run_id).await?;
+    match protocol {
+        ContentProtocol::Legacy => {
+            generate_replica_set_metrics(pool, run_id, &config, deregistered_nodes).await?;
+        }
+        ContentProtocol::StorageV2 => generate_placement_metrics(pool, run_id).await?,
+    }
+
+    // Sampled runs only checked some users of each primary, so estimate it for every user
+    if protocol == ContentProtocol::Legacy && runs::is_sampled(pool, run_id).await? {
+        generate_sync_estimates(pool, run_id, config.confidence_level).await?;
+    }
+
+    GENERATING_METRICS_DURATION_GAUGE
+        .with_label_values(&[&run_id.to_string()])
+        .set(i64::try_from(generating_start.elapsed().as_secs()).unwrap_or(i64::MAX));
+
+    let total_run_time = Utc::now() - run_time_start;
+    TOTAL_JOB_DURATION_GAUGE
+        .with_label_values(&[&run_id.to_string()])
+        .set(total_run_time.num_seconds());
+
+    // This run's series replace the previous run's of the same kind,
+    // the latest run of the other kind is still served alongside them
+    let mut served_run_ids = runs::get_latest_runs_of_other_kinds(pool, run_id).await?;
+    served_run_ids.push(run_id);
+    drop_stale_runs(&served_run_ids);
+
+    if config.export.push() {
+        // Every series already carries its run_id label,
+        // so it can't be used as a grouping label as well
+        let metric_families = prometheus::gather();
+        let push_gateway = config.push_gateway.clone();
+
+        // The push uses a blocking HTTP client, which can't run on the async runtime
+        tokio::task::spawn_blocking(move || {
+            prometheus::push_metrics(
+                "network-monitoring",
+                HashMap::new(),
+                &push_gateway,
+                metric_families,
+                None,
+            )
+        })
+        .await??;
+    }
+
+    // Alerting is best effort, a failed Slack post shouldn't fail the run
+    if let Err(e) = Box::pin(alerts::notify(pool, run_id, &config, deregistered_nodes)).await {
+        tracing::error!("failed to send alerts {:?}", e);
+    }
+
+    Ok(())
+}
+
+/// Metrics of the users' replica sets checked by the legacy protocol, and of their CIDs
+#[tracing::instrument(skip(pool, config, deregistered_nodes))]
+async fn generate_replica_set_metrics(
+    pool: &PgPool,
+    run_id: i32,
+    config: &MetricsSettings,
+    deregistered_nodes: &[String],
+) -> Result<()> {
     let all_user_count = get_all_user_count(pool, run_id).await?;
     let primary_user_count = get_primary_user_count(pool, run_id).await?;
     let fully_synced_users_count = get_fully_synced_users_count(pool, run_id).await?;
@@ -62,12 +225,12 @@ pub async fn generate(pool: &PgPool, run_id: i32, config: MetricsSettings) -> Re
 
     let users_status_by_primary = get_users_status_by_primary(pool, run_id).await?;
     let users_status_by_replica = get_users_status_by_replica(pool, run_id).await?;
+    let missed_users_count = get_missed_users_count(pool, run_id).await?;
+    let missing_cids_count = get_missing_cids_count(pool, run_id, deregistered_nodes).await?;
+    let unchecked_cids_count = get_unchecked_cids_count(pool, run_id).await?;
+    let cid_availability = get_cid_availability(pool, run_id, deregistered_nodes).await?;
 
     // REGISTER METRICS
-    USER_COUNT_GAUGE
-        .with_label_values(&[&run_id.to_string()])
-        .set(user_count);
-
     for cnode_count in all_user_count {
         ALL_USER_COUNT_GAUGE
             .with_label_values(&[&cnode_count.endpoint, &run_id.to_string()])
@@ -132,23 +295,237 @@ pub async fn generate(pool: &PgPool, run_id: i32, config: MetricsSettings) -> Re
             .set(users_status.unsynced_count);
     }
 
-    let total_run_time = Utc::now() - run_time_start;
-    TOTAL_JOB_DURATION_GAUGE
+    for cnode_count in missed_users_count {
+        MISSED_USERS_COUNT_GAUGE
+            .with_label_values(&[&cnode_count.endpoint, &run_id.to_string()])
+            .set(cnode_count.count);
+    }
+
+    for cnode_cid_count in missing_cids_count {
+        MISSING_CIDS_COUNT_GAUGE
+            .with_label_values(&[
+                &cnode_cid_count.endpoint,
+                &cnode_cid_count.ctype,
+                &run_id.to_string(),
+            ])
+            .set(cnode_cid_count.count);
+    }
+
+    for cnode_cid_count in unchecked_cids_count {
+        UNCHECKED_CIDS_COUNT_GAUGE
+            .with_label_values(&[
+                &cnode_cid_count.endpoint,
+                &cnode_cid_count.ctype,
+                &run_id.to_string(),
+            ])
+            .set(cnode_cid_count.count);
+    }
+
+    for availability in cid_availability {
+        EXPECTED_CIDS_COUNT_GAUGE
+            .with_label_values(&[
+                &availability.endpoint,
+                &availability.ctype,
+                &availability.replica,
+                &run_id.to_string(),
+            ])
+            .set(availability.expected_count);
+        PRESENT_CIDS_COUNT_GAUGE
+            .with_label_values(&[
+                &availability.endpoint,
+                &availability.ctype,
+                &availability.replica,
+                &run_id.to_string(),
+            ])
+            .set(availability.present_count);
+    }
+
+    Ok(())
+}
+
+/// Metrics of the storage-v2 CIDs checked on the content nodes rendezvous hashing places them on
+#[tracing::instrument(skip(pool))]
+async fn generate_placement_metrics(pool: &PgPool, run_id: i32) -> Result<()> {
+    let cid_replication = get_cid_replication(pool, run_id).await?;
+    let placed_cids_count = get_placed_cids_count(pool, run_id).await?;
+    let unchecked_cids_count = get_unchecked_cids_count(pool, run_id).await?;
+    let shortfall = replication_shortfall(&cid_replication);
+
+    // REGISTER METRICS
+    let mut cids_by_replicas = BTreeMap::new();
+    for replication in &cid_replication {
+        *cids_by_replicas.entry(replication.held).or_insert(0) += replication.count;
+    }
+    for (replicas, count) in cids_by_replicas {
+        CID_REPLICAS_COUNT_GAUGE
+            .with_label_values(&[&replicas.to_string(), &run_id.to_string()])
+            .set(count);
+    }
+
+    UNDER_REPLICATED_CIDS_COUNT_GAUGE
         .with_label_values(&[&run_id.to_string()])
-        .set(total_run_time.num_seconds());
+        .set(shortfall.under_replicated_count);
 
-    let metric_families = prometheus::gather();
-    prometheus::push_metrics(
-        "network-monitoring",
-        labels! {"run_id".to_owned() => run_id.to_string(),},
-        &config.push_gateway,
-        metric_families,
-        None,
-    )?;
+    UNAVAILABLE_CIDS_COUNT_GAUGE
+        .with_label_values(&[&run_id.to_string()])
+        .set(shortfall.unavailable_count);
+
+    REPLICATION_SHORTFALL_GAUGE
+        .with_label_values(&[&run_id.to_string()])
+        .set(shortfall.missing_replicas);
+
+    for cnode in placed_cids_count {
+        PLACED_CIDS_COUNT_GAUGE
+            .with_label_values(&[&cnode.endpoint, &run_id.to_string()])
+            .set(cnode.placed_count);
+        PLACED_CIDS_PRESENT_COUNT_GAUGE
+            .with_label_values(&[&cnode.endpoint, &run_id.to_string()])
+            .set(cnode.present_count);
+    }
+
+    for cnode_cid_count in unchecked_cids_count {
+        UNCHECKED_CIDS_COUNT_GAUGE
+            .with_label_values(&[
+                &cnode_cid_count.endpoint,
+                &cnode_cid_count.ctype,
+                &run_id.to_string(),
+            ])
+            .set(cnode_cid_count.count);
+    }
 
     Ok(())
 }
 
+/// Sum up how far the CIDs of a storage-v2 run fall short of being on every node they're placed on
+#[must_use]
+pub fn replication_shortfall(cid_replication: &[CidReplication]) -> ReplicationShortfall {
+    cid_replication
+        .iter()
+        .filter(|replication| replication.held < replication.placed)
+        .fold(
+            ReplicationShortfall::default(),
+            |mut shortfall, replication| {
+                shortfall.under_replicated_count += replication.count;
+                if replication.held == 0 {
+                    shortfall.unavailable_count += replication.count;
+                }
+                shortfall.missing_replicas +=
+                    (replication.placed - replication.held) * replication.count;
+                shortfall
+            },
+        )
+}
+
+/// Estimates of the sync status of every user from the users a sampled run checked
+#[tracing::instrument(skip(pool))]
+async fn generate_sync_estimates(pool: &PgPool, run_id: i32, confidence_level: f64) -> Result<()> {
+    let z =
+        z_score(confidence_level).ok_or(MetricsError::InvalidConfidenceLevel(confidence_level))?;
+    let strata = get_sampled_strata(pool, run_id).await?;
+
+    // REGISTER METRICS
+    SAMPLED_POPULATION_COUNT_GAUGE
+        .with_label_values(&[&run_id.to_string()])
+        .set(strata.iter().map(|stratum| stratum.population).sum());
+
+    for (status, estimate) in [
+        (
+            "fully_synced",
+            estimate_ratio(&strata, |stratum| stratum.fully_synced, z),
+        ),
+        (
+            "partially_synced",
+            estimate_ratio(&strata, |stratum| stratum.partially_synced, z),
+        ),
+        (
+            "unsynced",
+            estimate_ratio(&strata, |stratum| stratum.unsynced, z),
+        ),
+    ] {
+        let Some(estimate) = estimate else {
+            tracing::warn!("run {run_id} has no sampled users to estimate {status} users from");
+            continue;
+        };
+
+        for (bound, value) in [
+            ("estimate", estimate.ratio),
+            ("lower", estimate.lower),
+            ("upper", estimate.upper),
+        ] {
+            SYNC_STATUS_ESTIMATE_GAUGE
+                .with_label_values(&[status, bound, &run_id.to_string()])
+                .set(value);
+        }
+    }
+
+    Ok(())
+}
+
+/// The standard normal quantile of a two-sided `confidence_level`, e.g. 1.96 for 0.95.
+/// Abramowitz and Stegun 26.2.23, accurate to 4.5e-4.
+#[must_use]
+pub fn z_score(confidence_level: f64) -> Option<f64> {
+    if !(confidence_level > 0.0 && confidence_level < 1.0) {
+        return None;
+    }
+
+    let tail = (1.0 - confidence_level) / 2.0;
+    let t = (-2.0 * tail.ln()).sqrt();
+
+    Some(
+        t - (2.515_517 + 0.802_853 * t + 0.010_328 * t * t)
+            / (1.0 + 1.432_788 * t + 0.189_269 * t * t + 0.001_308 * t * t * t),
+    )
+}
+
+/// Stratified estimate of the share of every user counted by `count`, each primary's sample
+/// weighted by its share of the users, with a normal approximation interval `z` standard errors wide.
+/// `None` if no primary has sampled users.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn estimate_ratio(
+    strata: &[SampledStratum],
+    count: impl Fn(&SampledStratum) -> i64,
+    z: f64,
+) -> Option<Estimate> {
+    let strata = strata
+        .iter()
+        .filter(|stratum| stratum.sampled > 0)
+        .collect::<Vec<&SampledStratum>>();
+    let population = strata.iter().map(|stratum| stratum.population).sum::<i64>() as f64;
+    if population <= 0.0 {
+        return None;
+    }
+
+    let (ratio, variance) = strata
+        .iter()
+        .fold((0.0, 0.0), |(ratio, variance), stratum| {
+            let weight = stratum.population as f64 / population;
+            let sampled = stratum.sampled as f64;
+            let stratum_ratio = count(stratum) as f64 / sampled;
+
+            // A single user says nothing of the spread of its primary, assume the widest
+            let spread = if stratum.sampled > 1 {
+                stratum_ratio * (1.0 - stratum_ratio) / (sampled - 1.0)
+            } else {
+                0.25
+            };
+            let unsampled = (1.0 - sampled / stratum.population as f64).max(0.0);
+
+            (
+                ratio + weight * stratum_ratio,
+                variance + weight * weight * unsampled * spread,
+            )
+        });
+    let margin = z * variance.sqrt();
+
+    Some(Estimate {
+        ratio,
+        lower: (ratio - margin).max(0.0),
+        upper: (ratio + margin).min(1.0),
+    })
+}
+
 #[tracing::instrument(skip(pool))]
 async fn get_run_start_time(pool: &PgPool, run_id: i32) -> Result<DateTime<Utc>> {
     let run_start_time = sqlx::query!(
@@ -187,7 +564,7 @@ async fn get_user_count(pool: &PgPool, run_id: i32) -> Result<i64> {
 }
 
 #[tracing::instrument(skip(pool))]
-async fn get_all_user_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeCount>> {
+pub(crate) async fn get_all_user_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeCount>> {
     let all_user_count: Vec<CNodeCount> = sqlx::query!(
         r#"
         SELECT joined.endpoint AS endpoint, COUNT(*) AS count
@@ -220,7 +597,7 @@ async fn get_all_user_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeCount
 }
 
 #[tracing::instrument(skip(pool))]
-async fn get_primary_user_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeCount>> {
+pub(crate) async fn get_primary_user_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeCount>> {
     let primary_user_count: Vec<CNodeCount> = sqlx::query!(
         r#"
             SELECT 
@@ -307,7 +684,7 @@ async fn get_partially_synced_users_count(pool: &PgPool, run_id: i32) -> Result<
 }
 
 #[tracing::instrument(skip(pool))]
-async fn get_unsynced_users_count(pool: &PgPool, run_id: i32) -> Result<i64> {
+pub(crate) async fn get_unsynced_users_count(pool: &PgPool, run_id: i32) -> Result<i64> {
     let unsynced_users_count = sqlx::query!(
         r#"
     SELECT COUNT(*) as user_count
@@ -441,7 +818,10 @@ async fn get_users_with_entire_replica_set_not_in_spid_set_count(
 }
 
 #[tracing::instrument(skip(pool))]
-async fn get_users_status_by_primary(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeSyncedStatus>> {
+pub(crate) async fn get_users_status_by_primary(
+    pool: &PgPool,
+    run_id: i32,
+) -> Result<Vec<CNodeSyncedStatus>> {
     let users_status_by_primary = sqlx::query!(r#"
         SELECT fully_synced.spid, cnodes.endpoint, fully_synced.fully_synced_count, partially_synced.partially_synced_count, unsynced.unsynced_count
         FROM (
@@ -719,3 +1099,390 @@ async fn get_users_status_by_replica(pool: &PgPool, run_id: i32) -> Result<Vec<C
 
     Ok(users_status_by_replica)
 }
+
+/// Users of the batches that were still missed after the content stage retried them
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_missed_users_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeCount>> {
+    let missed_users_count = sqlx::query!(
+        r#"
+        SELECT cnodes.endpoint AS endpoint, SUM(missed.user_count)::bigint AS count
+        FROM network_monitoring_missed_batches AS missed
+        JOIN network_monitoring_content_nodes AS cnodes
+        ON
+            cnodes.run_id = missed.run_id
+        AND
+            cnodes.spid = missed.spid
+        WHERE missed.run_id = $1
+        GROUP BY cnodes.endpoint;
+        "#,
+        run_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CNodeCount {
+        endpoint: row.endpoint,
+        count: row.count.unwrap_or(0),
+    })
+    .collect::<Vec<CNodeCount>>();
+
+    Ok(missed_users_count)
+}
+
+/// The number of CIDs, grouped by content node and CID type, that discovery
+/// expects a content node to have because it's in the owner's replica set
+/// but that the content node reported not having, unchecked CIDs aren't missing.
+/// Deregistered and out of scope content nodes aren't asked, so they're left out.
+#[tracing::instrument(skip(pool, deregistered_nodes))]
+async fn get_missing_cids_count(
+    pool: &PgPool,
+    run_id: i32,
+    deregistered_nodes: &[String],
+) -> Result<Vec<CNodeCidCount>> {
+    let missing_cids_count = sqlx::query!(
+        r#"
+        SELECT cnodes.endpoint, expected.ctype, COUNT(*) AS count
+        FROM network_monitoring_content_nodes AS cnodes
+        JOIN network_monitoring_users AS users
+        ON
+            users.run_id = cnodes.run_id
+        AND 
+            cnodes.spid IN (users.primaryspid, users.secondary1spid, users.secondary2spid)
+        JOIN network_monitoring_cids_from_discovery AS expected
+        ON
+            expected.run_id = users.run_id
+        AND
+            expected.user_id = users.user_id
+        LEFT JOIN network_monitoring_cids_from_content AS found
+        ON
+            found.run_id = expected.run_id
+        AND
+            found.content_node_spid = cnodes.spid
+        AND
+            found.user_id = expected.user_id
+        AND
+            found.cid = expected.cid
+        WHERE
+            cnodes.run_id = $1
+        AND
+            cnodes.is_in_scope
+        AND
+            cnodes.endpoint <> ALL($2)
+        AND
+            found.cid IS NULL
+        AND NOT EXISTS (
+            SELECT 1
+            FROM network_monitoring_unchecked_cids AS unchecked
+            WHERE
+                unchecked.run_id = expected.run_id
+            AND
+                unchecked.content_node_spid = cnodes.spid
+            AND
+                unchecked.user_id = expected.user_id
+            AND
+                unchecked.cid = expected.cid
+        )
+        GROUP BY
+            cnodes.endpoint, expected.ctype;
+    "#,
+        run_id,
+        deregistered_nodes,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CNodeCidCount {
+        endpoint: row.endpoint,
+        ctype: row.ctype,
+        count: row.count.unwrap_or(0),
+    })
+    .collect::<Vec<CNodeCidCount>>();
+
+    Ok(missing_cids_count)
+}
+
+/// The number of CIDs, grouped by content node and CID type, that a content node
+/// was asked about but didn't answer for
+#[tracing::instrument(skip(pool))]
+async fn get_unchecked_cids_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodeCidCount>> {
+    let unchecked_cids_count = sqlx::query!(
+        r#"
+        SELECT cnodes.endpoint, expected.ctype, COUNT(*) AS count
+        FROM network_monitoring_unchecked_cids AS unchecked
+        JOIN network_monitoring_content_nodes AS cnodes
+        ON
+            cnodes.run_id = unchecked.run_id
+        AND
+            cnodes.spid = unchecked.content_node_spid
+        JOIN network_monitoring_cids_from_discovery AS expected
+        ON
+            expected.run_id = unchecked.run_id
+        AND
+            expected.user_id = unchecked.user_id
+        AND
+            expected.cid = unchecked.cid
+        WHERE
+            unchecked.run_id = $1
+        GROUP BY
+            cnodes.endpoint, expected.ctype;
+    "#,
+        run_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CNodeCidCount {
+        endpoint: row.endpoint,
+        ctype: row.ctype,
+        count: row.count.unwrap_or(0),
+    })
+    .collect::<Vec<CNodeCidCount>>();
+
+    Ok(unchecked_cids_count)
+}
+
+/// The number of CIDs discovery expects each content node to have and how many
+/// of those it has, grouped by CID type and by the replica the content node is
+/// for the owner of the CID (primary, secondary1, secondary2).
+/// Deregistered and out of scope content nodes aren't asked, so they're left out.
+#[tracing::instrument(skip(pool, deregistered_nodes))]
+async fn get_cid_availability(
+    pool: &PgPool,
+    run_id: i32,
+    deregistered_nodes: &[String],
+) -> Result<Vec<CNodeCidAvailability>> {
+    let cid_availability = sqlx::query!(
+        r#"
+        SELECT
+            cnodes.endpoint,
+            replicas.replica,
+            expected.ctype,
+            COUNT(*) AS expected_count,
+            COUNT(*) FILTER (
+                WHERE EXISTS (
+                    SELECT 1
+                    FROM network_monitoring_cids_from_content AS found
+                    WHERE
+                        found.run_id = expected.run_id
+                    AND
+                        found.content_node_spid = cnodes.spid
+                    AND
+                        found.user_id = expected.user_id
+                    AND
+                        found.cid = expected.cid
+                )
+            ) AS present_count
+        FROM network_monitoring_users AS users
+        CROSS JOIN LATERAL (
+            VALUES
+                ('primary', users.primaryspid),
+                ('secondary1', users.secondary1spid),
+                ('secondary2', users.secondary2spid)
+        ) AS replicas(replica, spid)
+        JOIN network_monitoring_content_nodes AS cnodes
+        ON
+            cnodes.run_id = users.run_id
+        AND
+            cnodes.spid = replicas.spid
+        JOIN network_monitoring_cids_from_discovery AS expected
+        ON
+            expected.run_id = users.run_id
+        AND
+            expected.user_id = users.user_id
+        WHERE
+            users.run_id = $1
+        AND
+            cnodes.is_in_scope
+        AND
+            cnodes.endpoint <> ALL($2)
+        GROUP BY
+            cnodes.endpoint, replicas.replica, expected.ctype;
+    "#,
+        run_id,
+        deregistered_nodes,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CNodeCidAvailability {
+        endpoint: row.endpoint,
+        replica: row.replica.unwrap_or_default(),
+        ctype: row.ctype,
+        expected_count: row.expected_count.unwrap_or(0),
+        present_count: row.present_count.unwrap_or(0),
+    })
+    .collect::<Vec<CNodeCidAvailability>>();
+
+    Ok(cid_availability)
+}
+
+/// The users each primary of a sampled run had, and the sync status of those it checked
+#[tracing::instrument(skip(pool))]
+async fn get_sampled_strata(pool: &PgPool, run_id: i32) -> Result<Vec<SampledStratum>> {
+    let sampled_strata = sqlx::query!(
+        r#"
+        SELECT
+            strata.population,
+            COUNT(users.user_id) AS sampled,
+            COUNT(*) FILTER (
+                WHERE
+                    users.primary_clock_value != -2
+                AND
+                    users.primary_clock_value = users.secondary1_clock_value
+                AND
+                    users.secondary1_clock_value = users.secondary2_clock_value
+            ) AS fully_synced,
+            COUNT(*) FILTER (
+                WHERE
+                    users.primary_clock_value != -2
+                AND (
+                    users.primary_clock_value = users.secondary1_clock_value
+                    OR
+                    users.primary_clock_value = users.secondary2_clock_value
+                )
+                AND
+                    users.secondary1_clock_value != users.secondary2_clock_value
+            ) AS partially_synced,
+            COUNT(*) FILTER (
+                WHERE
+                    users.primary_clock_value != -2
+                AND
+                    users.primary_clock_value != users.secondary1_clock_value
+                AND
+                    users.primary_clock_value != users.secondary2_clock_value
+            ) AS unsynced
+        FROM network_monitoring_sample_strata AS strata
+        LEFT JOIN network_monitoring_users AS users
+        ON
+            users.run_id = strata.run_id
+        AND
+            users.primaryspid = strata.primary_spid
+        WHERE strata.run_id = $1
+        GROUP BY strata.primary_spid, strata.population;
+    "#,
+        run_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| SampledStratum {
+        population: row.population,
+        sampled: row.sampled.unwrap_or(0),
+        fully_synced: row.fully_synced.unwrap_or(0),
+        partially_synced: row.partially_synced.unwrap_or(0),
+        unsynced: row.unsynced.unwrap_or(0),
+    })
+    .collect::<Vec<SampledStratum>>();
+
+    Ok(sampled_strata)
+}
+
+/// The CIDs of a storage-v2 run grouped by how many content nodes they're placed on
+/// and how many of those have them, leaving out the nodes that didn't answer for them.
+/// Unhealthy and out of scope content nodes aren't asked at all, so they're left out too.
+#[tracing::instrument(skip(pool))]
+async fn get_cid_replication(pool: &PgPool, run_id: i32) -> Result<Vec<CidReplication>> {
+    let cid_replication = sqlx::query!(
+        r#"
+        SELECT replication.placed, replication.held, COUNT(*) AS count
+        FROM (
+            SELECT
+                placements.cid,
+                COUNT(*) AS placed,
+                COUNT(*) FILTER (
+                    WHERE EXISTS (
+                        SELECT 1
+                        FROM network_monitoring_cids_from_content AS found
+                        WHERE
+                            found.run_id = placements.run_id
+                        AND
+                            found.content_node_spid = placements.spid
+                        AND
+                            found.cid = placements.cid
+                    )
+                ) AS held
+            FROM network_monitoring_cid_placements AS placements
+            JOIN network_monitoring_content_nodes AS cnodes
+            ON
+                cnodes.run_id = placements.run_id
+            AND
+                cnodes.spid = placements.spid
+            WHERE
+                placements.run_id = $1
+            AND cnodes.is_in_scope
+            AND cnodes.is_healthy
+            AND NOT EXISTS (
+                SELECT 1
+                FROM network_monitoring_unchecked_cids AS unchecked
+                WHERE
+                    unchecked.run_id = placements.run_id
+                AND
+                    unchecked.content_node_spid = placements.spid
+                AND
+                    unchecked.cid = placements.cid
+            )
+            GROUP BY placements.cid
+        ) AS replication
+        GROUP BY replication.placed, replication.held;
+    "#,
+        run_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CidReplication {
+        placed: row.placed.unwrap_or(0),
+        held: row.held.unwrap_or(0),
+        count: row.count.unwrap_or(0),
+    })
+    .collect::<Vec<CidReplication>>();
+
+    Ok(cid_replication)
+}
+
+/// The number of storage-v2 CIDs placed on each healthy content node in scope
+/// and how many of those it has
+#[tracing::instrument(skip(pool))]
+async fn get_placed_cids_count(pool: &PgPool, run_id: i32) -> Result<Vec<CNodePlacedCids>> {
+    let placed_cids_count = sqlx::query!(
+        r#"
+        SELECT
+            cnodes.endpoint,
+            COUNT(*) AS placed_count,
+            COUNT(*) FILTER (
+                WHERE EXISTS (
+                    SELECT 1
+                    FROM network_monitoring_cids_from_content AS found
+                    WHERE
+                        found.run_id = placements.run_id
+                    AND
+                        found.content_node_spid = placements.spid
+                    AND
+                        found.cid = placements.cid
+                )
+            ) AS present_count
+        FROM network_monitoring_cid_placements AS placements
+        JOIN network_monitoring_content_nodes AS cnodes
+        ON
+            cnodes.run_id = placements.run_id
+        AND
+            cnodes.spid = placements.spid
+        WHERE placements.run_id = $1
+        AND cnodes.is_in_scope
+        AND cnodes.is_healthy
+        GROUP BY cnodes.endpoint;
+    "#,
+        run_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| CNodePlacedCids {
+        endpoint: row.endpoint,
+        placed_count: row.placed_count.unwrap_or(0),
+        present_count: row.present_count.unwrap_or(0),
+    })
+    .collect::<Vec<CNodePlacedCids>>();
+
+    Ok(placed_cids_count)
+}
@@ -1,7 +1,24 @@
-use prometheus::IntGaugeVec;
+use std::collections::HashMap;
+
+use prometheus::{
+    core::{Collector, MetricVec, MetricVecBuilder},
+    CounterVec, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec,
+};
 
 use lazy_static::lazy_static;
-use prometheus::register_int_gauge_vec;
+use prometheus::{
+    register_counter_vec, register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge_vec,
+};
+
+/// Batches of 5000 users take from well under a second on a healthy node
+/// to minutes on a struggling one
+const USER_BATCH_DURATION_BUCKETS: &[f64] =
+    &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Most requests to content nodes are answered within a few seconds
+const CONTENT_NODE_REQUEST_DURATION_BUCKETS: &[f64] =
+    &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
 
 lazy_static! {
     pub(crate) static ref USER_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
@@ -84,7 +101,7 @@ lazy_static! {
     .unwrap();
     pub(crate) static ref USER_BATCH_DURATION_GAUGE: IntGaugeVec = register_int_gauge_vec!(
         "audius_nm_user_batch_duration",
-        "the amount of time it takes to fetch and save a user batch",
+        "the amount of time in milliseconds it took to fetch and save the latest user batch",
         &["run_id", "endpoint"],
     )
     .unwrap();
@@ -137,4 +154,215 @@ lazy_static! {
         &["run_id", "endpoint"]
     )
     .unwrap();
+    pub(crate) static ref REGISTRY_MISMATCH_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_registry_mismatch_count",
+        "the number of content nodes that differ between the registry and the cross-check registry",
+        &["source", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref MISSING_CIDS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_missing_cids_count",
+        "the number of CIDs a content node should have but does not grouped by CID type",
+        &["endpoint", "ctype", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref UNCHECKED_CIDS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_unchecked_cids_count",
+        "the number of CIDs a content node was asked about but didn't answer for grouped by CID type",
+        &["endpoint", "ctype", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref EXPECTED_CIDS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_expected_cids_count",
+        "the number of CIDs a content node should have grouped by CID type and replica",
+        &["endpoint", "ctype", "replica", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref PRESENT_CIDS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_present_cids_count",
+        "the number of expected CIDs a content node has grouped by CID type and replica",
+        &["endpoint", "ctype", "replica", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref CONTENT_NODE_HEALTHY_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_content_node_healthy",
+        "whether a storage-v2 content node reported itself healthy, 0 if it couldn't be reached",
+        &["endpoint", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref CID_REPLICAS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_cid_replicas_count",
+        "the number of storage-v2 CIDs grouped by how many of the content nodes they're placed on have them",
+        &["replicas", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref UNDER_REPLICATED_CIDS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_under_replicated_cids_count",
+        "the number of storage-v2 CIDs missing from at least one content node they're placed on",
+        &["run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref UNAVAILABLE_CIDS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_unavailable_cids_count",
+        "the number of storage-v2 CIDs none of the content nodes they're placed on have",
+        &["run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref REPLICATION_SHORTFALL_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_replication_shortfall",
+        "the number of replicas of storage-v2 CIDs missing from the content nodes they're placed on",
+        &["run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref PLACED_CIDS_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_placed_cids_count",
+        "the number of storage-v2 CIDs rendezvous hashing places on a content node",
+        &["endpoint", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref PLACED_CIDS_PRESENT_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_placed_cids_present_count",
+        "the number of storage-v2 CIDs placed on a content node that it has",
+        &["endpoint", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref SYNC_STATUS_ESTIMATE_GAUGE: GaugeVec = register_gauge_vec!(
+        "audius_nm_sync_status_estimate",
+        "the share of every user in a sync status estimated from a sampled run, with the bounds of its confidence interval",
+        &["status", "bound", "run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref SAMPLED_POPULATION_COUNT_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_sampled_population_count",
+        "the number of users a sampled run drew its sample from",
+        &["run_id"]
+    )
+    .unwrap();
+    pub(crate) static ref USER_BATCH_DURATION_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "audius_nm_user_batch_duration_seconds",
+        "the time it takes to fetch, check and save a user batch grouped by replica and status, `succeeded` or the step it failed at",
+        &["endpoint", "replica", "status"],
+        USER_BATCH_DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub(crate) static ref CONTENT_NODE_REQUEST_DURATION_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "audius_nm_content_node_request_duration_seconds",
+        "the time it takes a content node to answer a request attempt grouped by route",
+        &["endpoint", "route"],
+        CONTENT_NODE_REQUEST_DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub(crate) static ref CONTENT_NODE_REQUEST_ERRORS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "audius_nm_content_node_request_errors_total",
+        "the number of failed request attempts to a content node grouped by route, kind of error and HTTP status",
+        &["endpoint", "route", "kind", "status"]
+    )
+    .unwrap();
+    pub(crate) static ref CONTENT_NODE_REQUEST_LIMIT_GAUGE: GaugeVec = register_gauge_vec!(
+        "audius_nm_content_node_request_limit",
+        "the configured limits on requests to content nodes, 0 requests_per_second is unlimited",
+        &["limit"]
+    )
+    .unwrap();
+    pub(crate) static ref CONTENT_NODE_IN_FLIGHT_REQUESTS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "audius_nm_content_node_in_flight_requests",
+        "the number of requests to a content node currently in flight",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub(crate) static ref CONTENT_NODE_REQUEST_WAIT_SECONDS_COUNTER: CounterVec = register_counter_vec!(
+        "audius_nm_content_node_request_wait_seconds_total",
+        "the time requests to a content node waited on a limit grouped by limit",
+        &["endpoint", "limit"]
+    )
+    .unwrap();
+    pub(crate) static ref SKIPPED_RUNS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "audius_nm_skipped_runs_total",
+        "the number of scheduled runs skipped because another run held the run lock grouped by kind, sampled or full",
+        &["kind"]
+    )
+    .unwrap();
+}
+
+/// Clear the series of the requests to content nodes, which aren't labelled with a run,
+/// so that `/metrics` only serves the requests of the run in progress.
+/// The skipped runs are kept, they count the runs skipped since the daemon started.
+pub(crate) fn reset_request_metrics() {
+    CONTENT_NODE_IN_FLIGHT_REQUESTS_GAUGE.reset();
+    USER_BATCH_DURATION_HISTOGRAM.reset();
+    CONTENT_NODE_REQUEST_DURATION_HISTOGRAM.reset();
+    CONTENT_NODE_REQUEST_ERRORS_COUNTER.reset();
+    CONTENT_NODE_REQUEST_LIMIT_GAUGE.reset();
+    CONTENT_NODE_REQUEST_WAIT_SECONDS_COUNTER.reset();
+}
+
+/// Remove the series of every run but `run_ids`, so that `/metrics` keeps serving
+/// the latest runs until the next one has its metrics generated
+pub(crate) fn drop_stale_runs(run_ids: &[i32]) {
+    let run_ids = run_ids
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>();
+
+    for gauge in [
+        &*USER_COUNT_GAUGE,
+        &*ALL_USER_COUNT_GAUGE,
+        &*PRIMARY_USER_COUNT_GAUGE,
+        &*FULLY_SYNCED_USERS_COUNT_GAUGE,
+        &*PARTIALLY_SYNCED_USERS_COUNT_GAUGE,
+        &*UNSYNCED_USERS_COUNT_GAUGE,
+        &*NULL_PRIMARY_USERS_COUNT_GAUGE,
+        &*UNHEALTHY_REPLICA_USERS_COUNT_GAUGE,
+        &*MISSED_USERS_COUNT_GAUGE,
+        &*INDEXING_DISCOVERY_DURATION_GAUGE,
+        &*INDEXING_CONTENT_DURATION_GAUGE,
+        &*GENERATING_METRICS_DURATION_GAUGE,
+        &*TOTAL_JOB_DURATION_GAUGE,
+        &*USER_BATCH_DURATION_GAUGE,
+        &*USERS_WITH_ALL_FOUNDATION_NODE_REPLICA_SET_GAUGE,
+        &*USERS_WITH_NO_FOUNDATION_NODE_REPLICA_SET_GAUGE,
+        &*FULLY_SYNCED_USER_BY_PRIMARY_COUNT_GAUGE,
+        &*PARTIALLY_SYNCED_USER_BY_PRIMARY_COUNT_GAUGE,
+        &*UNSYNCED_USER_BY_PRIMARY_COUNT_GAUGE,
+        &*FULLY_SYNCED_USER_BY_REPLICA_COUNT_GAUGE,
+        &*PARTIALLY_SYNCED_USER_BY_REPLICA_COUNT_GAUGE,
+        &*UNSYNCED_USER_BY_REPLICA_COUNT_GAUGE,
+        &*REGISTRY_MISMATCH_COUNT_GAUGE,
+        &*MISSING_CIDS_COUNT_GAUGE,
+        &*UNCHECKED_CIDS_COUNT_GAUGE,
+        &*EXPECTED_CIDS_COUNT_GAUGE,
+        &*PRESENT_CIDS_COUNT_GAUGE,
+        &*CONTENT_NODE_HEALTHY_GAUGE,
+        &*CID_REPLICAS_COUNT_GAUGE,
+        &*UNDER_REPLICATED_CIDS_COUNT_GAUGE,
+        &*UNAVAILABLE_CIDS_COUNT_GAUGE,
+        &*REPLICATION_SHORTFALL_GAUGE,
+        &*PLACED_CIDS_COUNT_GAUGE,
+        &*PLACED_CIDS_PRESENT_COUNT_GAUGE,
+        &*SAMPLED_POPULATION_COUNT_GAUGE,
+    ] {
+        drop_stale_series(gauge, &run_ids);
+    }
+
+    drop_stale_series(&SYNC_STATUS_ESTIMATE_GAUGE, &run_ids);
+}
+
+fn drop_stale_series<T: MetricVecBuilder>(metric_vec: &MetricVec<T>, run_ids: &[String]) {
+    for family in metric_vec.collect() {
+        for metric in family.get_metric() {
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect::<HashMap<&str, &str>>();
+
+            if labels
+                .get("run_id")
+                .is_some_and(|run_id| !run_ids.iter().any(|kept| kept == run_id))
+            {
+                // The series was just collected, so it can't be missing
+                let _ = metric_vec.remove(&labels);
+            }
+        }
+    }
 }
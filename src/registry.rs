@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use thiserror::Error;
+
+use crate::{
+    configuration::{ChainSettings, DiscoverySettings, RegistrySource},
+    domain::ContentNode,
+};
+
+// First four bytes of the keccak256 hash of each ServiceProviderFactory function signature
+const GET_TOTAL_SERVICE_TYPE_PROVIDERS: &str = "623fa631"; // getTotalServiceTypeProviders(bytes32)
+const GET_SERVICE_ENDPOINT_INFO: &str = "748ea82c"; // getServiceEndpointInfo(bytes32,uint256)
+
+const WORD_SIZE: usize = 32;
+const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("the chain registry is selected but `discovery.chain` is not configured")]
+    ChainNotConfigured,
+    #[error("json-rpc call failed: {0}")]
+    RpcError(String),
+    #[error("json-rpc response is not valid ABI-encoded data")]
+    InvalidAbiData,
+    #[error("service type `{0}` does not fit in a bytes32")]
+    InvalidServiceType(String),
+}
+
+/// A source of truth for which content nodes are registered on the network
+#[async_trait]
+pub trait NodeRegistry: Send + Sync {
+    fn source(&self) -> RegistrySource;
+
+    async fn content_nodes(&self) -> Result<Vec<ContentNode>>;
+}
+
+/// Build the registry for `source` from the discovery settings
+///
+/// # Errors
+///
+/// Returns an error if the chain registry is selected without `discovery.chain`
+pub fn from_settings(
+    source: RegistrySource,
+    pool: &PgPool,
+    config: &DiscoverySettings,
+) -> Result<Box<dyn NodeRegistry>> {
+    let registry: Box<dyn NodeRegistry> = match source {
+        RegistrySource::Discovery => Box::new(DiscoveryRegistry::new(pool.clone())),
+        RegistrySource::Static => {
+            Box::new(StaticRegistry::new(config.static_content_nodes.clone()))
+        }
+        RegistrySource::Chain => Box::new(ChainRegistry::new(
+            config
+                .chain
+                .clone()
+                .ok_or(RegistryError::ChainNotConfigured)?,
+        )),
+    };
+
+    Ok(registry)
+}
+
+/// Content nodes indexed by discovery into `ursm_content_nodes`,
+/// leaving out the nodes without an endpoint to request
+pub struct DiscoveryRegistry {
+    pool: PgPool,
+}
+
+impl DiscoveryRegistry {
+    #[must_use]
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl NodeRegistry for DiscoveryRegistry {
+    fn source(&self) -> RegistrySource {
+        RegistrySource::Discovery
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn content_nodes(&self) -> Result<Vec<ContentNode>> {
+        let content_nodes = sqlx::query!(
+            r#"
+            SELECT DISTINCT ON (cnode_sp_id)
+                cnode_sp_id,
+                endpoint AS "endpoint!",
+                owner_wallet,
+                delegate_owner_wallet
+            FROM discovery.ursm_content_nodes
+            WHERE
+                is_current = TRUE
+            AND
+                endpoint IS NOT NULL
+            AND
+                endpoint <> ''
+            ORDER BY cnode_sp_id, blocknumber DESC;
+        "#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| ContentNode {
+            endpoint: row.endpoint,
+            spid: row.cnode_sp_id,
+            owner_wallet: Some(row.owner_wallet),
+            delegate_owner_wallet: Some(row.delegate_owner_wallet),
+        })
+        .collect::<Vec<ContentNode>>();
+
+        Ok(content_nodes)
+    }
+}
+
+/// Content nodes listed in `discovery.static_content_nodes`
+pub struct StaticRegistry {
+    content_nodes: Vec<ContentNode>,
+}
+
+impl StaticRegistry {
+    #[must_use]
+    pub fn new(content_nodes: Vec<ContentNode>) -> Self {
+        Self { content_nodes }
+    }
+}
+
+#[async_trait]
+impl NodeRegistry for StaticRegistry {
+    fn source(&self) -> RegistrySource {
+        RegistrySource::Static
+    }
+
+    async fn content_nodes(&self) -> Result<Vec<ContentNode>> {
+        Ok(self.content_nodes.clone())
+    }
+}
+
+/// Content nodes registered in the `ServiceProviderFactory` contract,
+/// read over Ethereum JSON-RPC
+pub struct ChainRegistry {
+    client: reqwest::Client,
+    config: ChainSettings,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcRequest {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'static str,
+    params: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcResponse {
+    result: Option<String>,
+    error: Option<RpcResponseError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcResponseError {
+    code: i64,
+    message: String,
+}
+
+impl ChainRegistry {
+    #[must_use]
+    pub fn new(config: ChainSettings) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    async fn eth_call(&self, data: String) -> Result<Vec<u8>> {
+        let request = RpcRequest {
+            jsonrpc: "2.0",
+            id: 1,
+            method: "eth_call",
+            params: json!([
+                {
+                    "to": self.config.service_provider_factory_address,
+                    "data": data,
+                },
+                "latest",
+            ]),
+        };
+
+        let response = self
+            .client
+            .post(&self.config.rpc_url)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<RpcResponse>()
+            .await?;
+
+        if let Some(error) = response.error {
+            return Err(RegistryError::RpcError(format!(
+                "{} (code {})",
+                error.message, error.code
+            ))
+            .into());
+        }
+
+        let result = response
+            .result
+            .ok_or_else(|| RegistryError::RpcError("response has no result".into()))?;
+
+        Ok(hex::decode(result.trim_start_matches("0x"))
+            .map_err(|_| RegistryError::InvalidAbiData)?)
+    }
+
+    fn service_type(&self) -> Result<String, RegistryError> {
+        encode_bytes32(&self.config.service_type)
+            .ok_or_else(|| RegistryError::InvalidServiceType(self.config.service_type.clone()))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_total_service_type_providers(&self) -> Result<u64> {
+        let data = format!(
+            "0x{GET_TOTAL_SERVICE_TYPE_PROVIDERS}{}",
+            self.service_type()?
+        );
+        let output = self.eth_call(data).await?;
+
+        Ok(decode_uint(&output, 0)?)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_service_endpoint_info(&self, spid: u64) -> Result<ContentNode> {
+        let data = format!(
+            "0x{GET_SERVICE_ENDPOINT_INFO}{}{spid:064x}",
+            self.service_type()?
+        );
+        let output = self.eth_call(data).await?;
+
+        // (address owner, string endpoint, uint256 blockNumber, address delegateOwnerWallet)
+        let owner_wallet = decode_address(&output, 0)?;
+        let endpoint = decode_string(&output, 1)?;
+        let delegate_owner_wallet = decode_address(&output, 3)?;
+
+        Ok(ContentNode {
+            endpoint,
+            spid: i32::try_from(spid).map_err(|_| RegistryError::InvalidAbiData)?,
+            owner_wallet: Some(owner_wallet),
+            delegate_owner_wallet: Some(delegate_owner_wallet),
+        })
+    }
+}
+
+#[async_trait]
+impl NodeRegistry for ChainRegistry {
+    fn source(&self) -> RegistrySource {
+        RegistrySource::Chain
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn content_nodes(&self) -> Result<Vec<ContentNode>> {
+        let total = self.get_total_service_type_providers().await?;
+
+        let mut content_nodes = Vec::new();
+        for spid in 1..=total {
+            let cnode = self.get_service_endpoint_info(spid).await?;
+
+            // Deregistered service providers keep their spid but have their info zeroed out
+            if cnode.endpoint.is_empty() && cnode.owner_wallet.as_deref() == Some(ZERO_ADDRESS) {
+                continue;
+            }
+
+            content_nodes.push(cnode);
+        }
+
+        Ok(content_nodes)
+    }
+}
+
+/// Content nodes that don't match between two registries
+#[derive(Debug, Default)]
+pub struct RegistryDiff {
+    pub missing_from_other: Vec<ContentNode>,
+    pub missing_from_registry: Vec<ContentNode>,
+    pub mismatched: Vec<(ContentNode, ContentNode)>,
+}
+
+impl RegistryDiff {
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.missing_from_other.len() + self.missing_from_registry.len() + self.mismatched.len()
+    }
+}
+
+/// Compare the content nodes of `registry` against those of `other` by spid,
+/// treating endpoints and wallets as equal regardless of case or trailing slashes
+#[must_use]
+pub fn cross_check(registry: &[ContentNode], other: &[ContentNode]) -> RegistryDiff {
+    let mut other_by_spid = other
+        .iter()
+        .map(|cnode| (cnode.spid, cnode))
+        .collect::<HashMap<i32, &ContentNode>>();
+
+    let mut diff = RegistryDiff::default();
+    for cnode in registry {
+        match other_by_spid.remove(&cnode.spid) {
+            None => diff.missing_from_other.push(cnode.clone()),
+            Some(other_cnode) if !same_node(cnode, other_cnode) => {
+                diff.mismatched.push((cnode.clone(), other_cnode.clone()));
+            }
+            Some(_) => (),
+        }
+    }
+
+    let mut missing_from_registry = other_by_spid
+        .into_values()
+        .cloned()
+        .collect::<Vec<ContentNode>>();
+    missing_from_registry.sort_by_key(|cnode| cnode.spid);
+    diff.missing_from_registry = missing_from_registry;
+
+    diff
+}
+
+fn same_node(a: &ContentNode, b: &ContentNode) -> bool {
+    let same_wallet = |a: &Option<String>, b: &Option<String>| match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        // a registry that doesn't know the wallet can't disagree about it
+        _ => true,
+    };
+
+    a.endpoint
+        .trim_end_matches('/')
+        .eq_ignore_ascii_case(b.endpoint.trim_end_matches('/'))
+        && same_wallet(&a.owner_wallet, &b.owner_wallet)
+        && same_wallet(&a.delegate_owner_wallet, &b.delegate_owner_wallet)
+}
+
+fn encode_bytes32(value: &str) -> Option<String> {
+    if value.len() > WORD_SIZE {
+        return None;
+    }
+
+    let mut word = value.as_bytes().to_vec();
+    word.resize(WORD_SIZE, 0);
+
+    Some(hex::encode(word))
+}
+
+fn word(data: &[u8], index: usize) -> Result<&[u8], RegistryError> {
+    data.get(index * WORD_SIZE..(index + 1) * WORD_SIZE)
+        .ok_or(RegistryError::InvalidAbiData)
+}
+
+fn decode_uint(data: &[u8], index: usize) -> Result<u64, RegistryError> {
+    let word = word(data, index)?;
+    let (high, low) = word.split_at(WORD_SIZE - 8);
+
+    if high.iter().any(|byte| *byte != 0) {
+        return Err(RegistryError::InvalidAbiData);
+    }
+
+    Ok(u64::from_be_bytes(
+        low.try_into().map_err(|_| RegistryError::InvalidAbiData)?,
+    ))
+}
+
+fn decode_address(data: &[u8], index: usize) -> Result<String, RegistryError> {
+    let word = word(data, index)?;
+
+    Ok(format!("0x{}", hex::encode(&word[WORD_SIZE - 20..])))
+}
+
+fn decode_string(data: &[u8], index: usize) -> Result<String, RegistryError> {
+    let offset =
+        usize::try_from(decode_uint(data, index)?).map_err(|_| RegistryError::InvalidAbiData)?;
+    // The offset and length come from the response, so they may point anywhere
+    let start = offset
+        .checked_add(WORD_SIZE)
+        .ok_or(RegistryError::InvalidAbiData)?;
+    let length_word = data
+        .get(offset..start)
+        .ok_or(RegistryError::InvalidAbiData)?;
+    let length =
+        usize::try_from(decode_uint(length_word, 0)?).map_err(|_| RegistryError::InvalidAbiData)?;
+    let end = start
+        .checked_add(length)
+        .ok_or(RegistryError::InvalidAbiData)?;
+    let bytes = data.get(start..end).ok_or(RegistryError::InvalidAbiData)?;
+
+    String::from_utf8(bytes.to_vec()).map_err(|_| RegistryError::InvalidAbiData)
+}
@@ -0,0 +1,33 @@
+use sha2::{Digest, Sha256};
+
+/// Rank `hosts` for `key` by rendezvous (highest random weight) hashing, the way storage-v2
+/// content nodes place blobs: by the SHA-256 of the host followed by the key, lowest first.
+/// The first `replication_factor` hosts are the ones expected to hold the blob,
+/// and only blobs of hosts that join or leave move.
+#[must_use]
+pub fn rank<'a>(hosts: &[&'a str], key: &str) -> Vec<&'a str> {
+    let mut scored = hosts
+        .iter()
+        .map(|host| {
+            let score: [u8; 32] = Sha256::new()
+                .chain_update(host.as_bytes())
+                .chain_update(key.as_bytes())
+                .finalize()
+                .into();
+
+            (score, *host)
+        })
+        .collect::<Vec<([u8; 32], &str)>>();
+    scored.sort_unstable();
+
+    scored.into_iter().map(|(_, host)| host).collect()
+}
+
+/// The `replication_factor` hosts expected to hold `key`
+#[must_use]
+pub fn placement<'a>(hosts: &[&'a str], key: &str, replication_factor: usize) -> Vec<&'a str> {
+    let mut ranked = rank(hosts, key);
+    ranked.truncate(replication_factor);
+
+    ranked
+}
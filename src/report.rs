@@ -0,0 +1,375 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use color_eyre::eyre::Result;
+use sqlx::PgPool;
+use thiserror::Error;
+
+use crate::{
+    alerts::post_to_slack,
+    configuration::MetricsSettings,
+    metrics::{
+        get_all_user_count, get_missed_users_count, get_primary_user_count,
+        get_users_status_by_primary,
+    },
+    runs::{ensure_complete, get_latest_run_id, get_previous_run_id},
+};
+
+// Regressed users listed in the report, the rest are only counted
+const MAX_LISTED_USERS: usize = 50;
+
+#[derive(Error, Debug)]
+pub enum ReportError {
+    #[error("there are no complete runs to report on")]
+    NoRuns,
+    #[error("there is no complete run before run {0} to compare it to")]
+    NoPreviousRun(i32),
+}
+
+/// Options of the `report` subcommand
+#[derive(clap::Args, Debug, Default, PartialEq, Eq)]
+pub struct ReportArgs {
+    /// Run to report on, defaults to the latest run
+    pub run_id: Option<i32>,
+    /// Run to compare it to, defaults to the run before `run_id`
+    #[arg(long = "previous", value_name = "RUN_ID")]
+    pub previous_run_id: Option<i32>,
+    /// Write the markdown report to this file instead of stdout
+    #[arg(long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+    /// Also post the report to `metrics.slack_url`
+    #[arg(long)]
+    pub slack: bool,
+    /// Compare runs even if they didn't complete
+    #[arg(long)]
+    pub allow_incomplete: bool,
+}
+
+/// Per-node counts for a single run
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NodeStats {
+    pub endpoint: String,
+    /// Users with the node anywhere in their replica set
+    pub all_users: i64,
+    pub primary_users: i64,
+    /// Sync status of the users the node is primary for
+    pub fully_synced: i64,
+    pub partially_synced: i64,
+    pub unsynced: i64,
+    /// Users in the node's replica sets that it returned no clock value for
+    pub missed_users: i64,
+}
+
+/// A node's counts in the current and previous runs.
+/// `None` when the node wasn't registered in that run.
+#[derive(Debug, Clone)]
+pub struct NodeDelta {
+    pub endpoint: String,
+    pub current: Option<NodeStats>,
+    pub previous: Option<NodeStats>,
+}
+
+/// A user that was fully synced in the previous run and is unsynced in the current one
+#[derive(Debug, Clone)]
+pub struct RegressedUser {
+    pub user_id: i32,
+    pub wallet: Option<String>,
+    pub primary_endpoint: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RunReport {
+    pub run_id: i32,
+    pub previous_run_id: i32,
+    pub nodes: Vec<NodeDelta>,
+    pub regressed_users: Vec<RegressedUser>,
+}
+
+/// Build, print or write, and optionally post the report described by `args`
+///
+/// # Errors
+///
+/// Returns an error if there is nothing to compare, a query fails,
+/// the output file can't be written or the Slack post fails
+pub async fn run(pool: &PgPool, args: ReportArgs, config: &MetricsSettings) -> Result<()> {
+    let report = generate(
+        pool,
+        args.run_id,
+        args.previous_run_id,
+        args.allow_incomplete,
+    )
+    .await?;
+    let markdown = report.to_markdown();
+
+    match &args.output {
+        Some(path) => std::fs::write(path, &markdown)?,
+        None => println!("{markdown}"),
+    }
+
+    if args.slack {
+        post_to_slack(&config.slack_url, &report.to_slack()).await?;
+    }
+
+    Ok(())
+}
+
+/// Compare `run_id` (the latest complete run by default)
+/// to `previous_run_id` (the complete run before it by default).
+/// Incomplete runs are refused unless `allow_incomplete`.
+///
+/// # Errors
+///
+/// Returns an error if either run can't be found, is incomplete, or a query fails
+#[tracing::instrument(skip(pool))]
+pub async fn generate(
+    pool: &PgPool,
+    run_id: Option<i32>,
+    previous_run_id: Option<i32>,
+    allow_incomplete: bool,
+) -> Result<RunReport> {
+    let run_id = match run_id {
+        Some(run_id) => run_id,
+        None => get_latest_run_id(pool, allow_incomplete)
+            .await?
+            .ok_or(ReportError::NoRuns)?,
+    };
+    let previous_run_id = match previous_run_id {
+        Some(previous_run_id) => previous_run_id,
+        None => get_previous_run_id(pool, run_id, allow_incomplete)
+            .await?
+            .ok_or(ReportError::NoPreviousRun(run_id))?,
+    };
+
+    if !allow_incomplete {
+        ensure_complete(pool, run_id).await?;
+        ensure_complete(pool, previous_run_id).await?;
+    }
+
+    let current = get_node_stats(pool, run_id).await?;
+    let previous = get_node_stats(pool, previous_run_id).await?;
+    let regressed_users = get_regressed_users(pool, run_id, previous_run_id).await?;
+
+    Ok(RunReport {
+        run_id,
+        previous_run_id,
+        nodes: compare(&current, &previous),
+        regressed_users,
+    })
+}
+
+/// Pair up the stats of each node by endpoint, ordered by endpoint
+#[must_use]
+pub fn compare(current: &[NodeStats], previous: &[NodeStats]) -> Vec<NodeDelta> {
+    let mut nodes = BTreeMap::<&str, NodeDelta>::new();
+
+    for stats in current {
+        nodes
+            .entry(&stats.endpoint)
+            .or_insert_with(|| empty_delta(&stats.endpoint))
+            .current = Some(stats.clone());
+    }
+
+    for stats in previous {
+        nodes
+            .entry(&stats.endpoint)
+            .or_insert_with(|| empty_delta(&stats.endpoint))
+            .previous = Some(stats.clone());
+    }
+
+    nodes.into_values().collect()
+}
+
+fn empty_delta(endpoint: &str) -> NodeDelta {
+    NodeDelta {
+        endpoint: endpoint.to_owned(),
+        current: None,
+        previous: None,
+    }
+}
+
+impl RunReport {
+    #[must_use]
+    pub fn to_markdown(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "## Run {} compared to run {}",
+                self.run_id, self.previous_run_id
+            ),
+            String::new(),
+        ];
+        lines.extend(self.node_table());
+
+        lines.push(String::new());
+        lines.push(format!(
+            "### {} users went from fully synced to unsynced",
+            self.regressed_users.len()
+        ));
+
+        if !self.regressed_users.is_empty() {
+            lines.push(String::new());
+            lines.push("| User | Wallet | Primary |".into());
+            lines.push("|---|---|---|".into());
+            lines.extend(
+                self.regressed_users
+                    .iter()
+                    .take(MAX_LISTED_USERS)
+                    .map(|user| {
+                        format!(
+                            "| {} | {} | {} |",
+                            user.user_id,
+                            user.wallet.as_deref().unwrap_or("-"),
+                            user.primary_endpoint.as_deref().unwrap_or("-")
+                        )
+                    }),
+            );
+
+            if self.regressed_users.len() > MAX_LISTED_USERS {
+                lines.push(String::new());
+                lines.push(format!(
+                    "…and {} more",
+                    self.regressed_users.len() - MAX_LISTED_USERS
+                ));
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    /// Slack doesn't render markdown tables, so the node table is sent as a code block
+    #[must_use]
+    pub fn to_slack(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "*Run {} compared to run {}*",
+                self.run_id, self.previous_run_id
+            ),
+            "```".into(),
+        ];
+        lines.extend(self.node_table());
+        lines.push("```".into());
+        lines.push(format!(
+            "{} users went from fully synced to unsynced",
+            self.regressed_users.len()
+        ));
+
+        lines.join("\n")
+    }
+
+    fn node_table(&self) -> Vec<String> {
+        let mut lines = vec![
+            "| Node | Users | Primary users | Fully synced | Partially synced | Unsynced | Missed users |".into(),
+            "|---|---|---|---|---|---|---|".into(),
+        ];
+
+        for node in &self.nodes {
+            let current = node.current.clone().unwrap_or_default();
+            let previous = node.previous.clone().unwrap_or_default();
+            let endpoint = match (&node.current, &node.previous) {
+                (Some(_), None) => format!("{} (new)", node.endpoint),
+                (None, Some(_)) => format!("{} (gone)", node.endpoint),
+                _ => node.endpoint.clone(),
+            };
+
+            lines.push(format!(
+                "| {endpoint} | {} | {} | {} | {} | {} | {} |",
+                delta(current.all_users, previous.all_users),
+                delta(current.primary_users, previous.primary_users),
+                delta(current.fully_synced, previous.fully_synced),
+                delta(current.partially_synced, previous.partially_synced),
+                delta(current.unsynced, previous.unsynced),
+                delta(current.missed_users, previous.missed_users),
+            ));
+        }
+
+        lines
+    }
+}
+
+fn delta(current: i64, previous: i64) -> String {
+    match current - previous {
+        0 => current.to_string(),
+        change => format!("{current} ({change:+})"),
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_node_stats(pool: &PgPool, run_id: i32) -> Result<Vec<NodeStats>> {
+    let mut nodes = BTreeMap::<String, NodeStats>::new();
+
+    for cnode in get_all_user_count(pool, run_id).await? {
+        node_stats(&mut nodes, &cnode.endpoint).all_users = cnode.count;
+    }
+
+    for cnode in get_primary_user_count(pool, run_id).await? {
+        node_stats(&mut nodes, &cnode.endpoint).primary_users = cnode.count;
+    }
+
+    for status in get_users_status_by_primary(pool, run_id).await? {
+        let stats = node_stats(&mut nodes, &status.endpoint);
+        stats.fully_synced = status.fully_synced_count;
+        stats.partially_synced = status.partially_synced_count;
+        stats.unsynced = status.unsynced_count;
+    }
+
+    for cnode in get_missed_users_count(pool, run_id).await? {
+        node_stats(&mut nodes, &cnode.endpoint).missed_users = cnode.count;
+    }
+
+    Ok(nodes.into_values().collect())
+}
+
+fn node_stats<'a>(nodes: &'a mut BTreeMap<String, NodeStats>, endpoint: &str) -> &'a mut NodeStats {
+    nodes
+        .entry(endpoint.to_owned())
+        .or_insert_with(|| NodeStats {
+            endpoint: endpoint.to_owned(),
+            ..NodeStats::default()
+        })
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_regressed_users(
+    pool: &PgPool,
+    run_id: i32,
+    previous_run_id: i32,
+) -> Result<Vec<RegressedUser>> {
+    let regressed_users = sqlx::query_as!(
+        RegressedUser,
+        r#"
+        SELECT
+            current_users.user_id AS "user_id!",
+            current_users.wallet AS "wallet?",
+            cnodes.endpoint AS "primary_endpoint?"
+        FROM network_monitoring_users AS previous_users
+        JOIN network_monitoring_users AS current_users
+        ON current_users.user_id = previous_users.user_id
+        LEFT JOIN network_monitoring_content_nodes AS cnodes
+        ON
+            cnodes.run_id = current_users.run_id
+        AND
+            cnodes.spid = current_users.primaryspid
+        WHERE
+            previous_users.run_id = $2
+        AND
+            previous_users.primary_clock_value != -2
+        AND
+            previous_users.primary_clock_value = previous_users.secondary1_clock_value
+        AND
+            previous_users.secondary1_clock_value = previous_users.secondary2_clock_value
+        AND
+            current_users.run_id = $1
+        AND
+            current_users.primary_clock_value != -2
+        AND
+            current_users.primary_clock_value != current_users.secondary1_clock_value
+        AND
+            current_users.primary_clock_value != current_users.secondary2_clock_value
+        ORDER BY current_users.user_id;
+        "#,
+        run_id,
+        previous_run_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(regressed_users)
+}
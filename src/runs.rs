@@ -0,0 +1,598 @@
+use std::fmt;
+
+use color_eyre::eyre::Result;
+use sqlx::PgPool;
+use thiserror::Error;
+
+use crate::configuration::ContentProtocol;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Discovery,
+    Content,
+    Metrics,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            Stage::Discovery => "discovery",
+            Stage::Content => "content",
+            Stage::Metrics => "metrics",
+        };
+
+        f.write_str(stage)
+    }
+}
+
+/// Value of the `<stage>_status` columns of `network_monitoring_index_blocks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl StageStatus {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StageStatus::Running => "running",
+            StageStatus::Succeeded => "succeeded",
+            StageStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("run {0} does not exist")]
+    NotFound(i32),
+    #[error("run {0} is incomplete, its stages didn't all succeed")]
+    Incomplete(i32),
+    #[error("run {0} hasn't finished indexing, the {1} stage didn't succeed")]
+    NotIndexed(i32, Stage),
+    #[error("run {0} is already complete")]
+    AlreadyComplete(i32),
+}
+
+/// Mark the stages left running by a run that was killed as failed.
+/// Must only be called while holding the run lock.
+///
+/// # Errors
+///
+/// Returns an error if the runs can't be updated
+#[tracing::instrument(skip(pool))]
+pub async fn fail_interrupted_stages(pool: &PgPool) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_index_blocks
+        SET
+            discovery_status = CASE WHEN discovery_status = 'running' THEN 'failed' ELSE discovery_status END,
+            content_status = CASE WHEN content_status = 'running' THEN 'failed' ELSE content_status END,
+            metrics_status = CASE WHEN metrics_status = 'running' THEN 'failed' ELSE metrics_status END
+        WHERE
+            discovery_status = 'running'
+        OR
+            content_status = 'running'
+        OR
+            metrics_status = 'running';
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Start a new run at the current discovery block
+///
+/// # Errors
+///
+/// Returns an error if the latest block can't be read from discovery or the run can't be inserted
+#[tracing::instrument(skip(pool))]
+pub async fn create_run(pool: &PgPool) -> Result<i32> {
+    // get latest block number
+    let latest_block_number = sqlx::query!(
+        r#"
+        SELECT number FROM discovery.blocks WHERE is_current = TRUE LIMIT 1;
+        "#,
+    )
+    .fetch_one(pool)
+    .await?
+    .number;
+
+    // create new run in DB
+    let run_id = sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_index_blocks (
+            is_current,
+            blocknumber,
+            is_complete,
+            created_at
+        ) VALUES (
+            TRUE,
+            $1,
+            FALSE,
+            NOW()
+        )
+        RETURNING run_id;
+    "#,
+        latest_block_number,
+    )
+    .fetch_one(pool)
+    .await?
+    .run_id;
+
+    // remove is_current from latest run
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_index_blocks
+        SET is_current = FALSE
+        WHERE blocknumber != $1;
+    "#,
+        latest_block_number,
+    )
+    .execute(pool)
+    .await?;
+
+    // return new run id
+    Ok(run_id)
+}
+
+/// Record that `stage` of `run_id` started
+///
+/// # Errors
+///
+/// Returns an error if the run can't be updated
+#[tracing::instrument(skip(pool))]
+pub async fn stage_started(pool: &PgPool, run_id: i32, stage: Stage) -> Result<()> {
+    let status = StageStatus::Running.as_str();
+
+    let query = match stage {
+        Stage::Discovery => sqlx::query!(
+            r#"
+            UPDATE network_monitoring_index_blocks
+            SET discovery_status = $2, discovery_started_at = NOW(), discovery_finished_at = NULL, discovery_error = NULL
+            WHERE run_id = $1;
+            "#,
+            run_id,
+            status,
+        ),
+        Stage::Content => sqlx::query!(
+            r#"
+            UPDATE network_monitoring_index_blocks
+            SET content_status = $2, content_started_at = NOW(), content_finished_at = NULL, content_error = NULL
+            WHERE run_id = $1;
+            "#,
+            run_id,
+            status,
+        ),
+        Stage::Metrics => sqlx::query!(
+            r#"
+            UPDATE network_monitoring_index_blocks
+            SET metrics_status = $2, metrics_started_at = NOW(), metrics_finished_at = NULL, metrics_error = NULL
+            WHERE run_id = $1;
+            "#,
+            run_id,
+            status,
+        ),
+    };
+
+    query.execute(pool).await?;
+
+    Ok(())
+}
+
+/// Record how `stage` of `run_id` finished, with the error text if it failed
+///
+/// # Errors
+///
+/// Returns an error if the run can't be updated
+#[tracing::instrument(skip(pool, error))]
+pub async fn stage_finished(
+    pool: &PgPool,
+    run_id: i32,
+    stage: Stage,
+    error: Option<String>,
+) -> Result<()> {
+    let status = match error {
+        None => StageStatus::Succeeded,
+        Some(_) => StageStatus::Failed,
+    }
+    .as_str();
+
+    let query = match stage {
+        Stage::Discovery => sqlx::query!(
+            r#"
+            UPDATE network_monitoring_index_blocks
+            SET discovery_status = $2, discovery_finished_at = NOW(), discovery_error = $3
+            WHERE run_id = $1;
+            "#,
+            run_id,
+            status,
+            error,
+        ),
+        Stage::Content => sqlx::query!(
+            r#"
+            UPDATE network_monitoring_index_blocks
+            SET content_status = $2, content_finished_at = NOW(), content_error = $3
+            WHERE run_id = $1;
+            "#,
+            run_id,
+            status,
+            error,
+        ),
+        Stage::Metrics => sqlx::query!(
+            r#"
+            UPDATE network_monitoring_index_blocks
+            SET metrics_status = $2, metrics_finished_at = NOW(), metrics_error = $3
+            WHERE run_id = $1;
+            "#,
+            run_id,
+            status,
+            error,
+        ),
+    };
+
+    query.execute(pool).await?;
+
+    Ok(())
+}
+
+/// Mark `run_id` complete once every stage succeeded
+///
+/// # Errors
+///
+/// Returns an error if the run can't be updated
+#[tracing::instrument(skip(pool))]
+pub async fn mark_complete(pool: &PgPool, run_id: i32) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_index_blocks
+        SET is_complete = TRUE
+        WHERE
+            run_id = $1
+        AND
+            discovery_status = 'succeeded'
+        AND
+            content_status = 'succeeded'
+        AND
+            metrics_status = 'succeeded';
+        "#,
+        run_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Fail unless `run_id` can be resumed: discovery was imported but the run didn't complete
+///
+/// # Errors
+///
+/// Returns an error if the run doesn't exist, is already complete, or discovery didn't succeed
+#[tracing::instrument(skip(pool))]
+pub async fn ensure_resumable(pool: &PgPool, run_id: i32) -> Result<()> {
+    let run = sqlx::query!(
+        r#"
+        SELECT is_complete, discovery_status
+        FROM network_monitoring_index_blocks
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(RunError::NotFound(run_id))?;
+
+    if run.is_complete.unwrap_or(false) {
+        return Err(RunError::AlreadyComplete(run_id).into());
+    }
+    if run.discovery_status.as_deref() != Some(StageStatus::Succeeded.as_str()) {
+        return Err(RunError::NotIndexed(run_id, Stage::Discovery).into());
+    }
+
+    Ok(())
+}
+
+/// Fail unless `run_id` is complete
+///
+/// # Errors
+///
+/// Returns an error if the run doesn't exist or isn't complete
+#[tracing::instrument(skip(pool))]
+pub async fn ensure_complete(pool: &PgPool, run_id: i32) -> Result<()> {
+    let is_complete = sqlx::query!(
+        r#"
+        SELECT is_complete
+        FROM network_monitoring_index_blocks
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(RunError::NotFound(run_id))?
+    .is_complete
+    .unwrap_or(false);
+
+    if !is_complete {
+        return Err(RunError::Incomplete(run_id).into());
+    }
+
+    Ok(())
+}
+
+/// Fail unless the discovery and content stages of `run_id` succeeded,
+/// which is all metrics need, even before the run is complete
+///
+/// # Errors
+///
+/// Returns an error if the run doesn't exist or a stage before metrics didn't succeed
+#[tracing::instrument(skip(pool))]
+pub async fn ensure_indexed(pool: &PgPool, run_id: i32) -> Result<()> {
+    let run = sqlx::query!(
+        r#"
+        SELECT discovery_status, content_status
+        FROM network_monitoring_index_blocks
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(RunError::NotFound(run_id))?;
+
+    let succeeded = Some(StageStatus::Succeeded.as_str());
+    if run.discovery_status.as_deref() != succeeded {
+        return Err(RunError::NotIndexed(run_id, Stage::Discovery).into());
+    }
+    if run.content_status.as_deref() != succeeded {
+        return Err(RunError::NotIndexed(run_id, Stage::Content).into());
+    }
+
+    Ok(())
+}
+
+/// The latest run, only considering complete runs unless `include_incomplete`.
+/// Scoped runs are left out, they only checked some content nodes or users.
+///
+/// # Errors
+///
+/// Returns an error if the query fails
+#[tracing::instrument(skip(pool))]
+pub async fn get_latest_run_id(pool: &PgPool, include_incomplete: bool) -> Result<Option<i32>> {
+    let latest_run_id = sqlx::query!(
+        r#"
+        SELECT MAX(run_id) AS run_id
+        FROM network_monitoring_index_blocks
+        WHERE
+            (is_complete = TRUE OR $1)
+        AND
+            NOT is_scoped;
+        "#,
+        include_incomplete,
+    )
+    .fetch_one(pool)
+    .await?
+    .run_id;
+
+    Ok(latest_run_id)
+}
+
+/// The latest complete run of each kind `run_id` doesn't replace,
+/// sampled or full, or both when `run_id` is scoped.
+/// Scoped runs are left out, they only checked some content nodes or users.
+///
+/// # Errors
+///
+/// Returns an error if the query fails
+#[tracing::instrument(skip(pool))]
+pub async fn get_latest_runs_of_other_kinds(pool: &PgPool, run_id: i32) -> Result<Vec<i32>> {
+    let run_ids = sqlx::query!(
+        r#"
+        SELECT MAX(runs.run_id) AS "run_id!"
+        FROM network_monitoring_index_blocks AS runs
+        JOIN network_monitoring_index_blocks AS current
+        ON current.run_id = $1
+        WHERE
+            runs.is_complete = TRUE
+        AND
+            NOT runs.is_scoped
+        AND
+            (current.is_scoped OR runs.is_sampled != current.is_sampled)
+        GROUP BY runs.is_sampled;
+        "#,
+        run_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.run_id)
+    .collect::<Vec<i32>>();
+
+    Ok(run_ids)
+}
+
+/// The run before `run_id`, only considering complete runs unless `include_incomplete`.
+/// Sampled runs are only compared with sampled runs, and full runs with full runs.
+/// Scoped runs are left out, they only checked some content nodes or users.
+///
+/// # Errors
+///
+/// Returns an error if the query fails
+#[tracing::instrument(skip(pool))]
+pub async fn get_previous_run_id(
+    pool: &PgPool,
+    run_id: i32,
+    include_incomplete: bool,
+) -> Result<Option<i32>> {
+    let previous_run_id = sqlx::query!(
+        r#"
+        SELECT MAX(run_id) AS run_id
+        FROM network_monitoring_index_blocks
+        WHERE
+            run_id < $1
+        AND
+            (is_complete = TRUE OR $2)
+        AND
+            NOT is_scoped
+        AND
+            is_sampled = (
+                SELECT is_sampled
+                FROM network_monitoring_index_blocks
+                WHERE run_id = $1
+            );
+        "#,
+        run_id,
+        include_incomplete,
+    )
+    .fetch_one(pool)
+    .await?
+    .run_id;
+
+    Ok(previous_run_id)
+}
+
+/// Record the protocol the content nodes of `run_id` are checked with
+///
+/// # Errors
+///
+/// Returns an error if the run can't be updated
+#[tracing::instrument(skip(pool))]
+pub async fn set_content_protocol(
+    pool: &PgPool,
+    run_id: i32,
+    protocol: ContentProtocol,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_index_blocks
+        SET content_protocol = $2
+        WHERE run_id = $1;
+        "#,
+        run_id,
+        protocol.as_str(),
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// The protocol the content nodes of `run_id` were checked with.
+/// Runs from before it was recorded were all checked with the legacy protocol.
+///
+/// # Errors
+///
+/// Returns an error if the run doesn't exist
+#[tracing::instrument(skip(pool))]
+pub async fn get_content_protocol(pool: &PgPool, run_id: i32) -> Result<ContentProtocol> {
+    let protocol = sqlx::query!(
+        r#"
+        SELECT content_protocol
+        FROM network_monitoring_index_blocks
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(RunError::NotFound(run_id))?
+    .content_protocol;
+
+    Ok(protocol
+        .as_deref()
+        .and_then(ContentProtocol::parse)
+        .unwrap_or_default())
+}
+
+/// Record that `run_id` only checks a sample of the users of each primary
+///
+/// # Errors
+///
+/// Returns an error if the run can't be updated
+#[tracing::instrument(skip(pool))]
+pub async fn mark_sampled(pool: &PgPool, run_id: i32) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_index_blocks
+        SET is_sampled = TRUE
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Whether `run_id` only checked a sample of the users of each primary
+///
+/// # Errors
+///
+/// Returns an error if the run doesn't exist
+#[tracing::instrument(skip(pool))]
+pub async fn is_sampled(pool: &PgPool, run_id: i32) -> Result<bool> {
+    let is_sampled = sqlx::query!(
+        r#"
+        SELECT is_sampled
+        FROM network_monitoring_index_blocks
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(RunError::NotFound(run_id))?
+    .is_sampled;
+
+    Ok(is_sampled)
+}
+
+/// Record that `run_id` only checks some of the content nodes or users
+///
+/// # Errors
+///
+/// Returns an error if the run can't be updated
+#[tracing::instrument(skip(pool))]
+pub async fn mark_scoped(pool: &PgPool, run_id: i32) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_index_blocks
+        SET is_scoped = TRUE
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Whether `run_id` only checked some of the content nodes or users
+///
+/// # Errors
+///
+/// Returns an error if the run doesn't exist
+#[tracing::instrument(skip(pool))]
+pub async fn is_scoped(pool: &PgPool, run_id: i32) -> Result<bool> {
+    let is_scoped = sqlx::query!(
+        r#"
+        SELECT is_scoped
+        FROM network_monitoring_index_blocks
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(RunError::NotFound(run_id))?
+    .is_scoped;
+
+    Ok(is_scoped)
+}
@@ -0,0 +1,421 @@
+use std::{
+    future::Future,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Result;
+use cron::Schedule;
+use sqlx::{ConnectOptions, PgConnection, PgPool};
+use thiserror::Error;
+use tokio::{
+    pin, select,
+    signal::unix::{signal, SignalKind},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    configuration::{SchedulerSettings, Settings},
+    content,
+    db::create_foreign_connection,
+    discovery, metrics,
+    prometheus::{
+        reset_request_metrics, INDEXING_CONTENT_DURATION_GAUGE, INDEXING_DISCOVERY_DURATION_GAUGE,
+        SKIPPED_RUNS_COUNTER,
+    },
+    runs::{self, Stage},
+    server::HealthState,
+};
+
+// Arbitrary key for the postgres advisory lock held for the duration of a run
+const RUN_LOCK_KEY: i64 = 0x006e_6d72_756e;
+
+#[derive(Error, Debug)]
+pub enum SchedulerError {
+    #[error("the {0} stage timed out after {1:?}")]
+    StageTimedOut(Stage, Duration),
+    #[error("another run is already in progress")]
+    RunInProgress,
+    #[error("either `scheduler.interval_seconds` or `scheduler.cron` must be set")]
+    NoSchedule,
+    #[error("`scheduler.interval_seconds` must be greater than zero")]
+    ZeroInterval,
+    #[error("invalid cron expression `{0}`: {1}")]
+    InvalidCron(String, String),
+}
+
+enum RunSchedule {
+    Interval(Duration),
+    Cron(Box<Schedule>),
+}
+
+impl RunSchedule {
+    fn from_settings(config: &SchedulerSettings) -> Result<Self, SchedulerError> {
+        if let Some(expression) = &config.cron {
+            let schedule = Schedule::from_str(expression)
+                .map_err(|e| SchedulerError::InvalidCron(expression.clone(), e.to_string()))?;
+            return Ok(Self::Cron(Box::new(schedule)));
+        }
+
+        match config.interval_seconds {
+            Some(0) => Err(SchedulerError::ZeroInterval),
+            Some(seconds) => Ok(Self::Interval(Duration::from_secs(seconds))),
+            None => Err(SchedulerError::NoSchedule),
+        }
+    }
+
+    /// The next time a run should start, skipping any that were missed
+    /// because the previous run was still in progress
+    fn next_after(&self, last_start: Option<DateTime<Utc>>, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Interval(interval) => {
+                let Some(mut next) = last_start else {
+                    return now;
+                };
+                let interval = chrono::Duration::from_std(*interval)
+                    .unwrap_or_else(|_| chrono::Duration::days(365));
+
+                while next <= now {
+                    next += interval;
+                }
+
+                next
+            }
+            Self::Cron(schedule) => schedule.after(&now).next().unwrap_or(now),
+        }
+    }
+}
+
+/// Run every stage once: index discovery, index the content nodes, then generate metrics
+///
+/// # Errors
+///
+/// Returns an error if another run holds the run lock, a stage fails,
+/// or a stage takes longer than its configured timeout
+pub async fn run_job(pool: &PgPool, config: &Settings) -> Result<i32> {
+    run(pool, config, None).await
+}
+
+/// Finish an interrupted run without importing discovery again.
+/// Only the batches the content stage hadn't saved yet are checked.
+///
+/// # Errors
+///
+/// Returns an error if another run holds the run lock, the run can't be resumed,
+/// a stage fails, or a stage takes longer than its configured timeout
+pub async fn resume_job(pool: &PgPool, config: &Settings, run_id: i32) -> Result<i32> {
+    run(pool, config, Some(run_id)).await
+}
+
+/// Create a run and only import discovery into it, returning its id
+///
+/// # Errors
+///
+/// Returns an error if another run holds the run lock, or the discovery stage fails or times out
+pub async fn discover(pool: &PgPool, config: &Settings) -> Result<i32> {
+    let lock_connection = lock_run(pool, config).await?;
+    let run_id = discovery_stage(pool, config).await?;
+    unlock_run(lock_connection).await?;
+
+    Ok(run_id)
+}
+
+/// Check the content nodes of a run whose discovery was imported.
+/// Only the batches an earlier attempt didn't save yet are checked.
+///
+/// # Errors
+///
+/// Returns an error if another run holds the run lock, the run is complete or wasn't discovered,
+/// or the content stage fails or times out
+pub async fn check_content(pool: &PgPool, config: &Settings, run_id: i32) -> Result<()> {
+    let lock_connection = lock_run(pool, config).await?;
+    runs::ensure_resumable(pool, run_id).await?;
+    content_stage(pool, config, run_id).await?;
+    unlock_run(lock_connection).await?;
+
+    Ok(())
+}
+
+/// Generate the metrics of a run again, completing the run if every stage has now succeeded.
+/// Runs whose discovery or content stage didn't succeed are refused unless `allow_incomplete`.
+///
+/// # Errors
+///
+/// Returns an error if another run holds the run lock, the run isn't indexed,
+/// or the metrics stage fails or times out
+pub async fn generate_metrics(
+    pool: &PgPool,
+    config: &Settings,
+    run_id: i32,
+    allow_incomplete: bool,
+) -> Result<()> {
+    let lock_connection = lock_run(pool, config).await?;
+    metrics_stage(pool, config, run_id, allow_incomplete).await?;
+    runs::mark_complete(pool, run_id).await?;
+    unlock_run(lock_connection).await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool, config))]
+async fn run(pool: &PgPool, config: &Settings, resume_run_id: Option<i32>) -> Result<i32> {
+    let lock_connection = lock_run(pool, config).await?;
+
+    let run_id = if let Some(run_id) = resume_run_id {
+        runs::ensure_resumable(pool, run_id).await?;
+        tracing::info!("resuming run {run_id}");
+
+        run_id
+    } else {
+        discovery_stage(pool, config).await?
+    };
+
+    content_stage(pool, config, run_id).await?;
+
+    if config.scope.dry_run {
+        tracing::info!("dry run {run_id} only planned its requests, skipping metrics");
+        unlock_run(lock_connection).await?;
+
+        return Ok(run_id);
+    }
+
+    metrics_stage(pool, config, run_id, false).await?;
+
+    runs::mark_complete(pool, run_id).await?;
+
+    unlock_run(lock_connection).await?;
+
+    Ok(run_id)
+}
+
+/// Take the run lock and clean up after runs that were killed.
+/// Dropping the returned connection releases the lock,
+/// even if the caller's future is dropped before the run finishes.
+async fn lock_run(pool: &PgPool, config: &Settings) -> Result<PgConnection> {
+    let mut lock_connection = config.database.with_db().connect().await?;
+    if !try_lock_run(&mut lock_connection).await? {
+        return Err(SchedulerError::RunInProgress.into());
+    }
+
+    runs::fail_interrupted_stages(pool).await?;
+
+    // The series of earlier runs are only dropped once this run's metrics replace them
+    reset_request_metrics();
+
+    Ok(lock_connection)
+}
+
+async fn unlock_run(mut lock_connection: PgConnection) -> Result<()> {
+    sqlx::query!(
+        r#"
+        SELECT pg_advisory_unlock($1);
+        "#,
+        RUN_LOCK_KEY,
+    )
+    .fetch_one(&mut lock_connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Create a new run in table `network_monitoring_index_blocks`
+/// and index data from the discovery node postgres DB
+/// into the separate network monitoring postgres DB.
+/// The foreign tables are imported again first, under the run lock,
+/// so a daemon picks up changes to the discovery schema or connection.
+async fn discovery_stage(pool: &PgPool, config: &Settings) -> Result<i32> {
+    create_foreign_connection(pool, &config.foreign_database).await?;
+
+    let run_id = runs::create_run(pool).await?;
+
+    run_stage(
+        pool,
+        run_id,
+        Stage::Discovery,
+        config.scheduler.stage_timeouts.discovery_seconds,
+        discovery::index(pool, run_id, config.discovery.clone(), config.scope.clone()),
+    )
+    .await?;
+
+    Ok(run_id)
+}
+
+/// Fetch data (CIDs and Users) from content nodes
+/// and save it into the network monitoring postgres DB
+async fn content_stage(pool: &PgPool, config: &Settings, run_id: i32) -> Result<()> {
+    run_stage(
+        pool,
+        run_id,
+        Stage::Content,
+        config.scheduler.stage_timeouts.content_seconds,
+        content::index(pool, run_id, config.content.clone(), config.scope.clone()),
+    )
+    .await
+}
+
+/// Run OLAP-type queries on the network monitoring DB
+/// and export the data to the prometheus push-gateway
+/// to be later scraped by prometheus
+async fn metrics_stage(
+    pool: &PgPool,
+    config: &Settings,
+    run_id: i32,
+    allow_incomplete: bool,
+) -> Result<()> {
+    run_stage(
+        pool,
+        run_id,
+        Stage::Metrics,
+        config.scheduler.stage_timeouts.metrics_seconds,
+        metrics::generate(
+            pool,
+            run_id,
+            config.metrics.clone(),
+            &config.content.deregistered_nodes,
+            allow_incomplete,
+        ),
+    )
+    .await
+}
+
+/// Run the job on the configured schedule until SIGTERM or SIGINT is received.
+/// A run in progress when the signal arrives gets `shutdown_timeout_seconds`
+/// to finish before it is aborted.
+/// The outcome of each run is recorded in `health` for the readiness endpoint.
+///
+/// # Errors
+///
+/// Returns an error if the schedule is misconfigured or the signal handlers can't be installed
+pub async fn run_daemon(pool: &PgPool, config: Settings, health: HealthState) -> Result<()> {
+    let schedule = RunSchedule::from_settings(&config.scheduler)?;
+    let shutdown_timeout = Duration::from_secs(config.scheduler.shutdown_timeout_seconds);
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    let mut last_start = None;
+    loop {
+        let next_start = schedule.next_after(last_start, Utc::now());
+        let wait = (next_start - Utc::now()).to_std().unwrap_or_default();
+        tracing::info!("next run starts at {next_start}");
+
+        select! {
+            () = sleep(wait) => (),
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+        }
+
+        last_start = Some(Utc::now());
+        health.run_started();
+
+        let run = run_job(pool, &config);
+        pin!(run);
+
+        let result = select! {
+            result = &mut run => result,
+            _ = sigterm.recv() => {
+                finish_or_abort(run, shutdown_timeout).await;
+                break;
+            }
+            _ = sigint.recv() => {
+                finish_or_abort(run, shutdown_timeout).await;
+                break;
+            }
+        };
+
+        match result {
+            Ok(run_id) => {
+                health.run_finished(&result);
+                tracing::info!("run {run_id} finished");
+            }
+            Err(e) if matches!(e.downcast_ref(), Some(SchedulerError::RunInProgress)) => {
+                health.run_skipped();
+                // Sampled and full runs share the run lock, a long full run skips the sampled ones
+                let kind = if config.scope.sample_users_per_primary.is_some() {
+                    "sampled"
+                } else {
+                    "full"
+                };
+                SKIPPED_RUNS_COUNTER.with_label_values(&[kind]).inc();
+                tracing::warn!("skipping scheduled {kind} run because another run is in progress");
+            }
+            Err(e) => {
+                tracing::error!("run failed {:?}", e);
+                health.run_finished(&Err(e));
+            }
+        }
+    }
+
+    tracing::info!("shutting down");
+
+    Ok(())
+}
+
+async fn finish_or_abort<F>(run: F, shutdown_timeout: Duration)
+where
+    F: Future<Output = Result<i32>>,
+{
+    tracing::info!("shutdown requested, waiting up to {shutdown_timeout:?} for the current run");
+
+    match timeout(shutdown_timeout, run).await {
+        Ok(Ok(run_id)) => tracing::info!("run {run_id} finished before shutdown"),
+        Ok(Err(e)) => tracing::error!("run failed during shutdown {:?}", e),
+        Err(_) => tracing::warn!("aborted the current run"),
+    }
+}
+
+/// Run a stage with its timeout, recording its status on the run and how long it took
+async fn run_stage<F>(
+    pool: &PgPool,
+    run_id: i32,
+    stage: Stage,
+    seconds: u64,
+    future: F,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    runs::stage_started(pool, run_id, stage).await?;
+
+    let started = Instant::now();
+    let duration = Duration::from_secs(seconds);
+    let result = timeout(duration, future)
+        .await
+        .unwrap_or_else(|_| Err(SchedulerError::StageTimedOut(stage, duration).into()));
+
+    // The metrics stage sets its own duration, it has to be set before the metrics are pushed
+    let duration_gauge = match stage {
+        Stage::Discovery => Some(&*INDEXING_DISCOVERY_DURATION_GAUGE),
+        Stage::Content => Some(&*INDEXING_CONTENT_DURATION_GAUGE),
+        Stage::Metrics => None,
+    };
+    if let Some(gauge) = duration_gauge {
+        gauge
+            .with_label_values(&[&run_id.to_string()])
+            .set(i64::try_from(started.elapsed().as_secs()).unwrap_or(i64::MAX));
+    }
+
+    let error = result.as_ref().err().map(|e| format!("{e:#}"));
+    if let Err(e) = runs::stage_finished(pool, run_id, stage, error).await {
+        tracing::error!("failed to record the end of the {stage} stage {:?}", e);
+    }
+
+    result
+}
+
+#[tracing::instrument(skip(connection))]
+async fn try_lock_run(connection: &mut PgConnection) -> Result<bool> {
+    let locked = sqlx::query!(
+        r#"
+        SELECT pg_try_advisory_lock($1) AS locked;
+        "#,
+        RUN_LOCK_KEY,
+    )
+    .fetch_one(connection)
+    .await?
+    .locked
+    .unwrap_or(false);
+
+    Ok(locked)
+}
@@ -0,0 +1,182 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Result;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::configuration::ServerSettings;
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("`{0}` does not resolve to a socket address")]
+    InvalidAddress(String),
+}
+
+/// What the daemon knows about its most recent run
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RunState {
+    pub running: bool,
+    pub last_run_id: Option<i32>,
+    pub last_started_at: Option<DateTime<Utc>>,
+    pub last_finished_at: Option<DateTime<Utc>>,
+    pub last_succeeded: Option<bool>,
+    pub last_error: Option<String>,
+}
+
+/// Run state shared between the scheduler and the health endpoints
+#[derive(Debug, Default, Clone)]
+pub struct HealthState(Arc<RwLock<RunState>>);
+
+impl HealthState {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the lock was poisoned
+    #[must_use]
+    pub fn snapshot(&self) -> RunState {
+        self.0.read().expect("health state lock poisoned").clone()
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the lock was poisoned
+    pub fn run_started(&self) {
+        let mut state = self.0.write().expect("health state lock poisoned");
+        state.running = true;
+        state.last_started_at = Some(Utc::now());
+    }
+
+    /// Undo `run_started` for a run that never started because another one holds the run lock
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock was poisoned
+    pub fn run_skipped(&self) {
+        let mut state = self.0.write().expect("health state lock poisoned");
+        state.running = false;
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the lock was poisoned
+    pub fn run_finished(&self, result: &Result<i32>) {
+        let mut state = self.0.write().expect("health state lock poisoned");
+        state.running = false;
+        state.last_finished_at = Some(Utc::now());
+
+        match result {
+            Ok(run_id) => {
+                state.last_run_id = Some(*run_id);
+                state.last_succeeded = Some(true);
+                state.last_error = None;
+            }
+            Err(e) => {
+                state.last_succeeded = Some(false);
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Ready when the latest finished run succeeded
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.snapshot().last_succeeded == Some(true)
+    }
+}
+
+/// Bind the HTTP server serving `/metrics`, `/healthz` and `/readyz`.
+/// Returns the bound address, so port 0 can be used to pick any free port,
+/// and the future that serves requests until it is dropped.
+///
+/// # Errors
+///
+/// Returns an error if the address can't be resolved or bound
+pub fn bind(
+    config: &ServerSettings,
+    health: HealthState,
+) -> Result<(SocketAddr, impl Future<Output = hyper::Result<()>>)> {
+    let address = format!("{}:{}", config.host, config.port);
+    let socket_address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or(ServerError::InvalidAddress(address))?;
+
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let health = health.clone();
+                async move { Ok::<_, Infallible>(route(&request, &health)) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&socket_address)?.serve(make_service);
+    let local_address = server.local_addr();
+    tracing::info!("serving metrics on http://{local_address}/metrics");
+
+    Ok((local_address, server))
+}
+
+fn route(request: &Request<Body>, health: &HealthState) -> Response<Body> {
+    if request.method() != Method::GET {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    match request.uri().path() {
+        "/metrics" => metrics(),
+        "/healthz" => run_state(StatusCode::OK, &health.snapshot()),
+        "/readyz" if health.is_ready() => run_state(StatusCode::OK, &health.snapshot()),
+        "/readyz" => run_state(StatusCode::SERVICE_UNAVAILABLE, &health.snapshot()),
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn metrics() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("failed to encode metrics {:?}", e);
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+fn run_state(code: StatusCode, state: &RunState) -> Response<Body> {
+    let Ok(body) = serde_json::to_vec(state) else {
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
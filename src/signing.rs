@@ -0,0 +1,153 @@
+use std::fmt;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use secrecy::{ExposeSecret, Secret};
+use sha3::{Digest, Keccak256};
+use thiserror::Error;
+
+use crate::utils::{SignatureParams, UnsignedParams};
+
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("`content.delegate_priv_key` is not a valid secp256k1 private key")]
+    InvalidPrivateKey,
+    #[error("signature is not a 65 byte hex encoded secp256k1 signature")]
+    InvalidSignature,
+    #[error("no public key can be recovered from the signature")]
+    RecoveryFailed,
+}
+
+/// Signs requests to content nodes as the service provider `spid`,
+/// with the private key of its delegate owner wallet
+#[derive(Clone)]
+pub struct Signer {
+    spid: u16,
+    key: SigningKey,
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer")
+            .field("spid", &self.spid)
+            .field("address", &self.address())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Signer {
+    /// # Errors
+    ///
+    /// Returns an error if `delegate_priv_key` isn't a hex encoded secp256k1 private key
+    pub fn new(spid: u16, delegate_priv_key: &Secret<String>) -> Result<Self, SigningError> {
+        let key = delegate_priv_key.expose_secret();
+        let bytes = hex::decode(key.trim_start_matches("0x"))
+            .map_err(|_| SigningError::InvalidPrivateKey)?;
+        let key = SigningKey::from_slice(&bytes).map_err(|_| SigningError::InvalidPrivateKey)?;
+
+        Ok(Self { spid, key })
+    }
+
+    /// Ethereum address of the delegate owner wallet, which content nodes check signatures against
+    #[must_use]
+    pub fn address(&self) -> String {
+        address(self.key.verifying_key())
+    }
+
+    /// Sign `{"spID":<spid>,"timestamp":<timestamp>}` the way content nodes verify it:
+    /// the keccak256 hash of the JSON, signed as an Ethereum message
+    #[must_use]
+    pub fn sign_params(&self, timestamp: DateTime<Utc>) -> SignatureParams {
+        let unsigned = UnsignedParams {
+            spid: self.spid,
+            timestamp: timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        };
+
+        SignatureParams {
+            spid: unsigned.spid,
+            signature: self.sign_message(&params_digest(&unsigned)),
+            timestamp: unsigned.timestamp,
+        }
+    }
+
+    /// Sign `message` prefixed as an Ethereum signed message, like `eth_sign` and
+    /// `web3.eth.accounts.sign`, returning the `0x` prefixed r || s || v signature
+    ///
+    /// # Panics
+    ///
+    /// Never, a valid key signs any digest
+    #[must_use]
+    pub fn sign_message(&self, message: &[u8]) -> String {
+        let (signature, recovery_id) = self
+            .key
+            .sign_prehash_recoverable(&hash_message(message))
+            .expect("a valid key signs any digest");
+
+        let mut bytes = signature.to_vec();
+        bytes.push(27 + recovery_id.to_byte());
+
+        format!("0x{}", hex::encode(bytes))
+    }
+}
+
+/// The address that signed `message` with `signature`, as returned by `Signer::sign_message`
+///
+/// # Errors
+///
+/// Returns an error if the signature is malformed or doesn't match any public key
+pub fn recover_address(message: &[u8], signature: &str) -> Result<String, SigningError> {
+    let bytes = hex::decode(signature.trim_start_matches("0x"))
+        .map_err(|_| SigningError::InvalidSignature)?;
+    let [rs @ .., v] = bytes.as_slice() else {
+        return Err(SigningError::InvalidSignature);
+    };
+
+    let signature = Signature::from_slice(rs).map_err(|_| SigningError::InvalidSignature)?;
+    let recovery_id =
+        RecoveryId::from_byte(v.wrapping_sub(27)).ok_or(SigningError::InvalidSignature)?;
+    let key = VerifyingKey::recover_from_prehash(&hash_message(message), &signature, recovery_id)
+        .map_err(|_| SigningError::RecoveryFailed)?;
+
+    Ok(address(&key))
+}
+
+/// The address that signed `params`, as returned by `Signer::sign_params`
+///
+/// # Errors
+///
+/// Returns an error if the signature is malformed or doesn't match any public key
+pub fn recover_params_address(params: &SignatureParams) -> Result<String, SigningError> {
+    let unsigned = UnsignedParams {
+        spid: params.spid,
+        timestamp: params.timestamp.clone(),
+    };
+
+    recover_address(&params_digest(&unsigned), &params.signature)
+}
+
+/// keccak256 of the params serialized as JSON, the message content nodes expect to be signed
+fn params_digest(params: &UnsignedParams) -> [u8; 32] {
+    let data = serde_json::to_string(params).expect("params always serialize");
+
+    keccak256(data.as_bytes())
+}
+
+/// keccak256 of `message` with the `\x19Ethereum Signed Message:\n<length>` prefix
+fn hash_message(message: &[u8]) -> [u8; 32] {
+    let mut prefixed = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+    prefixed.extend_from_slice(message);
+
+    keccak256(&prefixed)
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// Last 20 bytes of the keccak256 hash of the uncompressed public key
+fn address(key: &VerifyingKey) -> String {
+    let point = key.to_encoded_point(false);
+    let hash = keccak256(&point.as_bytes()[1..]);
+
+    format!("0x{}", hex::encode(&hash[12..]))
+}
@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use futures::{stream, StreamExt};
+use sqlx::PgPool;
+
+use crate::{
+    configuration::ContentProtocol,
+    content::{
+        get_content_nodes, save_cids, save_unchecked_cids, ContentChecker, ExpectedCid,
+        RequestPlan, BATCH_SIZE,
+    },
+    domain::{CidExists, ContentNode, NodeHealth},
+    prometheus::CONTENT_NODE_HEALTHY_GAUGE,
+    rendezvous,
+    utils::{ContentNodeClient, BLOB_INFO_ROUTE, HEALTH_CHECK_ROUTE},
+};
+
+/// Blobs of a batch checked at once on a node, the request limits still apply
+const BLOB_CHECK_CONCURRENCY: usize = 16;
+
+/// Checks storage-v2 content nodes, which don't keep users on replica sets
+/// but store each blob on the nodes ranked first for its CID by rendezvous hashing
+pub struct StorageV2Checker {
+    client: ContentNodeClient,
+    replication_factor: usize,
+    deregistered_nodes: Vec<String>,
+}
+
+impl StorageV2Checker {
+    #[must_use]
+    pub fn new(
+        client: ContentNodeClient,
+        replication_factor: usize,
+        deregistered_nodes: Vec<String>,
+    ) -> Self {
+        Self {
+            client,
+            replication_factor,
+            deregistered_nodes,
+        }
+    }
+
+    /// Endpoints of the content nodes blobs can be placed on, every registered node of the run
+    fn hosts<'a>(&self, content_nodes: &'a [ContentNode]) -> Vec<&'a str> {
+        content_nodes
+            .iter()
+            .map(|cnode| cnode.endpoint.as_str())
+            .filter(|host| !self.deregistered_nodes.iter().any(|node| node == host))
+            .collect()
+    }
+
+    /// Ask `endpoint` for every blob of the batch,
+    /// the CIDs whose request failed are returned apart as unchecked
+    async fn blobs_exist(
+        &self,
+        endpoint: &str,
+        expected_cids: &[ExpectedCid],
+    ) -> (Vec<CidExists>, HashSet<String>) {
+        let cids = expected_cids
+            .iter()
+            .map(|expected| expected.cid.clone())
+            .collect::<HashSet<String>>();
+
+        let results = stream::iter(cids)
+            .map(|cid| async move {
+                let exists = self.client.blob_exists(endpoint, &cid).await;
+                (cid, exists)
+            })
+            .buffer_unordered(BLOB_CHECK_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut cids_exist = Vec::new();
+        let mut unchecked = HashSet::new();
+        for (cid, exists) in results {
+            match exists {
+                Ok(exists) => cids_exist.push(CidExists { cid, exists }),
+                Err(e) => {
+                    tracing::error!("error checking blob {cid} {:?}", e);
+                    unchecked.insert(cid);
+                }
+            }
+        }
+
+        (cids_exist, unchecked)
+    }
+}
+
+#[async_trait]
+impl ContentChecker for StorageV2Checker {
+    fn protocol(&self) -> ContentProtocol {
+        ContentProtocol::StorageV2
+    }
+
+    /// 1. Ask the content node whether it's healthy, unhealthy nodes aren't checked further
+    /// 2. Get the CIDs discovery knows of that rendezvous hashing places on the content node
+    /// 3. Ask the content node which of their blobs it has
+    /// 4. Save the CIDs the content node has in the `network_monitoring` DB
+    #[tracing::instrument(skip(self, pool))]
+    async fn check_node(&self, pool: &PgPool, run_id: i32, cnode: ContentNode) -> Result<()> {
+        let ContentNode { spid, endpoint, .. } = cnode;
+
+        let health = match self.client.health_check(&endpoint).await {
+            Ok(health) => Some(health),
+            Err(e) => {
+                tracing::error!("error checking the health of {endpoint} {:?}", e);
+                None
+            }
+        };
+        save_health(pool, run_id, spid, health.as_ref()).await?;
+
+        let healthy = health.is_some_and(|health| health.healthy);
+        CONTENT_NODE_HEALTHY_GAUGE
+            .with_label_values(&[&endpoint, &run_id.to_string()])
+            .set(i64::from(healthy));
+        if !healthy {
+            tracing::warn!("skipping the blobs of {endpoint} because it is unhealthy");
+            return Ok(());
+        }
+
+        let content_nodes = get_content_nodes(pool, run_id).await?;
+        let hosts = self.hosts(&content_nodes);
+
+        // Inserting CIDs isn't idempotent, so an interrupted attempt at this run starts over
+        delete_saved_cids(pool, run_id, spid).await?;
+
+        let mut after_cid = String::new();
+        let mut after_user_id = i32::MIN;
+        loop {
+            let cid_batch = match get_cid_batch(pool, run_id, &after_cid, after_user_id).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    tracing::error!("error getting cid batch {:?}", e);
+                    // Later batches can't be found without this one, so the check fails
+                    return Err(e);
+                }
+            };
+
+            let Some(last_cid) = cid_batch.last() else {
+                break;
+            };
+            after_cid.clone_from(&last_cid.cid);
+            after_user_id = last_cid.user_id;
+
+            let placed_cids = cid_batch
+                .into_iter()
+                .filter(|expected| {
+                    rendezvous::placement(&hosts, &expected.cid, self.replication_factor)
+                        .contains(&endpoint.as_str())
+                })
+                .collect::<Vec<ExpectedCid>>();
+            if placed_cids.is_empty() {
+                continue;
+            }
+
+            let (cids_exist, unchecked) = self.blobs_exist(&endpoint, &placed_cids).await;
+
+            let unchecked_cids =
+                if let Err(e) = save_cids(pool, run_id, spid, &placed_cids, &cids_exist).await {
+                    tracing::error!("error saving cids {:?}", e);
+                    // None of the batch is known to be saved, so all of it is left unchecked
+                    placed_cids
+                } else {
+                    placed_cids
+                        .into_iter()
+                        .filter(|expected| unchecked.contains(&expected.cid))
+                        .collect::<Vec<ExpectedCid>>()
+                };
+            if unchecked_cids.is_empty() {
+                continue;
+            }
+            if let Err(e) = save_unchecked_cids(pool, run_id, spid, &unchecked_cids).await {
+                tracing::error!("error saving unchecked cids {:?}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Save the content nodes rendezvous hashing places every CID of the run on,
+    /// which metrics compare with the nodes that have its blob
+    #[tracing::instrument(skip(self, pool, content_nodes))]
+    async fn finish(
+        &self,
+        pool: &PgPool,
+        run_id: i32,
+        content_nodes: &[ContentNode],
+    ) -> Result<()> {
+        let hosts = self.hosts(content_nodes);
+        let spids = content_nodes
+            .iter()
+            .map(|cnode| (cnode.endpoint.as_str(), cnode.spid))
+            .collect::<HashMap<&str, i32>>();
+
+        delete_placements(pool, run_id).await?;
+
+        let mut after_cid = String::new();
+        let mut after_user_id = i32::MIN;
+        loop {
+            let cid_batch = get_cid_batch(pool, run_id, &after_cid, after_user_id).await?;
+
+            let Some(last_cid) = cid_batch.last() else {
+                break;
+            };
+            after_cid.clone_from(&last_cid.cid);
+            after_user_id = last_cid.user_id;
+
+            let cids = cid_batch
+                .iter()
+                .map(|expected| expected.cid.as_str())
+                .collect::<HashSet<&str>>();
+            let placements = cids
+                .into_iter()
+                .flat_map(|cid| {
+                    rendezvous::placement(&hosts, cid, self.replication_factor)
+                        .into_iter()
+                        .enumerate()
+                        .map(|(rank, host)| Placement {
+                            cid: cid.into(),
+                            spid: spids[host],
+                            rank: i16::try_from(rank).unwrap_or(i16::MAX),
+                        })
+                        .collect::<Vec<Placement>>()
+                })
+                .collect::<Vec<Placement>>();
+
+            save_placements(pool, run_id, &placements).await?;
+        }
+
+        Ok(())
+    }
+
+    /// A health check, then a request per CID placed on the node
+    #[tracing::instrument(skip(self, pool))]
+    async fn plan_node(
+        &self,
+        pool: &PgPool,
+        run_id: i32,
+        cnode: &ContentNode,
+    ) -> Result<RequestPlan> {
+        let content_nodes = get_content_nodes(pool, run_id).await?;
+        let hosts = self.hosts(&content_nodes);
+
+        let mut placed_cids = HashSet::new();
+        let mut after_cid = String::new();
+        let mut after_user_id = i32::MIN;
+        loop {
+            let cid_batch = get_cid_batch(pool, run_id, &after_cid, after_user_id).await?;
+
+            let Some(last_cid) = cid_batch.last() else {
+                break;
+            };
+            after_cid.clone_from(&last_cid.cid);
+            after_user_id = last_cid.user_id;
+
+            placed_cids.extend(
+                cid_batch
+                    .into_iter()
+                    .filter(|expected| {
+                        rendezvous::placement(&hosts, &expected.cid, self.replication_factor)
+                            .contains(&cnode.endpoint.as_str())
+                    })
+                    .map(|expected| expected.cid),
+            );
+        }
+
+        let mut plan = RequestPlan::default();
+        plan.add_batches(HEALTH_CHECK_ROUTE, 1, 1);
+        plan.add_batches(
+            BLOB_INFO_ROUTE,
+            i64::try_from(placed_cids.len()).unwrap_or(i64::MAX),
+            1,
+        );
+
+        Ok(plan)
+    }
+}
+
+/// A content node a CID is placed on, ranked `rank` for it
+#[derive(Debug)]
+struct Placement {
+    cid: String,
+    spid: i32,
+    rank: i16,
+}
+
+#[tracing::instrument(skip(pool))]
+async fn save_health(
+    pool: &PgPool,
+    run_id: i32,
+    spid: i32,
+    health: Option<&NodeHealth>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE network_monitoring_content_nodes
+        SET is_healthy = $3, version = $4
+        WHERE run_id = $1
+        AND spid = $2;
+        "#,
+        run_id,
+        spid,
+        health.is_some_and(|health| health.healthy),
+        health.and_then(|health| health.version.clone()),
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+async fn delete_saved_cids(pool: &PgPool, run_id: i32, spid: i32) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_cids_from_content
+        WHERE run_id = $1
+        AND content_node_spid = $2;
+        "#,
+        run_id,
+        spid,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_unchecked_cids
+        WHERE run_id = $1
+        AND content_node_spid = $2;
+        "#,
+        run_id,
+        spid,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// The next batch of every CID of the run, after `after_cid` and `after_user_id`.
+/// Every node pages through all of them since any CID may be placed on it.
+#[tracing::instrument(skip(pool))]
+async fn get_cid_batch(
+    pool: &PgPool,
+    run_id: i32,
+    after_cid: &str,
+    after_user_id: i32,
+) -> Result<Vec<ExpectedCid>> {
+    let batch = sqlx::query_as!(
+        ExpectedCid,
+        r#"
+        SELECT cid, user_id, ctype
+        FROM network_monitoring_cids_from_discovery
+        WHERE run_id = $1
+        AND (cid, user_id) > ($2, $3)
+        ORDER BY cid, user_id
+        LIMIT $4;
+        "#,
+        run_id,
+        after_cid,
+        after_user_id,
+        BATCH_SIZE,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(batch)
+}
+
+#[tracing::instrument(skip(pool))]
+async fn delete_placements(pool: &PgPool, run_id: i32) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM network_monitoring_cid_placements
+        WHERE run_id = $1;
+        "#,
+        run_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Save the placements of a batch, a CID spanning two batches is placed once
+#[tracing::instrument(skip(pool, placements))]
+async fn save_placements(pool: &PgPool, run_id: i32, placements: &[Placement]) -> Result<()> {
+    let (cids, (spids, ranks)): (Vec<String>, (Vec<i32>, Vec<i16>)) = placements
+        .iter()
+        .map(|placement| (placement.cid.clone(), (placement.spid, placement.rank)))
+        .unzip();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO network_monitoring_cid_placements (run_id, cid, spid, rank)
+        SELECT $1::int, tmp.cid, tmp.spid, tmp.rank
+        FROM UNNEST($2::text[], $3::int[], $4::smallint[]) AS tmp(cid, spid, rank)
+        ON CONFLICT DO NOTHING;
+        "#,
+        run_id,
+        &cids,
+        &spids,
+        &ranks,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
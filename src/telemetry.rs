@@ -4,6 +4,7 @@ use tracing_log::LogTracer;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 
+/// Subscriber logging the events `env_filter` lets through to `sink` as bunyan JSON
 pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
@@ -12,8 +13,7 @@ pub fn get_subscriber<Sink>(
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
-    let env_filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
+    let env_filter = EnvFilter::new(env_filter);
 
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
 
@@ -23,6 +23,11 @@ where
         .with(formatting_layer)
 }
 
+/// Register `subscriber` as the global default and route `log` records through it
+///
+/// # Panics
+///
+/// Panics if a logger or global subscriber has already been set
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
 
@@ -0,0 +1,486 @@
+use std::{convert::Infallible, fmt, str::FromStr};
+
+use color_eyre::eyre::Result;
+use sqlx::PgPool;
+use thiserror::Error;
+use tokio::join;
+
+use crate::{
+    configuration::{ContentProtocol, Settings},
+    content::{
+        client_from_settings, get_cids_exist, get_user_clock_values, Replica, CIDS_EXIST_ROUTE,
+        IMAGE_CIDS_EXIST_ROUTE,
+    },
+    domain::ContentNode,
+    registry,
+    utils::ContentNodeClient,
+};
+
+/// Clock value content nodes return for a wallet they have no data for
+const NO_CLOCK: i32 = -1;
+
+#[derive(Error, Debug)]
+pub enum UserCheckError {
+    #[error("no user matches {0}")]
+    NotFound(UserQuery),
+    #[error("user {0} has no wallet")]
+    NoWallet(i32),
+    #[error("users can only be checked on networks running the legacy protocol")]
+    UnsupportedProtocol,
+}
+
+/// How a user is looked up in discovery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserQuery {
+    Id(i32),
+    Wallet(String),
+    Handle(String),
+}
+
+impl FromStr for UserQuery {
+    type Err = Infallible;
+
+    /// A number is a user id, a `0x` prefixed 20 byte hex string a wallet,
+    /// anything else a handle, with or without its `@`
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        if let Ok(user_id) = query.parse() {
+            return Ok(Self::Id(user_id));
+        }
+
+        let is_wallet = query.len() == 42
+            && query.starts_with("0x")
+            && query[2..].chars().all(|c| c.is_ascii_hexdigit());
+        if is_wallet {
+            return Ok(Self::Wallet(query.to_lowercase()));
+        }
+
+        Ok(Self::Handle(query.trim_start_matches('@').to_lowercase()))
+    }
+}
+
+impl fmt::Display for UserQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(user_id) => write!(f, "user id {user_id}"),
+            Self::Wallet(wallet) => write!(f, "wallet {wallet}"),
+            Self::Handle(handle) => write!(f, "handle @{handle}"),
+        }
+    }
+}
+
+/// A user as discovery currently knows them
+#[derive(Debug, Clone)]
+pub struct DiscoveryUser {
+    pub user_id: i32,
+    pub handle: Option<String>,
+    pub wallet: Option<String>,
+    pub primary_id: Option<i32>,
+    pub secondary_ids: Vec<i32>,
+}
+
+/// What one content node of the user's replica set was asked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaStatus {
+    /// The user's replica set has no content node at this position
+    Unassigned,
+    /// The spid isn't a registered content node
+    Unregistered(i32),
+    Checked {
+        spid: i32,
+        endpoint: String,
+        clock: Result<i32, String>,
+        /// CIDs of the user the content node doesn't have, or why they couldn't be checked
+        missing_cids: Result<Vec<String>, String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Has the primary's clock value and every CID
+    Healthy,
+    Unassigned,
+    Unregistered,
+    /// Didn't answer the clock request
+    Unreachable,
+    /// Has no data for the user at all
+    MissingUser,
+    /// Has a different clock value than the primary
+    OutOfSync {
+        clock: i32,
+        primary_clock: i32,
+    },
+    /// Is in sync but doesn't have some of the user's CIDs
+    MissingCids(usize),
+    /// Answered the clock request but not the CID requests
+    CidsUnchecked,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Healthy => f.write_str("ok"),
+            Self::Unassigned => f.write_str("not assigned"),
+            Self::Unregistered => f.write_str("not a registered content node"),
+            Self::Unreachable => f.write_str("unreachable"),
+            Self::MissingUser => f.write_str("doesn't have the user"),
+            Self::OutOfSync {
+                clock,
+                primary_clock,
+            } if clock < primary_clock => {
+                write!(f, "behind the primary ({clock} < {primary_clock})")
+            }
+            Self::OutOfSync {
+                clock,
+                primary_clock,
+            } => write!(f, "ahead of the primary ({clock} > {primary_clock})"),
+            Self::MissingCids(count) => write!(f, "missing {count} CIDs"),
+            Self::CidsUnchecked => f.write_str("CIDs couldn't be checked"),
+        }
+    }
+}
+
+impl ReplicaStatus {
+    /// Judge the replica against the clock value of the user's primary
+    #[must_use]
+    pub fn verdict(&self, primary_clock: Option<i32>) -> Verdict {
+        let Self::Checked {
+            clock,
+            missing_cids,
+            ..
+        } = self
+        else {
+            return match self {
+                Self::Unregistered(_) => Verdict::Unregistered,
+                _ => Verdict::Unassigned,
+            };
+        };
+
+        match (clock, missing_cids) {
+            (Err(_), _) => Verdict::Unreachable,
+            (Ok(NO_CLOCK), _) => Verdict::MissingUser,
+            (Ok(clock), _)
+                if primary_clock.is_some_and(|primary_clock| *clock != primary_clock) =>
+            {
+                Verdict::OutOfSync {
+                    clock: *clock,
+                    primary_clock: primary_clock.unwrap_or_default(),
+                }
+            }
+            (Ok(_), Err(_)) => Verdict::CidsUnchecked,
+            (Ok(_), Ok(missing)) if !missing.is_empty() => Verdict::MissingCids(missing.len()),
+            (Ok(_), Ok(_)) => Verdict::Healthy,
+        }
+    }
+}
+
+/// The live state of a user's replica set
+#[derive(Debug, Clone)]
+pub struct UserReport {
+    pub user: DiscoveryUser,
+    pub cid_count: usize,
+    pub replicas: Vec<(&'static str, ReplicaStatus)>,
+}
+
+impl UserReport {
+    /// Clock value of the primary, if it answered with one
+    #[must_use]
+    pub fn primary_clock(&self) -> Option<i32> {
+        self.replicas
+            .iter()
+            .find_map(|(replica, status)| match status {
+                ReplicaStatus::Checked {
+                    clock: Ok(clock), ..
+                } if *replica == Replica::Primary.as_str() && *clock != NO_CLOCK => Some(*clock),
+                _ => None,
+            })
+    }
+
+    /// One line per replica with its verdict, then the CIDs each replica is missing
+    #[must_use]
+    pub fn to_text(&self) -> String {
+        let user = &self.user;
+        let mut lines = vec![format!(
+            "user {} (@{}, {}), {} CIDs",
+            user.user_id,
+            user.handle.as_deref().unwrap_or("-"),
+            user.wallet.as_deref().unwrap_or("-"),
+            self.cid_count,
+        )];
+
+        let primary_clock = self.primary_clock();
+        for (replica, status) in &self.replicas {
+            let verdict = status.verdict(primary_clock);
+            lines.push(match status {
+                ReplicaStatus::Unassigned => format!("{replica:<10}  {verdict}"),
+                ReplicaStatus::Unregistered(spid) => {
+                    format!("{replica:<10}  spid {spid:<4}  {verdict}")
+                }
+                ReplicaStatus::Checked {
+                    spid,
+                    endpoint,
+                    clock,
+                    ..
+                } => {
+                    let clock = clock
+                        .as_ref()
+                        .map_or_else(|_| "-".into(), ToString::to_string);
+                    format!("{replica:<10}  spid {spid:<4}  {endpoint}  clock {clock}  {verdict}")
+                }
+            });
+        }
+
+        for (replica, status) in &self.replicas {
+            match status {
+                ReplicaStatus::Checked { clock: Err(e), .. } => {
+                    lines.push(format!("{replica} clock request failed: {e}"));
+                }
+                ReplicaStatus::Checked {
+                    missing_cids: Err(e),
+                    ..
+                } => lines.push(format!("{replica} CID requests failed: {e}")),
+                ReplicaStatus::Checked {
+                    missing_cids: Ok(missing),
+                    ..
+                } if !missing.is_empty() => {
+                    lines.push(format!("{replica} is missing {}", missing.join(", ")));
+                }
+                _ => (),
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Look up a user in discovery, ask every content node of their replica set
+/// for their clock value and CIDs, and print a verdict per replica.
+/// Nothing is saved, so this doesn't need a run.
+///
+/// # Errors
+///
+/// Returns an error if the network doesn't run the legacy protocol,
+/// the user can't be found, or discovery can't be read
+pub async fn run(pool: &PgPool, config: &Settings, query: &UserQuery) -> Result<()> {
+    let report = check(pool, config, query).await?;
+
+    println!("{}", report.to_text());
+
+    Ok(())
+}
+
+/// Check the replica set of the user matching `query` live
+///
+/// # Errors
+///
+/// Returns an error if the network doesn't run the legacy protocol,
+/// the user can't be found, or discovery can't be read
+#[tracing::instrument(skip(pool, config))]
+pub async fn check(pool: &PgPool, config: &Settings, query: &UserQuery) -> Result<UserReport> {
+    if config.content.protocol != ContentProtocol::Legacy {
+        return Err(UserCheckError::UnsupportedProtocol.into());
+    }
+
+    let user = get_user(pool, query)
+        .await?
+        .ok_or_else(|| UserCheckError::NotFound(query.clone()))?;
+    let wallet = user
+        .wallet
+        .clone()
+        .ok_or(UserCheckError::NoWallet(user.user_id))?;
+    let cids = get_user_cids(pool, user.user_id).await?;
+
+    let content_nodes =
+        registry::from_settings(config.discovery.registry, pool, &config.discovery)?
+            .content_nodes()
+            .await?;
+    let client = client_from_settings(&config.content)?;
+
+    let spid_of = |replica: &Replica| match replica {
+        Replica::Primary => user.primary_id,
+        Replica::Secondary1 => user.secondary_ids.first().copied(),
+        Replica::Secondary2 => user.secondary_ids.get(1).copied(),
+    };
+    let check_position = |replica: Replica| {
+        let spid = spid_of(&replica);
+        let (client, content_nodes, cids, wallet) = (&client, &content_nodes, &cids, &wallet);
+        async move {
+            let status = match spid {
+                None => ReplicaStatus::Unassigned,
+                Some(spid) => match content_nodes.iter().find(|cnode| cnode.spid == spid) {
+                    None => ReplicaStatus::Unregistered(spid),
+                    Some(cnode) => check_replica(client, cnode, wallet, cids).await,
+                },
+            };
+
+            (replica.as_str(), status)
+        }
+    };
+
+    let (primary, secondary1, secondary2) = join!(
+        check_position(Replica::Primary),
+        check_position(Replica::Secondary1),
+        check_position(Replica::Secondary2),
+    );
+
+    Ok(UserReport {
+        cid_count: cids.len(),
+        user,
+        replicas: vec![primary, secondary1, secondary2],
+    })
+}
+
+/// Ask `cnode` for the clock value of `wallet` and which of `cids` it has
+async fn check_replica(
+    client: &ContentNodeClient,
+    cnode: &ContentNode,
+    wallet: &str,
+    cids: &[UserCid],
+) -> ReplicaStatus {
+    let endpoint = cnode.endpoint.as_str();
+
+    let clock = get_user_clock_values(client, endpoint, vec![wallet.to_owned()])
+        .await
+        .map_err(|e| e.to_string())
+        .map(|clock_values| {
+            clock_values
+                .into_iter()
+                .find(|pair| pair.wallet_public_key.eq_ignore_ascii_case(wallet))
+                .map_or(NO_CLOCK, |pair| pair.clock)
+        });
+
+    let mut missing_cids = Ok(Vec::new());
+    for (route, is_dir) in [(CIDS_EXIST_ROUTE, false), (IMAGE_CIDS_EXIST_ROUTE, true)] {
+        let batch = cids
+            .iter()
+            .filter(|cid| (cid.ctype == "dir") == is_dir)
+            .map(|cid| cid.cid.clone())
+            .collect::<Vec<String>>();
+        if batch.is_empty() {
+            continue;
+        }
+
+        match get_cids_exist(client, endpoint, route, batch).await {
+            Ok(cids_exist) => {
+                if let Ok(missing) = &mut missing_cids {
+                    missing.extend(
+                        cids_exist
+                            .into_iter()
+                            .filter(|cid_exists| !cid_exists.exists)
+                            .map(|cid_exists| cid_exists.cid),
+                    );
+                }
+            }
+            Err(e) => missing_cids = Err(e.to_string()),
+        }
+    }
+
+    ReplicaStatus::Checked {
+        spid: cnode.spid,
+        endpoint: cnode.endpoint.clone(),
+        clock,
+        missing_cids,
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_user(pool: &PgPool, query: &UserQuery) -> Result<Option<DiscoveryUser>> {
+    let (user_id, wallet, handle) = match query {
+        UserQuery::Id(user_id) => (Some(*user_id), None, None),
+        UserQuery::Wallet(wallet) => (None, Some(wallet.as_str()), None),
+        UserQuery::Handle(handle) => (None, None, Some(handle.as_str())),
+    };
+
+    let user = sqlx::query!(
+        r#"
+        SELECT user_id, handle, wallet, primary_id, secondary_ids
+        FROM discovery.users
+        WHERE is_current = TRUE
+        AND (user_id = $1 OR wallet = $2 OR handle_lc = $3)
+        LIMIT 1;
+        "#,
+        user_id,
+        wallet,
+        handle,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| DiscoveryUser {
+        user_id: row.user_id,
+        handle: row.handle,
+        wallet: row.wallet,
+        primary_id: row.primary_id,
+        secondary_ids: row.secondary_ids.unwrap_or_default(),
+    });
+
+    Ok(user)
+}
+
+#[derive(Debug)]
+struct UserCid {
+    cid: String,
+    ctype: String,
+}
+
+/// The CIDs discovery says the user's content should have,
+/// the same ones discovery imports into `network_monitoring_cids_from_discovery`
+#[tracing::instrument(skip(pool))]
+async fn get_user_cids(pool: &PgPool, user_id: i32) -> Result<Vec<UserCid>> {
+    let cids = sqlx::query!(
+        r#"
+        SELECT cids.cid AS "cid!", cids.ctype AS "ctype!"
+        FROM (
+            SELECT metadata_multihash AS cid, 'metadata' AS ctype
+            FROM discovery.users
+            WHERE user_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT profile_picture, 'image'
+            FROM discovery.users
+            WHERE user_id = $1 AND is_current = TRUE AND profile_picture != '0'
+            UNION ALL
+            SELECT profile_picture_sizes, 'dir'
+            FROM discovery.users
+            WHERE user_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT cover_photo, 'image'
+            FROM discovery.users
+            WHERE user_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT cover_photo_sizes, 'dir'
+            FROM discovery.users
+            WHERE user_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT cover_art, 'image'
+            FROM discovery.tracks
+            WHERE owner_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT cover_art_sizes, 'dir'
+            FROM discovery.tracks
+            WHERE owner_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT metadata_multihash, 'metadata'
+            FROM discovery.tracks
+            WHERE owner_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT download ->> 'cid', 'track'
+            FROM discovery.tracks
+            WHERE owner_id = $1 AND is_current = TRUE
+            UNION ALL
+            SELECT jsonb_array_elements(track_segments) ->> 'multihash', 'track'
+            FROM discovery.tracks
+            WHERE owner_id = $1 AND is_current = TRUE AND track_segments IS NOT NULL
+        ) AS cids
+        WHERE cids.cid IS NOT NULL;
+        "#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| UserCid {
+        cid: row.cid,
+        ctype: row.ctype,
+    })
+    .collect::<Vec<UserCid>>();
+
+    Ok(cids)
+}
@@ -1,28 +1,47 @@
+use chrono::Utc;
 use color_eyre::eyre::Result;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 
-use tokio_retry::{
-    strategy::{jitter, ExponentialBackoff},
-    Retry,
-};
+use tokio_retry::{strategy::jitter, RetryIf};
 
-use crate::domain::WalletClockPair;
+use crate::{
+    configuration::{HttpSettings, LimitSettings},
+    domain::{CidExists, NodeHealth, WalletClockPair},
+    limits::RequestLimiter,
+    prometheus::{CONTENT_NODE_REQUEST_DURATION_HISTOGRAM, CONTENT_NODE_REQUEST_ERRORS_COUNTER},
+    signing::Signer,
+};
 
 // const UNHEALTHY_TIME_RANGE_MS: i32 = 300_000; // 5min
 
+/// Routes of storage-v2 content nodes
+pub const HEALTH_CHECK_ROUTE: &str = "/health_check";
+/// Labelled with the `:cid` placeholder so metrics don't get a series per CID
+pub const BLOB_INFO_ROUTE: &str = "/internal/blobs/info/:cid";
+
+/// Query parameters content nodes authenticate service providers with
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SignatureParams {
+    #[serde(rename = "spID")]
     pub spid: u16,
-    pub timestamp: SystemTime,
-    pub signature: Vec<u8>,
+    pub timestamp: String,
+    pub signature: String,
 }
 
+/// What `SignatureParams::signature` signs, serialized with its keys sorted
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UnsignedParams {
+    #[serde(rename = "spID")]
     pub spid: u16,
-    pub timestamp: SystemTime,
+    pub timestamp: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,25 +57,417 @@ pub struct UserStatusPayload {
     pub wallet_public_keys: Vec<String>,
 }
 
-#[tracing::instrument]
-pub async fn make_request(url: &str, payload: &UserStatusPayload) -> Result<Vec<WalletClockPair>> {
-    let retry_strategy = ExponentialBackoff::from_millis(10)
-        .map(jitter) // add jitter to delays
-        .take(3); // limit to 3 retries
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CidsExistPayload {
+    pub cids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CidsExistResponse {
+    data: CidsExistData,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CidsExistData {
+    cids: Vec<CidExists>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HealthCheckResponse {
+    data: NodeHealth,
+}
+
+/// Why a request to a content node failed
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error("request timed out")]
+    Timeout(#[source] reqwest::Error),
+    #[error("connection refused")]
+    ConnectionRefused(#[source] reqwest::Error),
+    #[error("TLS handshake failed")]
+    Tls(#[source] reqwest::Error),
+    #[error("failed to connect")]
+    Connect(#[source] reqwest::Error),
+    #[error("content node responded with {0}")]
+    ClientError(StatusCode),
+    #[error("content node responded with {0}")]
+    ServerError(StatusCode),
+    #[error("response isn't valid JSON")]
+    MalformedJson(#[source] reqwest::Error),
+    #[error("response `data` isn't in the expected format")]
+    BadPayload(#[source] serde_json::Error),
+    #[error("request failed")]
+    Other(#[source] reqwest::Error),
+}
+
+impl RequestError {
+    /// Value of the `kind` label of the request error counter
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RequestError::Timeout(_) => "timeout",
+            RequestError::ConnectionRefused(_) => "connection_refused",
+            RequestError::Tls(_) => "tls",
+            RequestError::Connect(_) => "connect",
+            RequestError::ClientError(_) => "client_error",
+            RequestError::ServerError(_) => "server_error",
+            RequestError::MalformedJson(_) => "malformed_json",
+            RequestError::BadPayload(_) => "bad_payload",
+            RequestError::Other(_) => "other",
+        }
+    }
+
+    /// Timeouts, connection errors, 5xx and 429 statuses may not happen again,
+    /// anything else would fail the same way on every attempt
+    #[must_use]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RequestError::Timeout(_)
+            | RequestError::ConnectionRefused(_)
+            | RequestError::Connect(_)
+            | RequestError::ServerError(_)
+            | RequestError::Other(_) => true,
+            RequestError::ClientError(status) => *status == StatusCode::TOO_MANY_REQUESTS,
+            RequestError::Tls(_) | RequestError::MalformedJson(_) | RequestError::BadPayload(_) => {
+                false
+            }
+        }
+    }
+
+    /// The HTTP status the content node responded with, if it responded
+    #[must_use]
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestError::ClientError(status) | RequestError::ServerError(status) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for RequestError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            RequestError::Timeout(e)
+        } else if e.is_connect() {
+            if caused_by(&e, |cause| {
+                cause
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|io| io.kind() == io::ErrorKind::ConnectionRefused)
+            }) {
+                RequestError::ConnectionRefused(e)
+            } else if caused_by(&e, |cause| {
+                // The TLS backend's error types aren't exposed by reqwest
+                let cause = cause.to_string().to_lowercase();
+                cause.contains("tls") || cause.contains("ssl") || cause.contains("certificate")
+            }) {
+                RequestError::Tls(e)
+            } else {
+                RequestError::Connect(e)
+            }
+        } else if e.is_decode() {
+            RequestError::MalformedJson(e)
+        } else {
+            RequestError::Other(e)
+        }
+    }
+}
+
+fn caused_by(
+    e: &(dyn StdError + 'static),
+    matches: impl Fn(&(dyn StdError + 'static)) -> bool,
+) -> bool {
+    let mut cause = e.source();
+    while let Some(error) = cause {
+        if matches(error) {
+            return true;
+        }
+        cause = error.source();
+    }
+
+    false
+}
+
+/// HTTP client shared by every request to content nodes
+#[derive(Debug, Clone)]
+pub struct ContentNodeClient {
+    client: reqwest::Client,
+    limiter: Arc<RequestLimiter>,
+    signer: Option<Signer>,
+    retries: u32,
+    retry_backoff: Duration,
+}
+
+impl ContentNodeClient {
+    /// # Errors
+    ///
+    /// Returns an error if the proxy URL is invalid or the client can't be built
+    pub fn new(
+        config: &HttpSettings,
+        limits: &LimitSettings,
+        signer: Option<Signer>,
+    ) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout_seconds))
+            .timeout(Duration::from_secs(config.request_timeout_seconds))
+            .pool_max_idle_per_host(config.max_idle_connections)
+            .user_agent(&config.user_agent);
 
-    let wallet_batch =
-        Retry::spawn(retry_strategy, async || network_call(url, payload).await).await?;
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
 
-    Ok(wallet_batch)
+        Ok(Self {
+            client: builder.build()?,
+            limiter: Arc::new(RequestLimiter::new(limits)),
+            signer,
+            retries: config.retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff_millis),
+        })
+    }
+
+    /// Get the clock values of a batch of users from a content node
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt if every attempt fails,
+    /// or the first error that isn't worth retrying
+    #[tracing::instrument(skip(self, payload))]
+    pub async fn make_request(
+        &self,
+        endpoint: &str,
+        route: &str,
+        payload: &UserStatusPayload,
+    ) -> Result<Vec<WalletClockPair>, RequestError> {
+        let wallet_batch = RetryIf::spawn(
+            self.retry_strategy(),
+            async || {
+                let _permit = self.limiter.acquire(endpoint).await;
+                observe(endpoint, route, self.network_call(endpoint, route, payload)).await
+            },
+            RequestError::is_retryable,
+        )
+        .await?;
+
+        Ok(wallet_batch)
+    }
+
+    async fn network_call(
+        &self,
+        endpoint: &str,
+        route: &str,
+        payload: &UserStatusPayload,
+    ) -> Result<Vec<WalletClockPair>, RequestError> {
+        let res = self.post(endpoint, route, payload).await?;
+
+        let js = res.json::<WalletBatchResponse>().await?;
+
+        let wallet_batch: Vec<WalletClockPair> =
+            serde_json::from_str(&js.data).map_err(RequestError::BadPayload)?;
+
+        Ok(wallet_batch)
+    }
+
+    /// Ask a content node which of a batch of CIDs it has
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt if every attempt fails,
+    /// or the first error that isn't worth retrying
+    #[tracing::instrument(skip(self, payload))]
+    pub async fn make_cids_request(
+        &self,
+        endpoint: &str,
+        route: &str,
+        payload: &CidsExistPayload,
+    ) -> Result<Vec<CidExists>, RequestError> {
+        let cids_exist = RetryIf::spawn(
+            self.retry_strategy(),
+            async || {
+                let _permit = self.limiter.acquire(endpoint).await;
+                observe(
+                    endpoint,
+                    route,
+                    self.cids_network_call(endpoint, route, payload),
+                )
+                .await
+            },
+            RequestError::is_retryable,
+        )
+        .await?;
+
+        Ok(cids_exist)
+    }
+
+    async fn cids_network_call(
+        &self,
+        endpoint: &str,
+        route: &str,
+        payload: &CidsExistPayload,
+    ) -> Result<Vec<CidExists>, RequestError> {
+        let res = self.post(endpoint, route, payload).await?;
+
+        let js = res.json::<CidsExistResponse>().await?;
+
+        Ok(js.data.cids)
+    }
+
+    /// Ask a storage-v2 content node whether it's healthy and which version it runs
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt if every attempt fails,
+    /// or the first error that isn't worth retrying
+    #[tracing::instrument(skip(self))]
+    pub async fn health_check(&self, endpoint: &str) -> Result<NodeHealth, RequestError> {
+        RetryIf::spawn(
+            self.retry_strategy(),
+            async || {
+                let _permit = self.limiter.acquire(endpoint).await;
+                observe(endpoint, HEALTH_CHECK_ROUTE, async {
+                    let res = self.get(endpoint, HEALTH_CHECK_ROUTE).await?;
+                    let js = res.json::<HealthCheckResponse>().await?;
+
+                    Ok(js.data)
+                })
+                .await
+            },
+            RequestError::is_retryable,
+        )
+        .await
+    }
+
+    /// Ask a storage-v2 content node whether it holds the blob of `cid`
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt if every attempt fails,
+    /// or the first error that isn't worth retrying
+    #[tracing::instrument(skip(self))]
+    pub async fn blob_exists(&self, endpoint: &str, cid: &str) -> Result<bool, RequestError> {
+        let path = BLOB_INFO_ROUTE.replace(":cid", cid);
+
+        RetryIf::spawn(
+            self.retry_strategy(),
+            async || {
+                let _permit = self.limiter.acquire(endpoint).await;
+                observe(endpoint, BLOB_INFO_ROUTE, async {
+                    match self.get(endpoint, &path).await {
+                        Ok(_) => Ok(true),
+                        // The node answered, it just doesn't have the blob
+                        Err(RequestError::ClientError(StatusCode::NOT_FOUND)) => Ok(false),
+                        Err(e) => Err(e),
+                    }
+                })
+                .await
+            },
+            RequestError::is_retryable,
+        )
+        .await
+    }
+
+    /// POST `payload` to `route` of a content node, signed as the service provider,
+    /// failing on error statuses
+    async fn post(
+        &self,
+        endpoint: &str,
+        route: &str,
+        payload: &impl Serialize,
+    ) -> Result<reqwest::Response, RequestError> {
+        let res = self
+            .sign(self.client.post(format!("{endpoint}{route}")))
+            .json(payload)
+            .send()
+            .await?;
+
+        error_for_status(res)
+    }
+
+    /// GET `path` of a content node, signed as the service provider, failing on error statuses
+    async fn get(&self, endpoint: &str, path: &str) -> Result<reqwest::Response, RequestError> {
+        let res = self
+            .sign(self.client.get(format!("{endpoint}{path}")))
+            .send()
+            .await?;
+
+        error_for_status(res)
+    }
+
+    /// Attach the signature of the service provider, without a signer the request is left unsigned
+    fn sign(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.signer {
+            Some(signer) => request.query(&signer.sign_params(Utc::now())),
+            None => request,
+        }
+    }
+
+    /// Exponential backoff from `retry_backoff`, with jitter so retries to a node don't line up
+    fn retry_strategy(&self) -> impl Iterator<Item = Duration> {
+        let retry_backoff = self.retry_backoff;
+
+        (0..self.retries)
+            .map(move |retry| retry_backoff.saturating_mul(2_u32.saturating_pow(retry)))
+            .map(jitter)
+    }
+}
+
+fn error_for_status(res: reqwest::Response) -> Result<reqwest::Response, RequestError> {
+    let status = res.status();
+    if status.is_client_error() {
+        return Err(RequestError::ClientError(status));
+    }
+    if status.is_server_error() {
+        return Err(RequestError::ServerError(status));
+    }
+
+    Ok(res)
 }
 
-async fn network_call(url: &str, payload: &UserStatusPayload) -> Result<Vec<WalletClockPair>> {
-    let client = reqwest::Client::new();
-    let res = client.post(url).json(payload).send().await?;
+/// Record how long a request attempt took and why it failed
+async fn observe<T>(
+    endpoint: &str,
+    route: &str,
+    request: impl Future<Output = Result<T, RequestError>>,
+) -> Result<T, RequestError> {
+    let started = Instant::now();
+    let result = request.await;
 
-    let js = res.json::<WalletBatchResponse>().await?;
+    CONTENT_NODE_REQUEST_DURATION_HISTOGRAM
+        .with_label_values(&[endpoint, route])
+        .observe(started.elapsed().as_secs_f64());
+
+    if let Err(e) = &result {
+        let status = e.status().map(|status| status.as_u16().to_string());
+        CONTENT_NODE_REQUEST_ERRORS_COUNTER
+            .with_label_values(&[endpoint, route, e.kind(), status.as_deref().unwrap_or("")])
+            .inc();
+    }
+
+    result
+}
 
-    let wallet_batch: Vec<WalletClockPair> = serde_json::from_str(&js.data)?;
+/// Write `items` with `write`, at most `chunk_size` at a time, in order.
+/// Every item is passed to `write` exactly once, unless a write fails,
+/// which stops before the next chunk.
+///
+/// # Errors
+///
+/// Returns the first error returned by `write`
+///
+/// # Panics
+///
+/// Panics if `chunk_size` is 0
+pub async fn write_in_chunks<'a, T, F, Fut>(
+    items: &'a [T],
+    chunk_size: usize,
+    mut write: F,
+) -> Result<()>
+where
+    F: FnMut(&'a [T]) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    for chunk in items.chunks(chunk_size) {
+        write(chunk).await?;
+    }
 
-    Ok(wallet_batch)
+    Ok(())
 }
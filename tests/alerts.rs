@@ -0,0 +1,160 @@
+use audius_network_monitor::{
+    alerts::{format_message, plan, post_to_slack, primary_user_drops, Alert, SentAlert},
+    metrics::CNodeCount,
+};
+use chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_json, method},
+    Mock, MockServer, ResponseTemplate,
+};
+
+fn alert(fingerprint: &str) -> Alert {
+    Alert {
+        fingerprint: fingerprint.into(),
+        summary: format!("{fingerprint} fired"),
+    }
+}
+
+fn sent(fingerprint: &str, hours_ago: i64, resolved: bool) -> SentAlert {
+    let now = Utc.with_ymd_and_hms(2023, 3, 16, 12, 0, 0).unwrap();
+
+    SentAlert {
+        fingerprint: fingerprint.into(),
+        summary: format!("{fingerprint} fired"),
+        last_notified_at: now - Duration::hours(hours_ago),
+        resolved_at: resolved.then_some(now - Duration::hours(1)),
+    }
+}
+
+fn cnode(endpoint: &str, count: i64) -> CNodeCount {
+    CNodeCount {
+        endpoint: endpoint.into(),
+        count,
+    }
+}
+
+#[test]
+fn plan_sends_new_alerts_and_dedups_recent_ones() {
+    let now = Utc.with_ymd_and_hms(2023, 3, 16, 12, 0, 0).unwrap();
+    let firing = vec![
+        alert("new"),
+        alert("recent"),
+        alert("stale"),
+        alert("reopened"),
+    ];
+    let sent = vec![
+        sent("recent", 2, false),
+        sent("stale", 30, false),
+        sent("reopened", 48, true),
+    ];
+
+    let notification = plan(&firing, &sent, now, Duration::hours(24));
+
+    let fingerprints = notification
+        .firing
+        .iter()
+        .map(|alert| alert.fingerprint.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(fingerprints, ["new", "stale", "reopened"]);
+    assert!(notification.resolved.is_empty());
+}
+
+#[test]
+fn plan_resolves_alerts_that_stopped_firing() {
+    let now = Utc.with_ymd_and_hms(2023, 3, 16, 12, 0, 0).unwrap();
+    let sent = vec![sent("fixed", 2, false), sent("already_resolved", 2, true)];
+
+    let notification = plan(&[], &sent, now, Duration::hours(24));
+
+    assert!(notification.firing.is_empty());
+    assert_eq!(notification.resolved.len(), 1);
+    assert_eq!(notification.resolved[0].fingerprint, "fixed");
+}
+
+#[test]
+fn plan_is_empty_when_nothing_changed() {
+    let now = Utc.with_ymd_and_hms(2023, 3, 16, 12, 0, 0).unwrap();
+
+    let notification = plan(
+        &[alert("recent")],
+        &[sent("recent", 2, false)],
+        now,
+        Duration::hours(24),
+    );
+
+    assert!(notification.is_empty());
+}
+
+#[test]
+fn primary_user_drops_above_the_threshold_fire() {
+    let previous = vec![
+        cnode("https://cn1.audius.co", 1000),
+        cnode("https://cn2.audius.co", 1000),
+        cnode("https://cn3.audius.co", 1000),
+    ];
+    let current = vec![
+        cnode("https://cn1.audius.co", 900),
+        cnode("https://cn2.audius.co", 700),
+    ];
+
+    let alerts = primary_user_drops(41, &previous, &current, 20.0);
+
+    assert_eq!(alerts.len(), 2);
+    assert_eq!(
+        alerts[0].fingerprint,
+        "primary_user_drop:https://cn2.audius.co"
+    );
+    assert!(alerts[0].summary.contains("30.0%"));
+    assert!(alerts[0].summary.contains("run 41"));
+    assert_eq!(
+        alerts[1].fingerprint,
+        "primary_user_drop:https://cn3.audius.co"
+    );
+}
+
+#[test]
+fn message_lists_firing_and_resolved_alerts() {
+    let now = Utc.with_ymd_and_hms(2023, 3, 16, 12, 0, 0).unwrap();
+    let notification = plan(
+        &[alert("unsynced_users")],
+        &[sent("unreachable_node:https://cn1.audius.co", 2, false)],
+        now,
+        Duration::hours(24),
+    );
+
+    let message = format_message(42, &notification);
+
+    assert_eq!(
+        message,
+        "*Network monitoring run 42*\n\
+         :rotating_light: 1 firing\n\
+         • unsynced_users fired\n\
+         :white_check_mark: 1 resolved\n\
+         • ~unreachable_node:https://cn1.audius.co fired~"
+    );
+}
+
+#[tokio::test]
+async fn alerts_are_posted_to_the_webhook() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_json(json!({ "text": "hello" })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    post_to_slack(&server.uri(), "hello").await.unwrap();
+}
+
+#[tokio::test]
+async fn webhook_errors_are_surfaced() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(404).set_body_string("no_service"))
+        .mount(&server)
+        .await;
+
+    assert!(post_to_slack(&server.uri(), "hello").await.is_err());
+}
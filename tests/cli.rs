@@ -0,0 +1,152 @@
+use audius_network_monitor::{
+    cli::{Cli, Command, ScopeArgs},
+    configuration::{Environment, ScopeSettings},
+    report::ReportArgs,
+};
+use clap::Parser;
+
+fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+    Cli::try_parse_from(std::iter::once("audius_network_monitor").chain(args.iter().copied()))
+}
+
+#[test]
+fn no_subcommand_runs_every_stage() {
+    let cli = parse(&[]).unwrap();
+
+    assert_eq!(cli.command, None);
+    assert_eq!(cli.config_dir, std::path::PathBuf::from("configuration"));
+}
+
+#[test]
+fn global_flags_are_accepted_after_the_subcommand() {
+    let cli = parse(&[
+        "metrics",
+        "12",
+        "--allow-incomplete",
+        "--environment",
+        "production",
+        "--config-dir",
+        "/etc/network-monitor",
+        "--log-level",
+        "debug",
+    ])
+    .unwrap();
+
+    assert_eq!(
+        cli.command,
+        Some(Command::Metrics {
+            run_id: Some(12),
+            allow_incomplete: true,
+        })
+    );
+    assert_eq!(cli.environment, Environment::Production);
+    assert_eq!(
+        cli.config_dir,
+        std::path::PathBuf::from("/etc/network-monitor")
+    );
+    assert_eq!(cli.log_level, "debug");
+}
+
+#[test]
+fn stages_take_a_run_id() {
+    assert_eq!(
+        parse(&["run", "--resume", "7"]).unwrap().command,
+        Some(Command::Run {
+            resume: Some(7),
+            scope: ScopeArgs::default(),
+        })
+    );
+    assert_eq!(
+        parse(&["check-content", "7"]).unwrap().command,
+        Some(Command::CheckContent {
+            run_id: 7,
+            scope: ScopeArgs::default(),
+        })
+    );
+    assert!(parse(&["check-content"]).is_err());
+    assert!(parse(&["check-content", "latest"]).is_err());
+}
+
+#[test]
+fn scope_flags_override_the_configured_scope() {
+    let cli = parse(&[
+        "run",
+        "--include-spid",
+        "2",
+        "--include-spid",
+        "3",
+        "--sample-percent",
+        "12.5",
+        "--dry-run",
+    ])
+    .unwrap();
+    let Some(Command::Run { scope: args, .. }) = cli.command else {
+        panic!("expected the run subcommand");
+    };
+
+    let mut scope = ScopeSettings {
+        include_spids: vec![1],
+        exclude_spids: vec![4],
+        max_user_id: Some(1_000),
+        ..ScopeSettings::default()
+    };
+    args.apply(&mut scope);
+
+    assert_eq!(
+        scope,
+        ScopeSettings {
+            include_spids: vec![2, 3],
+            exclude_spids: vec![4],
+            max_user_id: Some(1_000),
+            sample_percent: Some(12.5),
+            dry_run: true,
+            ..ScopeSettings::default()
+        }
+    );
+    assert!(parse(&["discover", "--min-user-id", "10"]).is_ok());
+    assert!(parse(&["metrics", "--dry-run"]).is_err());
+}
+
+#[test]
+fn report_args_default_to_the_latest_runs() {
+    assert_eq!(
+        parse(&["report"]).unwrap().command,
+        Some(Command::Report(ReportArgs::default()))
+    );
+}
+
+#[test]
+fn report_args_are_parsed() {
+    let cli = parse(&[
+        "report",
+        "12",
+        "--previous",
+        "9",
+        "--output",
+        "report.md",
+        "--slack",
+        "--allow-incomplete",
+    ])
+    .unwrap();
+
+    assert_eq!(
+        cli.command,
+        Some(Command::Report(ReportArgs {
+            run_id: Some(12),
+            previous_run_id: Some(9),
+            output: Some("report.md".into()),
+            slack: true,
+            allow_incomplete: true,
+        }))
+    );
+}
+
+#[test]
+fn invalid_args_are_rejected() {
+    assert!(parse(&["report", "latest"]).is_err());
+    assert!(parse(&["report", "--previous"]).is_err());
+    assert!(parse(&["report", "--verbose"]).is_err());
+    assert!(parse(&["report", "12", "13"]).is_err());
+    assert!(parse(&["--environment", "dev"]).is_err());
+    assert!(parse(&["--resume", "7"]).is_err());
+}
@@ -0,0 +1,204 @@
+use audius_network_monitor::{
+    configuration::{
+        ContentProtocol, ContentSettings, HttpSettings, LimitSettings, StorageV2Settings,
+    },
+    content::from_settings,
+    domain::{NodeHealth, WalletClockPair},
+    signing::Signer,
+    utils::{CidsExistPayload, ContentNodeClient, UserStatusPayload},
+};
+use secrecy::Secret;
+use serde_json::json;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const PRIVATE_KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+const STORED_CID: &str = "baeaaaiqsea6n5tqubbo4hbfzyytisyumpv5a5mmfr7uhnvftkijvi4qbxu2oy";
+const MISSING_CID: &str = "baeaaaiqsebwcm4xvzsqtaf6xr2a4f7wj6mbbdfkhycqbaj6alfkyqyuhbw4ea";
+
+fn settings(protocol: ContentProtocol) -> ContentSettings {
+    ContentSettings {
+        deregistered_nodes: vec![],
+        signature_spid: 1,
+        delegate_priv_key: Some(Secret::new(PRIVATE_KEY.into())),
+        protocol,
+        storage_v2: StorageV2Settings::default(),
+        http: HttpSettings::default(),
+        limits: LimitSettings::default(),
+    }
+}
+
+fn client() -> ContentNodeClient {
+    ContentNodeClient::new(
+        &HttpSettings {
+            retries: 0,
+            ..HttpSettings::default()
+        },
+        &LimitSettings::default(),
+        Some(Signer::new(1, &Secret::new(PRIVATE_KEY.into())).unwrap()),
+    )
+    .unwrap()
+}
+
+/// A content node running the legacy protocol, every user is at clock 7
+async fn legacy_node() -> MockServer {
+    let server = MockServer::start().await;
+
+    let data = json!([{ "walletPublicKey": "0xabc", "clock": 7 }]).to_string();
+    Mock::given(method("POST"))
+        .and(path("/users/batch_clock_status"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": data,
+            "method": "POST",
+            "headers": {},
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/batch_cids_exist"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "cids": [
+                { "cid": STORED_CID, "exists": true },
+                { "cid": MISSING_CID, "exists": false },
+            ] },
+        })))
+        .mount(&server)
+        .await;
+
+    server
+}
+
+/// A content node running storage-v2 that only stores `STORED_CID`
+async fn storage_v2_node(healthy: bool) -> MockServer {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/health_check"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "healthy": healthy, "version": "0.4.2", "service": "content-node" },
+            "signer": "0x2c7536e3605d9c16a7a3d7b1898e529396a65c23",
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/internal/blobs/info/{STORED_CID}")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "key": STORED_CID })))
+        .mount(&server)
+        .await;
+
+    server
+}
+
+#[test]
+fn the_checker_follows_the_protocol_of_the_network() {
+    for protocol in [ContentProtocol::Legacy, ContentProtocol::StorageV2] {
+        assert_eq!(
+            from_settings(&settings(protocol)).unwrap().protocol(),
+            protocol
+        );
+    }
+}
+
+#[test]
+fn an_invalid_delegate_key_is_rejected() {
+    let settings = ContentSettings {
+        delegate_priv_key: Some(Secret::new("0x00".into())),
+        ..settings(ContentProtocol::Legacy)
+    };
+
+    assert!(from_settings(&settings).is_err());
+}
+
+#[test]
+fn the_delegate_key_is_optional() {
+    let settings = ContentSettings {
+        delegate_priv_key: None,
+        ..settings(ContentProtocol::Legacy)
+    };
+
+    assert!(from_settings(&settings).is_ok());
+}
+
+#[tokio::test]
+async fn legacy_nodes_report_clock_values_and_cids() {
+    let node = legacy_node().await;
+
+    let clock_values = client()
+        .make_request(
+            &node.uri(),
+            "/users/batch_clock_status",
+            &UserStatusPayload {
+                wallet_public_keys: vec!["0xabc".into()],
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        clock_values,
+        [WalletClockPair {
+            wallet_public_key: "0xabc".into(),
+            clock: 7,
+        }]
+    );
+
+    let cids_exist = client()
+        .make_cids_request(
+            &node.uri(),
+            "/batch_cids_exist",
+            &CidsExistPayload {
+                cids: vec![STORED_CID.into(), MISSING_CID.into()],
+            },
+        )
+        .await
+        .unwrap();
+    assert!(cids_exist[0].exists);
+    assert!(!cids_exist[1].exists);
+}
+
+#[tokio::test]
+async fn storage_v2_nodes_report_their_health_and_blobs() {
+    let node = storage_v2_node(true).await;
+
+    assert_eq!(
+        client().health_check(&node.uri()).await.unwrap(),
+        NodeHealth {
+            healthy: true,
+            version: Some("0.4.2".into()),
+        }
+    );
+    assert!(client().blob_exists(&node.uri(), STORED_CID).await.unwrap());
+    assert!(!client()
+        .blob_exists(&node.uri(), MISSING_CID)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn unhealthy_storage_v2_nodes_are_reported() {
+    let node = storage_v2_node(false).await;
+
+    assert!(!client().health_check(&node.uri()).await.unwrap().healthy);
+}
+
+#[tokio::test]
+async fn each_protocol_is_only_spoken_by_its_nodes() {
+    let legacy = legacy_node().await;
+    let storage_v2 = storage_v2_node(true).await;
+
+    let error = client().health_check(&legacy.uri()).await.unwrap_err();
+    assert_eq!(error.kind(), "client_error");
+
+    let error = client()
+        .make_request(
+            &storage_v2.uri(),
+            "/users/batch_clock_status",
+            &UserStatusPayload {
+                wallet_public_keys: vec!["0xabc".into()],
+            },
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), "client_error");
+}
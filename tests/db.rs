@@ -0,0 +1,36 @@
+use audius_network_monitor::db::{option_changes, quote_ident, quote_literal};
+
+#[test]
+fn literals_are_quoted() {
+    assert_eq!(quote_literal("discovery"), "'discovery'");
+    assert_eq!(
+        quote_literal("pa'ss'); DROP TABLE users; --"),
+        "'pa''ss''); DROP TABLE users; --'"
+    );
+    assert_eq!(quote_literal(r"C:\data'"), r"E'C:\\data'''");
+}
+
+#[test]
+fn identifiers_are_quoted() {
+    assert_eq!(
+        quote_ident("fdw_server_connection"),
+        r#""fdw_server_connection""#
+    );
+    assert_eq!(quote_ident(r#"my"server"#), r#""my""server""#);
+}
+
+#[test]
+fn only_changed_options_are_altered() {
+    let current = vec!["dbname=discovery".to_owned(), "host=old-host".to_owned()];
+    let wanted = [
+        ("dbname", "discovery".to_owned()),
+        ("host", "new-host".to_owned()),
+        ("port", "5432".to_owned()),
+    ];
+
+    assert_eq!(
+        option_changes(&current, &wanted),
+        vec![r#"SET "host" 'new-host'"#, r#"ADD "port" '5432'"#]
+    );
+    assert!(option_changes(&["port=5432".to_owned()], &[("port", "5432".to_owned())]).is_empty());
+}
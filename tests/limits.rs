@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+use audius_network_monitor::{
+    configuration::LimitSettings,
+    limits::{RequestLimiter, TokenBucket},
+};
+use tokio::time::timeout;
+
+const CN1: &str = "https://cn1.audius.co";
+const CN2: &str = "https://cn2.audius.co";
+
+fn limiter(max_in_flight: usize, max_in_flight_per_endpoint: usize) -> RequestLimiter {
+    RequestLimiter::new(&LimitSettings {
+        max_in_flight,
+        max_in_flight_per_endpoint,
+        requests_per_second: None,
+        burst: 1,
+    })
+}
+
+#[test]
+fn bursts_are_let_through_then_requests_are_spaced_out() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(2.0, 3, start);
+
+    for _ in 0..3 {
+        assert_eq!(bucket.take(start), Duration::ZERO);
+    }
+    assert_eq!(bucket.take(start), Duration::from_millis(500));
+    assert_eq!(bucket.take(start), Duration::from_secs(1));
+}
+
+#[test]
+fn the_bucket_refills_up_to_the_burst() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(2.0, 2, start);
+    bucket.take(start);
+    bucket.take(start);
+
+    let later = start + Duration::from_secs(60);
+    assert_eq!(bucket.take(later), Duration::ZERO);
+    assert_eq!(bucket.take(later), Duration::ZERO);
+    assert_eq!(bucket.take(later), Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn requests_to_an_endpoint_are_capped() {
+    let limiter = limiter(10, 2);
+    let first = limiter.acquire(CN1).await;
+    let _second = limiter.acquire(CN1).await;
+
+    assert!(timeout(Duration::from_millis(50), limiter.acquire(CN1))
+        .await
+        .is_err());
+    let _other_endpoint = limiter.acquire(CN2).await;
+
+    drop(first);
+    assert!(timeout(Duration::from_millis(50), limiter.acquire(CN1))
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn requests_across_endpoints_are_capped() {
+    let limiter = limiter(2, 2);
+    let _first = limiter.acquire(CN1).await;
+    let second = limiter.acquire(CN2).await;
+
+    assert!(timeout(Duration::from_millis(50), limiter.acquire(CN2))
+        .await
+        .is_err());
+
+    drop(second);
+    assert!(timeout(Duration::from_millis(50), limiter.acquire(CN2))
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn requests_wait_for_the_rate_limit() {
+    let limiter = RequestLimiter::new(&LimitSettings {
+        max_in_flight: 10,
+        max_in_flight_per_endpoint: 10,
+        requests_per_second: Some(20.0),
+        burst: 1,
+    });
+
+    let started = Instant::now();
+    for _ in 0..3 {
+        drop(limiter.acquire(CN1).await);
+    }
+
+    // The first request takes the burst, the next two wait 50ms each
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
@@ -0,0 +1,95 @@
+use audius_network_monitor::metrics::{
+    estimate_ratio, replication_shortfall, z_score, CidReplication, ReplicationShortfall,
+    SampledStratum,
+};
+
+fn replication(placed: i64, held: i64, count: i64) -> CidReplication {
+    CidReplication {
+        placed,
+        held,
+        count,
+    }
+}
+
+#[test]
+fn fully_replicated_cids_have_no_shortfall() {
+    let shortfall = replication_shortfall(&[replication(3, 3, 120), replication(2, 2, 4)]);
+
+    assert_eq!(shortfall, ReplicationShortfall::default());
+}
+
+#[test]
+fn missing_replicas_are_weighted_by_cid_count() {
+    let shortfall = replication_shortfall(&[
+        replication(3, 3, 100),
+        replication(3, 2, 10),
+        replication(3, 1, 5),
+    ]);
+
+    assert_eq!(
+        shortfall,
+        ReplicationShortfall {
+            under_replicated_count: 15,
+            unavailable_count: 0,
+            missing_replicas: 10 + 2 * 5,
+        }
+    );
+}
+
+#[test]
+fn cids_held_by_none_of_their_nodes_are_unavailable() {
+    let shortfall = replication_shortfall(&[replication(3, 0, 2), replication(1, 0, 1)]);
+
+    assert_eq!(
+        shortfall,
+        ReplicationShortfall {
+            under_replicated_count: 3,
+            unavailable_count: 3,
+            missing_replicas: 3 * 2 + 1,
+        }
+    );
+}
+
+fn stratum(population: i64, sampled: i64, unsynced: i64) -> SampledStratum {
+    SampledStratum {
+        population,
+        sampled,
+        fully_synced: sampled - unsynced,
+        partially_synced: 0,
+        unsynced,
+    }
+}
+
+#[test]
+fn z_scores_match_the_normal_quantiles() {
+    assert!((z_score(0.95).unwrap() - 1.96).abs() < 1e-3);
+    assert!((z_score(0.99).unwrap() - 2.576).abs() < 1e-3);
+    assert_eq!(z_score(1.0), None);
+    assert_eq!(z_score(0.0), None);
+}
+
+#[test]
+fn strata_are_weighted_by_their_population() {
+    let strata = [stratum(9_000, 100, 10), stratum(1_000, 100, 50)];
+
+    let estimate = estimate_ratio(&strata, |stratum| stratum.unsynced, 1.96).unwrap();
+
+    // 0.9 * 0.1 + 0.1 * 0.5
+    assert!((estimate.ratio - 0.14).abs() < 1e-9);
+    assert!(estimate.lower < estimate.ratio && estimate.ratio < estimate.upper);
+    assert!((estimate.upper - estimate.ratio - 0.0536).abs() < 1e-3);
+}
+
+#[test]
+fn fully_sampled_strata_have_no_margin() {
+    let strata = [stratum(40, 40, 4), stratum(10, 10, 0), stratum(25, 0, 0)];
+
+    let estimate = estimate_ratio(&strata, |stratum| stratum.unsynced, 1.96).unwrap();
+
+    assert!((estimate.ratio - 0.08).abs() < 1e-9);
+    assert!((estimate.lower - estimate.upper).abs() < 1e-9);
+    assert_eq!(
+        estimate_ratio(&[stratum(25, 0, 0)], |stratum| stratum.unsynced, 1.96),
+        None
+    );
+}
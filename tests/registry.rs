@@ -0,0 +1,226 @@
+use audius_network_monitor::{
+    configuration::ChainSettings,
+    domain::ContentNode,
+    registry::{cross_check, ChainRegistry, NodeRegistry},
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_string_contains, method},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const SERVICE_TYPE: &str = "636f6e74656e742d6e6f64650000000000000000000000000000000000000000";
+const OWNER: &str = "0x1111111111111111111111111111111111111111";
+const DELEGATE: &str = "0x2222222222222222222222222222222222222222";
+const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+fn uint(value: usize) -> String {
+    format!("{value:064x}")
+}
+
+fn address(value: &str) -> String {
+    format!("{:0>64}", value.trim_start_matches("0x"))
+}
+
+/// ABI-encode `(address, string, uint256, address)`
+fn endpoint_info(owner: &str, endpoint: &str, delegate: &str) -> String {
+    let mut padded = endpoint.as_bytes().to_vec();
+    padded.resize(endpoint.len().div_ceil(32) * 32, 0);
+
+    format!(
+        "0x{}{}{}{}{}{}",
+        address(owner),
+        uint(4 * 32),
+        uint(1234),
+        address(delegate),
+        uint(endpoint.len()),
+        hex::encode(padded)
+    )
+}
+
+fn rpc_result(result: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "result": result,
+    }))
+}
+
+async fn mock_eth_call(server: &MockServer, data: String, result: &str) {
+    Mock::given(method("POST"))
+        .and(body_string_contains(data))
+        .respond_with(rpc_result(result))
+        .mount(server)
+        .await;
+}
+
+fn chain_registry(server: &MockServer) -> ChainRegistry {
+    ChainRegistry::new(ChainSettings {
+        rpc_url: server.uri(),
+        service_provider_factory_address: "0xD17A9bc90c582249e211a4f4b16721e7f65156c8".into(),
+        service_type: "content-node".into(),
+    })
+}
+
+#[tokio::test]
+async fn chain_registry_reads_registered_content_nodes() {
+    let server = MockServer::start().await;
+
+    mock_eth_call(
+        &server,
+        format!("0x623fa631{SERVICE_TYPE}"),
+        &format!("0x{}", uint(2)),
+    )
+    .await;
+    mock_eth_call(
+        &server,
+        format!("0x748ea82c{SERVICE_TYPE}{}", uint(1)),
+        &endpoint_info(OWNER, "https://creatornode.audius.co", DELEGATE),
+    )
+    .await;
+    mock_eth_call(
+        &server,
+        format!("0x748ea82c{SERVICE_TYPE}{}", uint(2)),
+        &endpoint_info(OWNER, "https://creatornode2.audius.co", DELEGATE),
+    )
+    .await;
+
+    let content_nodes = chain_registry(&server).content_nodes().await.unwrap();
+
+    assert_eq!(content_nodes.len(), 2);
+    assert_eq!(content_nodes[0].spid, 1);
+    assert_eq!(content_nodes[0].endpoint, "https://creatornode.audius.co");
+    assert_eq!(content_nodes[0].owner_wallet.as_deref(), Some(OWNER));
+    assert_eq!(
+        content_nodes[0].delegate_owner_wallet.as_deref(),
+        Some(DELEGATE)
+    );
+    assert_eq!(content_nodes[1].spid, 2);
+    assert_eq!(content_nodes[1].endpoint, "https://creatornode2.audius.co");
+}
+
+#[tokio::test]
+async fn chain_registry_skips_deregistered_content_nodes() {
+    let server = MockServer::start().await;
+
+    mock_eth_call(
+        &server,
+        format!("0x623fa631{SERVICE_TYPE}"),
+        &format!("0x{}", uint(2)),
+    )
+    .await;
+    mock_eth_call(
+        &server,
+        format!("0x748ea82c{SERVICE_TYPE}{}", uint(1)),
+        &endpoint_info(ZERO_ADDRESS, "", ZERO_ADDRESS),
+    )
+    .await;
+    mock_eth_call(
+        &server,
+        format!("0x748ea82c{SERVICE_TYPE}{}", uint(2)),
+        &endpoint_info(OWNER, "https://creatornode2.audius.co", DELEGATE),
+    )
+    .await;
+
+    let content_nodes = chain_registry(&server).content_nodes().await.unwrap();
+
+    assert_eq!(content_nodes.len(), 1);
+    assert_eq!(content_nodes[0].spid, 2);
+}
+
+#[tokio::test]
+async fn chain_registry_surfaces_rpc_errors() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32000, "message": "execution reverted" },
+        })))
+        .mount(&server)
+        .await;
+
+    let error = chain_registry(&server).content_nodes().await.unwrap_err();
+
+    assert!(error.to_string().contains("execution reverted"));
+}
+
+#[tokio::test]
+async fn chain_registry_rejects_truncated_abi_data() {
+    let server = MockServer::start().await;
+
+    mock_eth_call(&server, format!("0x623fa631{SERVICE_TYPE}"), "0x01").await;
+
+    assert!(chain_registry(&server).content_nodes().await.is_err());
+}
+
+#[tokio::test]
+async fn chain_registry_rejects_overflowing_abi_offsets() {
+    let server = MockServer::start().await;
+
+    mock_eth_call(
+        &server,
+        format!("0x623fa631{SERVICE_TYPE}"),
+        &format!("0x{}", uint(1)),
+    )
+    .await;
+    // A string offset pointing at a length word of u64::MAX
+    mock_eth_call(
+        &server,
+        format!("0x748ea82c{SERVICE_TYPE}{}", uint(1)),
+        &format!(
+            "0x{}{}{}{}{:0>64}",
+            address(OWNER),
+            uint(4 * 32),
+            uint(1234),
+            address(DELEGATE),
+            "ffffffffffffffff"
+        ),
+    )
+    .await;
+
+    assert!(chain_registry(&server).content_nodes().await.is_err());
+}
+
+fn cnode(spid: i32, endpoint: &str, owner_wallet: Option<&str>) -> ContentNode {
+    ContentNode {
+        endpoint: endpoint.into(),
+        spid,
+        owner_wallet: owner_wallet.map(Into::into),
+        delegate_owner_wallet: None,
+    }
+}
+
+#[test]
+fn cross_check_finds_missing_and_mismatched_nodes() {
+    let discovery = vec![
+        cnode(1, "https://creatornode.audius.co", Some(OWNER)),
+        cnode(2, "https://creatornode2.audius.co", Some(OWNER)),
+        cnode(3, "https://creatornode3.audius.co", Some(OWNER)),
+    ];
+    let chain = vec![
+        cnode(
+            1,
+            "https://CreatorNode.audius.co/",
+            Some(&OWNER.to_uppercase()),
+        ),
+        cnode(2, "https://creatornode2.audius.co", Some(DELEGATE)),
+        cnode(4, "https://creatornode4.audius.co", Some(OWNER)),
+    ];
+
+    let diff = cross_check(&discovery, &chain);
+
+    assert_eq!(diff.count(), 3);
+    assert_eq!(diff.missing_from_other[0].spid, 3);
+    assert_eq!(diff.missing_from_registry[0].spid, 4);
+    assert_eq!(diff.mismatched[0].0.spid, 2);
+}
+
+#[test]
+fn cross_check_ignores_wallets_unknown_to_one_registry() {
+    let discovery = vec![cnode(1, "https://creatornode.audius.co", Some(OWNER))];
+    let configured = vec![cnode(1, "https://creatornode.audius.co", None)];
+
+    assert_eq!(cross_check(&discovery, &configured).count(), 0);
+}
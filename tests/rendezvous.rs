@@ -0,0 +1,94 @@
+use audius_network_monitor::rendezvous::{placement, rank};
+
+const HOSTS: [&str; 5] = [
+    "https://cn1.audius.co",
+    "https://cn2.audius.co",
+    "https://cn3.audius.co",
+    "https://cn4.audius.co",
+    "https://cn5.audius.co",
+];
+
+fn cid(i: usize) -> String {
+    format!("baeaaaiqse{i:050}")
+}
+
+#[test]
+fn hosts_are_ranked_by_the_hash_of_host_and_key() {
+    // Ranked by sha256(host + key) with Python's hashlib
+    assert_eq!(
+        rank(
+            &HOSTS,
+            "baeaaaiqsea6n5tqubbo4hbfzyytisyumpv5a5mmfr7uhnvftkijvi4qbxu2oy"
+        ),
+        [
+            "https://cn5.audius.co",
+            "https://cn4.audius.co",
+            "https://cn3.audius.co",
+            "https://cn1.audius.co",
+            "https://cn2.audius.co",
+        ]
+    );
+    assert_eq!(
+        rank(&HOSTS, "QmYfSQCgCwhxwYcdEwCkFJHicDe6rzCAb7AtLz3GrHmuU6"),
+        [
+            "https://cn2.audius.co",
+            "https://cn5.audius.co",
+            "https://cn3.audius.co",
+            "https://cn4.audius.co",
+            "https://cn1.audius.co",
+        ]
+    );
+}
+
+#[test]
+fn the_order_of_hosts_does_not_matter() {
+    let mut reversed = HOSTS;
+    reversed.reverse();
+
+    for i in 0..100 {
+        assert_eq!(rank(&HOSTS, &cid(i)), rank(&reversed, &cid(i)));
+    }
+}
+
+#[test]
+fn placement_is_the_top_of_the_ranking() {
+    let placed = placement(&HOSTS, &cid(0), 3);
+
+    assert_eq!(placed, rank(&HOSTS, &cid(0))[..3]);
+    assert_eq!(placement(&HOSTS[..2], &cid(0), 3).len(), 2);
+}
+
+#[test]
+fn only_blobs_of_a_removed_host_move() {
+    let remaining = &HOSTS[..4];
+
+    for i in 0..1_000 {
+        let before = placement(&HOSTS, &cid(i), 3);
+        let after = placement(remaining, &cid(i), 3);
+
+        if before.contains(&HOSTS[4]) {
+            assert!(before
+                .iter()
+                .filter(|host| **host != HOSTS[4])
+                .all(|host| after.contains(host)));
+        } else {
+            assert_eq!(before, after);
+        }
+    }
+}
+
+#[test]
+fn blobs_are_spread_across_hosts() {
+    let mut counts = [0; HOSTS.len()];
+    for i in 0..5_000 {
+        for host in placement(&HOSTS, &cid(i), 3) {
+            counts[HOSTS.iter().position(|h| *h == host).unwrap()] += 1;
+        }
+    }
+
+    // Each host should hold 3/5 of 5000 blobs
+    assert!(
+        counts.iter().all(|count| (2_700..3_300).contains(count)),
+        "{counts:?}"
+    );
+}
@@ -0,0 +1,73 @@
+use audius_network_monitor::report::{compare, NodeStats, RegressedUser, RunReport};
+
+fn stats(endpoint: &str, all_users: i64, unsynced: i64) -> NodeStats {
+    NodeStats {
+        endpoint: endpoint.into(),
+        all_users,
+        primary_users: all_users / 3,
+        fully_synced: all_users / 3 - unsynced,
+        partially_synced: 0,
+        unsynced,
+        missed_users: 0,
+    }
+}
+
+#[test]
+fn nodes_are_paired_by_endpoint() {
+    let current = vec![
+        stats("https://cn1.audius.co", 300, 10),
+        stats("https://cn3.audius.co", 30, 0),
+    ];
+    let previous = vec![
+        stats("https://cn1.audius.co", 270, 0),
+        stats("https://cn2.audius.co", 90, 0),
+    ];
+
+    let nodes = compare(&current, &previous);
+
+    let endpoints = nodes
+        .iter()
+        .map(|node| node.endpoint.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(
+        endpoints,
+        [
+            "https://cn1.audius.co",
+            "https://cn2.audius.co",
+            "https://cn3.audius.co"
+        ]
+    );
+    assert!(nodes[0].current.is_some() && nodes[0].previous.is_some());
+    assert!(nodes[1].current.is_none());
+    assert!(nodes[2].previous.is_none());
+}
+
+#[test]
+fn markdown_shows_deltas_and_regressed_users() {
+    let report = RunReport {
+        run_id: 12,
+        previous_run_id: 11,
+        nodes: compare(
+            &[stats("https://cn1.audius.co", 300, 10)],
+            &[
+                stats("https://cn1.audius.co", 270, 0),
+                stats("https://cn2.audius.co", 90, 0),
+            ],
+        ),
+        regressed_users: vec![RegressedUser {
+            user_id: 7,
+            wallet: Some("0xabc".into()),
+            primary_endpoint: Some("https://cn1.audius.co".into()),
+        }],
+    };
+
+    let markdown = report.to_markdown();
+
+    assert!(markdown.starts_with("## Run 12 compared to run 11\n"));
+    assert!(markdown
+        .contains("| https://cn1.audius.co | 300 (+30) | 100 (+10) | 90 | 0 | 10 (+10) | 0 |"));
+    assert!(markdown
+        .contains("| https://cn2.audius.co (gone) | 0 (-90) | 0 (-30) | 0 (-30) | 0 | 0 | 0 |"));
+    assert!(markdown.contains("### 1 users went from fully synced to unsynced"));
+    assert!(markdown.contains("| 7 | 0xabc | https://cn1.audius.co |"));
+}
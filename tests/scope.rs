@@ -0,0 +1,67 @@
+use audius_network_monitor::{
+    configuration::ScopeSettings,
+    content::{PlannedRequests, RequestPlan},
+    domain::ContentNode,
+};
+
+fn cnode(spid: i32, endpoint: &str) -> ContentNode {
+    ContentNode {
+        endpoint: endpoint.into(),
+        spid,
+        owner_wallet: None,
+        delegate_owner_wallet: None,
+    }
+}
+
+#[test]
+fn every_node_is_included_by_default() {
+    let scope = ScopeSettings::default();
+
+    assert!(scope.includes(&cnode(1, "https://cn1.example.com")));
+    assert!(!scope.limits_nodes());
+    assert!(!scope.limits_users());
+}
+
+#[test]
+fn included_spids_and_endpoints_add_up() {
+    let scope = ScopeSettings {
+        include_spids: vec![1],
+        include_endpoints: vec!["https://cn2.example.com".into()],
+        ..ScopeSettings::default()
+    };
+
+    assert!(scope.includes(&cnode(1, "https://cn1.example.com")));
+    assert!(scope.includes(&cnode(2, "https://cn2.example.com")));
+    assert!(!scope.includes(&cnode(3, "https://cn3.example.com")));
+}
+
+#[test]
+fn exclusions_win_over_inclusions() {
+    let scope = ScopeSettings {
+        include_spids: vec![1, 2],
+        exclude_endpoints: vec!["https://cn2.example.com".into()],
+        ..ScopeSettings::default()
+    };
+
+    assert!(scope.includes(&cnode(1, "https://cn1.example.com")));
+    assert!(!scope.includes(&cnode(2, "https://cn2.example.com")));
+    assert!(scope.limits_nodes());
+}
+
+#[test]
+fn items_are_planned_in_batches() {
+    let mut plan = RequestPlan::default();
+    plan.add_batches("/batch_cids_exist", 10_001, 5_000);
+    plan.add_batches("/batch_cids_exist", 5_000, 5_000);
+    plan.add_batches("/users/batch_clock_status", 0, 5_000);
+
+    assert_eq!(plan.requests(), 4);
+    assert_eq!(
+        plan.routes.get("/batch_cids_exist"),
+        Some(&PlannedRequests {
+            requests: 4,
+            items: 15_001,
+        })
+    );
+    assert!(!plan.routes.contains_key("/users/batch_clock_status"));
+}
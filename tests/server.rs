@@ -0,0 +1,86 @@
+use std::net::SocketAddr;
+
+use audius_network_monitor::{
+    configuration::ServerSettings,
+    server::{self, HealthState},
+};
+use color_eyre::eyre::eyre;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+fn spawn_server(health: HealthState) -> SocketAddr {
+    let config = ServerSettings {
+        host: "127.0.0.1".into(),
+        port: 0,
+    };
+    let (address, server) = server::bind(&config, health).unwrap();
+    tokio::spawn(server);
+
+    address
+}
+
+async fn get(address: SocketAddr, path: &str) -> reqwest::Response {
+    reqwest::get(format!("http://{address}{path}"))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn metrics_are_served_in_the_text_format() {
+    let address = spawn_server(HealthState::new());
+
+    let response = get(address, "/metrics").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+}
+
+#[tokio::test]
+async fn healthz_reports_the_run_state() {
+    let health = HealthState::new();
+    let address = spawn_server(health.clone());
+
+    health.run_started();
+    let state = get(address, "/healthz")
+        .await
+        .json::<Value>()
+        .await
+        .unwrap();
+
+    assert_eq!(state["running"], true);
+    assert_eq!(state["last_succeeded"], Value::Null);
+}
+
+#[tokio::test]
+async fn readyz_follows_the_latest_run() {
+    let health = HealthState::new();
+    let address = spawn_server(health.clone());
+
+    assert_eq!(
+        get(address, "/readyz").await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    health.run_started();
+    health.run_finished(&Ok(42));
+    let response = get(address, "/readyz").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>().await.unwrap()["last_run_id"], 42);
+
+    health.run_started();
+    health.run_finished(&Err(eyre!("content stage failed")));
+    assert_eq!(
+        get(address, "/readyz").await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[tokio::test]
+async fn unknown_paths_are_not_found() {
+    let address = spawn_server(HealthState::new());
+
+    assert_eq!(get(address, "/nope").await.status(), StatusCode::NOT_FOUND);
+}
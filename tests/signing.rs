@@ -0,0 +1,54 @@
+use audius_network_monitor::signing::{recover_address, recover_params_address, Signer};
+use chrono::{TimeZone, Utc};
+use secrecy::Secret;
+
+// Example account and signature of the web3.js `eth.accounts` documentation
+const PRIVATE_KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+const ADDRESS: &str = "0x2c7536e3605d9c16a7a3d7b1898e529396a65c23";
+const SOME_DATA_SIGNATURE: &str = "0xb91467e570a6466aa9e9876cbcd013baba02900b8979d43fe208a4a4f339f5fd6007e74cd82e037b800186422fc2da167c747ef045e5d18a5f5d4300f8e1a0291c";
+
+fn signer() -> Signer {
+    Signer::new(7, &Secret::new(PRIVATE_KEY.into())).unwrap()
+}
+
+#[test]
+fn the_address_is_derived_from_the_key() {
+    assert_eq!(signer().address(), ADDRESS);
+}
+
+#[test]
+fn messages_are_signed_like_web3() {
+    assert_eq!(signer().sign_message(b"Some data"), SOME_DATA_SIGNATURE);
+    assert_eq!(
+        recover_address(b"Some data", SOME_DATA_SIGNATURE).unwrap(),
+        ADDRESS
+    );
+}
+
+#[test]
+fn params_are_signed_over_spid_and_timestamp() {
+    let timestamp = Utc.with_ymd_and_hms(2023, 3, 30, 12, 0, 0).unwrap();
+
+    let params = signer().sign_params(timestamp);
+
+    assert_eq!(params.spid, 7);
+    assert_eq!(params.timestamp, "2023-03-30T12:00:00.000Z");
+    // Signature of the keccak256 hash of {"spID":7,"timestamp":"2023-03-30T12:00:00.000Z"}
+    assert_eq!(params.signature, "0x54d2c5c575e4250ed639120fddc38f1e73597d442789ee082c543551af11db565eb2f970e2477196e44361e0e81eaedc7e5bfaec556c9333cc24e8944bb3fde01b");
+    assert_eq!(recover_params_address(&params).unwrap(), ADDRESS);
+}
+
+#[test]
+fn tampered_params_recover_another_address() {
+    let mut params = signer().sign_params(Utc::now());
+    params.spid = 8;
+
+    assert_ne!(recover_params_address(&params).unwrap(), ADDRESS);
+}
+
+#[test]
+fn invalid_keys_and_signatures_are_rejected() {
+    assert!(Signer::new(7, &Secret::new("0x00".into())).is_err());
+    assert!(Signer::new(7, &Secret::new("not hex".into())).is_err());
+    assert!(recover_address(b"Some data", "0x1234").is_err());
+}
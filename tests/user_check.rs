@@ -0,0 +1,83 @@
+use audius_network_monitor::user_check::{ReplicaStatus, UserQuery, Verdict};
+
+fn checked(clock: Result<i32, &str>, missing_cids: Result<&[&str], &str>) -> ReplicaStatus {
+    ReplicaStatus::Checked {
+        spid: 1,
+        endpoint: "https://cn1.audius.co".into(),
+        clock: clock.map_err(ToString::to_string),
+        missing_cids: missing_cids
+            .map(|cids| cids.iter().map(ToString::to_string).collect())
+            .map_err(ToString::to_string),
+    }
+}
+
+#[test]
+fn queries_are_told_apart() {
+    assert_eq!("42".parse(), Ok(UserQuery::Id(42)));
+    assert_eq!(
+        "0x2C7536E3605D9C16A7A3D7B1898E529396A65C23".parse(),
+        Ok(UserQuery::Wallet(
+            "0x2c7536e3605d9c16a7a3d7b1898e529396a65c23".into()
+        ))
+    );
+    assert_eq!("@Audius".parse(), Ok(UserQuery::Handle("audius".into())));
+    assert_eq!("0xdead".parse(), Ok(UserQuery::Handle("0xdead".into())));
+}
+
+#[test]
+fn replicas_are_judged_against_the_primary_clock() {
+    assert_eq!(checked(Ok(12), Ok(&[])).verdict(Some(12)), Verdict::Healthy);
+    assert_eq!(
+        checked(Ok(10), Ok(&[])).verdict(Some(12)),
+        Verdict::OutOfSync {
+            clock: 10,
+            primary_clock: 12
+        }
+    );
+    assert_eq!(
+        checked(Ok(14), Ok(&[])).verdict(Some(12)),
+        Verdict::OutOfSync {
+            clock: 14,
+            primary_clock: 12
+        }
+    );
+    assert_eq!(
+        checked(Ok(-1), Ok(&[])).verdict(Some(12)),
+        Verdict::MissingUser
+    );
+    assert_eq!(
+        checked(Err("timed out"), Ok(&[])).verdict(Some(12)),
+        Verdict::Unreachable
+    );
+}
+
+#[test]
+fn missing_cids_are_reported_once_the_clock_is_in_sync() {
+    assert_eq!(
+        checked(Ok(12), Ok(&["Qm1", "Qm2"])).verdict(Some(12)),
+        Verdict::MissingCids(2)
+    );
+    assert_eq!(
+        checked(Ok(12), Err("503")).verdict(None),
+        Verdict::CidsUnchecked
+    );
+    assert_eq!(
+        checked(Ok(10), Ok(&["Qm1"])).verdict(Some(12)),
+        Verdict::OutOfSync {
+            clock: 10,
+            primary_clock: 12
+        }
+    );
+}
+
+#[test]
+fn replicas_without_a_node_are_not_checked() {
+    assert_eq!(
+        ReplicaStatus::Unassigned.verdict(Some(12)),
+        Verdict::Unassigned
+    );
+    assert_eq!(
+        ReplicaStatus::Unregistered(9).verdict(Some(12)),
+        Verdict::Unregistered
+    );
+}
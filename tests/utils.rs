@@ -0,0 +1,348 @@
+use std::{collections::HashMap, net::TcpListener, sync::Mutex, time::Duration};
+
+use audius_network_monitor::{
+    configuration::{HttpSettings, LimitSettings},
+    domain::WalletClockPair,
+    signing::{recover_params_address, Signer},
+    utils::{write_in_chunks, ContentNodeClient, RequestError, SignatureParams, UserStatusPayload},
+};
+use color_eyre::eyre::eyre;
+use proptest::prelude::*;
+use secrecy::Secret;
+use serde_json::json;
+use wiremock::{
+    matchers::{header, method},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const CLOCK_ROUTE: &str = "/users/batch_clock_status";
+const PRIVATE_KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+/// Pairs with distinct wallets, since clock values are saved by wallet
+fn wallet_clock_pairs() -> impl Strategy<Value = Vec<WalletClockPair>> {
+    prop::collection::vec(-1..i32::MAX, 0..2_000).prop_map(|clocks| {
+        clocks
+            .into_iter()
+            .enumerate()
+            .map(|(i, clock)| WalletClockPair {
+                wallet_public_key: format!("0x{i:040x}"),
+                clock,
+            })
+            .collect()
+    })
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+proptest! {
+    #[test]
+    fn every_pair_is_written_exactly_once(pairs in wallet_clock_pairs(), chunk_size in 1..1_000_usize) {
+        let written = Mutex::new(Vec::new());
+
+        block_on(write_in_chunks(&pairs, chunk_size, |chunk| {
+            written.lock().unwrap().push(chunk.to_vec());
+            async { Ok(()) }
+        }))
+        .unwrap();
+
+        let chunks = written.into_inner().unwrap();
+        prop_assert!(chunks.iter().all(|chunk| !chunk.is_empty() && chunk.len() <= chunk_size));
+        prop_assert_eq!(chunks.len(), pairs.len().div_ceil(chunk_size));
+        prop_assert_eq!(chunks.concat(), pairs);
+    }
+
+    #[test]
+    fn writing_stops_at_the_first_error(pairs in wallet_clock_pairs(), chunk_size in 1..1_000_usize, failing_chunk in 0..10_usize) {
+        let mut writes = 0;
+
+        let result = block_on(write_in_chunks(&pairs, chunk_size, |_| {
+            writes += 1;
+            let failed = writes > failing_chunk;
+            async move {
+                if failed {
+                    Err(eyre!("write failed"))
+                } else {
+                    Ok(())
+                }
+            }
+        }));
+
+        let chunk_count = pairs.len().div_ceil(chunk_size);
+        prop_assert_eq!(result.is_err(), chunk_count > failing_chunk);
+        prop_assert_eq!(writes, chunk_count.min(failing_chunk + 1));
+    }
+}
+
+#[tokio::test]
+async fn a_single_pair_is_written() {
+    let pairs = vec![WalletClockPair {
+        wallet_public_key: "0xabc".into(),
+        clock: 7,
+    }];
+    let mut written = Vec::new();
+
+    write_in_chunks(&pairs, 500, |chunk| {
+        written.extend_from_slice(chunk);
+        async { Ok(()) }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(written, pairs);
+}
+
+fn signer() -> Signer {
+    Signer::new(1, &Secret::new(PRIVATE_KEY.into())).unwrap()
+}
+
+fn client() -> ContentNodeClient {
+    ContentNodeClient::new(
+        &HttpSettings {
+            request_timeout_seconds: 1,
+            retries: 2,
+            retry_backoff_millis: 1,
+            ..HttpSettings::default()
+        },
+        &LimitSettings::default(),
+        Some(signer()),
+    )
+    .unwrap()
+}
+
+fn payload() -> UserStatusPayload {
+    UserStatusPayload {
+        wallet_public_keys: vec!["0xabc".into()],
+    }
+}
+
+async fn node_responding_with(response: ResponseTemplate) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(response)
+        .mount(&server)
+        .await;
+
+    server
+}
+
+async fn request_error(response: ResponseTemplate) -> RequestError {
+    let server = node_responding_with(response).await;
+
+    client()
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err()
+}
+
+#[tokio::test]
+async fn clock_values_are_read_from_the_data_payload() {
+    let data = json!([{ "walletPublicKey": "0xabc", "clock": 7 }]).to_string();
+    let server = node_responding_with(ResponseTemplate::new(200).set_body_json(json!({
+        "data": data,
+        "method": "POST",
+        "headers": {},
+    })))
+    .await;
+
+    let clock_values = client()
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        clock_values,
+        [WalletClockPair {
+            wallet_public_key: "0xabc".into(),
+            clock: 7,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn error_statuses_are_classified() {
+    let error = request_error(ResponseTemplate::new(404)).await;
+    assert_eq!(error.kind(), "client_error");
+    assert_eq!(error.status().map(|status| status.as_u16()), Some(404));
+
+    let error = request_error(ResponseTemplate::new(503)).await;
+    assert_eq!(error.kind(), "server_error");
+    assert_eq!(error.status().map(|status| status.as_u16()), Some(503));
+}
+
+#[tokio::test]
+async fn invalid_responses_are_classified() {
+    let error = request_error(ResponseTemplate::new(200).set_body_string("<html>")).await;
+    assert_eq!(error.kind(), "malformed_json");
+
+    let error = request_error(ResponseTemplate::new(200).set_body_json(json!({
+        "data": "{\"error\": \"not clock values\"}",
+        "method": "POST",
+        "headers": {},
+    })))
+    .await;
+    assert_eq!(error.kind(), "bad_payload");
+}
+
+#[tokio::test]
+async fn refused_connections_are_classified() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let error = client()
+        .make_request(&format!("http://{address}"), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.kind(), "connection_refused");
+}
+
+#[tokio::test]
+async fn slow_nodes_time_out() {
+    let error = request_error(ResponseTemplate::new(200).set_delay(Duration::from_secs(3))).await;
+
+    assert_eq!(error.kind(), "timeout");
+}
+
+#[tokio::test]
+async fn client_errors_are_not_retried() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client()
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn server_errors_are_retried() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(3)
+        .mount(&server)
+        .await;
+
+    client()
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn requests_carry_the_configured_user_agent() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(header("user-agent", "network-monitor-test"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = ContentNodeClient::new(
+        &HttpSettings {
+            user_agent: "network-monitor-test".into(),
+            ..HttpSettings::default()
+        },
+        &LimitSettings::default(),
+        Some(signer()),
+    )
+    .unwrap();
+
+    let error = client
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), "client_error");
+}
+
+#[tokio::test]
+async fn failed_attempts_are_counted_per_endpoint() {
+    let server = node_responding_with(ResponseTemplate::new(502)).await;
+
+    client()
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err();
+
+    let failed_attempts = prometheus::gather()
+        .into_iter()
+        .find(|family| family.get_name() == "audius_nm_content_node_request_errors_total")
+        .unwrap()
+        .get_metric()
+        .iter()
+        .filter(|metric| {
+            let has_label = |name: &str, value: &str| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.get_name() == name && label.get_value() == value)
+            };
+
+            // Mock servers are pooled, so other tests may have used this endpoint
+            has_label("endpoint", &server.uri()) && has_label("status", "502")
+        })
+        .map(|metric| metric.get_counter().get_value())
+        .sum::<f64>();
+
+    // The first attempt and its 2 retries
+    assert!((failed_attempts - 3.0).abs() < f64::EPSILON);
+}
+
+#[tokio::test]
+async fn requests_are_signed_as_the_service_provider() {
+    let server = node_responding_with(ResponseTemplate::new(404)).await;
+
+    client()
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err();
+
+    let requests = server.received_requests().await.unwrap();
+    let query = requests[0]
+        .url
+        .query_pairs()
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let params = SignatureParams {
+        spid: query["spID"].parse().unwrap(),
+        timestamp: query["timestamp"].clone(),
+        signature: query["signature"].clone(),
+    };
+    assert_eq!(params.spid, 1);
+    assert_eq!(recover_params_address(&params).unwrap(), signer().address());
+}
+
+#[tokio::test]
+async fn requests_are_unsigned_without_a_signer() {
+    let server = node_responding_with(ResponseTemplate::new(404)).await;
+
+    let client = ContentNodeClient::new(
+        &HttpSettings {
+            retries: 0,
+            ..HttpSettings::default()
+        },
+        &LimitSettings::default(),
+        None,
+    )
+    .unwrap();
+
+    client
+        .make_request(&server.uri(), CLOCK_ROUTE, &payload())
+        .await
+        .unwrap_err();
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].url.query(), None);
+}